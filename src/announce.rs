@@ -0,0 +1,201 @@
+extern crate time;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+use default_handler::GlobalState;
+use tracker::{open_tracker, Address, AnnounceRequest, AnnounceResponse, Event, Tracker, TrackerList};
+
+/// Contains the announce schedule of a torrent. The first announce says started, later ones come
+/// at the interval the tracker asks for (a minute at least), completed goes out as soon as nothing
+/// is left (though no sooner than the min interval allows) and stopped when the session ends
+
+/// How long to wait before trying again when no tracker answers, in seconds
+pub const RETRY_INTERVAL: i64 = 60;
+/// The shortest wait between regular announces, whatever interval a tracker asks for
+pub const MIN_ANNOUNCE_INTERVAL: i64 = 60;
+
+/// Byte counts for the session so far, as reported to trackers
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TransferStats {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64
+}
+
+pub struct AnnounceScheduler {
+    trackers: TrackerList,
+    //opened on first use and kept, UDP trackers hold on to their connection ids
+    open: BTreeMap<String, Box<dyn Tracker + Send>>,
+    info_hash: [u8; 20],
+    peer_id: String,
+    port: u16,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    started: bool,
+    completed: bool,
+    //the tracker that last answered, the only one told when we stop
+    answered: Option<String>,
+    next_announce: i64,
+    not_before: i64
+}
+
+impl AnnounceScheduler {
+    pub fn new (trackers: TrackerList, info_hash: [u8; 20], peer_id: String, port: u16) -> AnnounceScheduler {
+        AnnounceScheduler {
+            trackers: trackers,
+            open: BTreeMap::new(),
+            info_hash: info_hash,
+            peer_id: peer_id,
+            port: port,
+            ipv4: None,
+            ipv6: None,
+            started: false,
+            completed: false,
+            answered: None,
+            next_announce: 0,
+            not_before: 0
+        }
+    }
+
+    /// Announces to url go to tracker instead of one opened by scheme
+    pub fn set_tracker (&mut self, tracker: Box<dyn Tracker + Send>) {
+        self.open.insert(tracker.url().to_string(), tracker);
+    }
+
+    /// Our addresses to send to trackers along with announces, as from tracker::local_addresses
+    pub fn set_local_addresses (&mut self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) {
+        self.ipv4 = ipv4;
+        self.ipv6 = ipv6;
+    }
+
+    /// When the next regular announce is due
+    pub fn next_announce (&self) -> i64 {
+        self.next_announce
+    }
+
+    /// The event to announce with at now given the stats, or None if no announce is due
+    pub fn due (&self, now: i64, stats: &TransferStats) -> Option<Event> {
+        if !self.started {
+            if now >= self.next_announce { Some(Event::Started) } else { None }
+        } else if stats.left == 0 && !self.completed && now >= self.not_before {
+            Some(Event::Completed)
+        } else if now >= self.next_announce {
+            Some(Event::None)
+        } else {
+            None
+        }
+    }
+
+    /// Announces if one is due and returns the peers the tracker gave back
+    pub fn poll (&mut self, now: i64, stats: &TransferStats) -> Option<Vec<Address>> {
+        let event = self.due(now, stats)?;
+        match self.announce(event, stats) {
+            Some(response) => {
+                match event {
+                    //a session that starts out seeding has nothing to complete
+                    Event::Started => {
+                        self.started = true;
+                        self.completed = stats.left == 0;
+                    },
+                    Event::Completed => self.completed = true,
+                    _ => {}
+                }
+                let min_interval = response.min_interval.unwrap_or(0) as i64;
+                self.next_announce = now + cmp::max(cmp::max(response.interval as i64, min_interval), MIN_ANNOUNCE_INTERVAL);
+                self.not_before = now + min_interval;
+                Some(response.addresses())
+            },
+            None => {
+                println!("none of the trackers responded");
+                self.next_announce = now + RETRY_INTERVAL;
+                self.not_before = now + RETRY_INTERVAL;
+                None
+            }
+        }
+    }
+
+    /// Tells the tracker that last answered we're leaving, if it was ever told we started. The
+    /// rest of the list isn't tried, unreachable trackers could hold up shutdown for minutes
+    pub fn stop (&mut self, stats: &TransferStats) {
+        if !self.started {
+            return
+        }
+        self.started = false;
+        let request = self.request(Event::Stopped, stats);
+        let tracker = match self.answered {
+            Some(ref url) => self.open.get_mut(url),
+            None => None
+        };
+        if let Some(tracker) = tracker {
+            if let Err(e) = tracker.announce(&request) {
+                println!("tracker {}: {}", tracker.url(), e);
+            }
+        }
+    }
+
+    /// Announces as they come due, handing the peers that come back to on_peers, until stop gets
+    /// a message or hangs up. Then sends stopped before returning
+    pub fn run<F> (mut self, global_arc: Arc<Mutex<GlobalState>>, stop: Receiver<()>, mut on_peers: F) where F: FnMut(Vec<Address>) {
+        loop {
+            let stats = global_arc.lock().unwrap().transfer_stats();
+            if let Some(peers) = self.poll(time::get_time().sec, &stats) {
+                on_peers(peers);
+            }
+            match stop.recv_timeout(Duration::from_secs(1)) {
+                Err(RecvTimeoutError::Timeout) => {},
+                _ => break
+            }
+        }
+        let stats = global_arc.lock().unwrap().transfer_stats();
+        self.stop(&stats);
+    }
+
+    fn request (&self, event: Event, stats: &TransferStats) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id.clone(),
+            port: self.port,
+            uploaded: stats.uploaded,
+            downloaded: stats.downloaded,
+            left: stats.left,
+            event: event,
+            ipv4: self.ipv4,
+            ipv6: self.ipv6
+        }
+    }
+
+    fn announce (&mut self, event: Event, stats: &TransferStats) -> Option<AnnounceResponse> {
+        let request = self.request(event, stats);
+        let open = &mut self.open;
+        let mut answered = None;
+        let response = self.trackers.try_each(|url| {
+            if !open.contains_key(url) {
+                match open_tracker(url) {
+                    Ok(tracker) => { open.insert(url.to_string(), tracker); },
+                    Err(e) => {
+                        println!("tracker {}: {}", url, e);
+                        return None
+                    }
+                }
+            }
+            match open.get_mut(url).unwrap().announce(&request) {
+                Ok(response) => {
+                    answered = Some(url.to_string());
+                    Some(response)
+                },
+                Err(e) => {
+                    println!("tracker {}: {}", url, e);
+                    None
+                }
+            }
+        });
+        if answered.is_some() {
+            self.answered = answered;
+        }
+        response
+    }
+}
@@ -1,36 +1,168 @@
-#![allow(unused_imports, unused_must_use, dead_code)]
-extern crate combine;
+#![allow(dead_code)]
+
+extern crate serde_json;
 
 use std::io::prelude::*;
+use std::io;
 use std::fs::File;
 use std::path::Path;
-use std::collections::HashMap;
-use std::marker::PhantomData;
-use std::str;
-use combine::{parser, between, many, many1, digit, char, Parser, ParserExt};
-use combine::primitives::{State, Stream, ParseResult, Consumed};
-use combine::combinator::FnParser;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+#[macro_use]
+mod convert;
+mod decoder;
+mod encoder;
+mod json;
+mod pretty;
+
+pub use convert::{ToBencode, ToBencodeError, FromBencode, FromBencodeError, from_dict_entry};
+pub use decoder::{Decoder, Decoded, read_value};
+pub use encoder::{Encoder, EncodeError};
+pub use json::{to_json, from_json, from_json_str, JsonError};
+pub use pretty::{Pretty, DEFAULT_TRUNCATE};
+pub use serde_json::Value as Json;
 
 //my own bencode stuff!
+//dictionary keys are byte strings like any other (the spec doesn't require them to be text, and
+//DHT/extension messages do use binary keys). a BTreeMap keeps them ordered by their raw bytes,
+//which is exactly the canonical order
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Bencode {
     Int(i64),
     ByteString(Vec<u8>),
     List(Vec<Bencode>),
-    Dict(HashMap<String, Bencode>)
+    Dict(BTreeMap<Vec<u8>, Bencode>)
+}
+
+/// A decoded value that borrows its byte strings straight out of the input instead of copying
+/// them. This is what the parser produces, use to_bencode when an owned value is needed
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum BencodeRef<'a> {
+    Int(i64),
+    ByteString(&'a [u8]),
+    List(Vec<BencodeRef<'a>>),
+    Dict(BTreeMap<&'a [u8], BencodeRef<'a>>)
+}
+
+impl <'a> BencodeRef<'a> {
+    /// Looks up a key if this is a dictionary
+    pub fn get (&self, key: &str) -> Option<&BencodeRef<'a>> {
+        match *self {
+            BencodeRef::Dict(ref dict) => dict.get(key.as_bytes()),
+            _ => None
+        }
+    }
+
+    pub fn as_int (&self) -> Option<i64> {
+        match *self {
+            BencodeRef::Int(int) => Some(int),
+            _ => None
+        }
+    }
+
+    pub fn as_bytes (&self) -> Option<&'a [u8]> {
+        match *self {
+            BencodeRef::ByteString(string) => Some(string),
+            _ => None
+        }
+    }
+
+    pub fn as_list (&self) -> Option<&[BencodeRef<'a>]> {
+        match *self {
+            BencodeRef::List(ref list) => Some(list),
+            _ => None
+        }
+    }
+
+    pub fn as_dict (&self) -> Option<&BTreeMap<&'a [u8], BencodeRef<'a>>> {
+        match *self {
+            BencodeRef::Dict(ref dict) => Some(dict),
+            _ => None
+        }
+    }
+
+    /// Copies everything out of the input into an owned value
+    pub fn to_bencode (&self) -> Bencode {
+        match *self {
+            BencodeRef::Int(int) => Bencode::Int(int),
+            BencodeRef::ByteString(string) => Bencode::ByteString(string.to_vec()),
+            BencodeRef::List(ref list) => Bencode::List(list.iter().map(|x| x.to_bencode()).collect()),
+            BencodeRef::Dict(ref dict) => Bencode::Dict(dict.iter().map(|(k, v)| (k.to_vec(), v.to_bencode())).collect())
+        }
+    }
+}
+
+impl <'a> From<BencodeRef<'a>> for Bencode {
+    fn from (value: BencodeRef<'a>) -> Bencode {
+        value.to_bencode()
+    }
+}
+
+/// The byte range a value was decoded from. start is inclusive, end is exclusive
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+
+impl Span {
+    /// The bytes of input covered by this span
+    pub fn of<'a> (&self, input: &'a [u8]) -> &'a [u8] {
+        &input[self.start..self.end]
+    }
+}
+
+/// A decoded value which remembers where in the input it (and each of its children) came from
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum SpannedBencode {
+    Int(i64, Span),
+    ByteString(Vec<u8>, Span),
+    List(Vec<SpannedBencode>, Span),
+    Dict(BTreeMap<Vec<u8>, SpannedBencode>, Span)
+}
+
+impl SpannedBencode {
+    pub fn span (&self) -> Span {
+        match *self {
+            SpannedBencode::Int(_, span)
+            | SpannedBencode::ByteString(_, span)
+            | SpannedBencode::List(_, span)
+            | SpannedBencode::Dict(_, span) => span
+        }
+    }
+
+    /// Looks up a key if this is a dictionary
+    pub fn get (&self, key: &str) -> Option<&SpannedBencode> {
+        match *self {
+            SpannedBencode::Dict(ref dict, _) => dict.get(key.as_bytes()),
+            _ => None
+        }
+    }
+
+    /// Drops the spans
+    pub fn into_bencode (self) -> Bencode {
+        match self {
+            SpannedBencode::Int(int, _) => Bencode::Int(int),
+            SpannedBencode::ByteString(string, _) => Bencode::ByteString(string),
+            SpannedBencode::List(list, _) => Bencode::List(list.into_iter().map(|x| x.into_bencode()).collect()),
+            SpannedBencode::Dict(dict, _) => Bencode::Dict(dict.into_iter().map(|(k, v)| (k, v.into_bencode())).collect())
+        }
+    }
 }
 
 //for options over Bencode Shapes
 pub trait BencodeVecOption {
     /// Unwraps self if it wraps a dictionary
-    fn to_singleton_dict (&self) -> Option<HashMap<String, Bencode>>;
+    fn to_singleton_dict (&self) -> Option<BTreeMap<Vec<u8>, Bencode>>;
 }
 
 impl BencodeVecOption for Option<Vec<Bencode>> {
-    fn to_singleton_dict (&self) -> Option <HashMap<String, Bencode>> {
-        match self {
-            &Some(ref a) => match (a.first(), a.len()) {
-                (Some(&Bencode::Dict(ref b)), 1) => Some(b.to_owned()),
+    fn to_singleton_dict (&self) -> Option <BTreeMap<Vec<u8>, Bencode>> {
+        match *self {
+            Some(ref a) => match (a.first(), a.len()) {
+                (Some(Bencode::Dict(b)), 1) => Some(b.to_owned()),
                 _ => None
             },
             _ => None
@@ -42,106 +174,235 @@ pub trait BencodeToString {
     fn to_bencode_string (&self) -> Vec<u8>;
 }
 
-//yes, we could just use the offsets from the parsed file. but we aren't keeping it around, nor
-//are the parsers returning offsets right now (and as it's only needed for computing the
-//hashsum... and computing this on the fly is comparatively easier than navigating through
-//the sigils of combine
-//
-//might be worth adapting at the end as its modular, probably not worth it though)
+//n.b. this is not guaranteed to reproduce the bytes a value was parsed from - non canonical input
+//(unsorted or duplicate keys, leading zeros) comes back out canonical. anything that needs the
+//original bytes (e.g. the info hash) should use deserialize_with_spans and slice the input
 impl BencodeToString for Bencode {
     fn to_bencode_string (&self) -> Vec<u8> {
-        match *self { //oh god... what did i do...
-            Bencode::Int(ref int) => {
-                let mut vec: Vec<u8> = Vec::new();
-                vec.push('i' as u8);
-                for a_char in int.to_string().chars() {
-                    vec.push(a_char as u8);
-                }
-                vec.push('e' as u8);
-                vec
-            }
-            Bencode::ByteString(ref string) => {
-                //this is horrible and ugly because of a string/ascii oversight
-                let mut vec: Vec<u8> = Vec::new();
-                for a_char in string.len().to_string().chars() {
-                    vec.push(a_char as u8);
-                }
-                vec.push(':' as u8);
-                for byte in string.iter() {
-                    vec.push(*byte as u8);
-                }
-                vec
-            },
-            Bencode::List(ref list) => {
-                list.iter().flat_map(|x| x.to_bencode_string()).collect::<Vec<u8>>()
-            },
-            Bencode::Dict(ref dict) => {
-                let mut vec: Vec<u8> = Vec::new();
-                let mut kvs: Vec<(&String, &Bencode)> = dict.iter().collect();
-                kvs.sort_by(|a, b| a.0.cmp(&b.0));
-                vec.push('d' as u8);
-                for (key_name, val) in kvs {
-                    for a_char in key_name.len().to_string().chars() {
-                        vec.push(a_char as u8);
-                    }
-                    vec.push(':' as u8);
-                    for byte in key_name.chars() {
-                        vec.push(byte as u8);
-                    }
-                    // vec.push(key_name.to_string());
-                    for byte in val.to_bencode_string().iter() {
-                        vec.push(*byte as u8);
-                    }
-                }
-                vec.push('e' as u8);
-                vec
-            }
+        let mut encoder = Encoder::new(Vec::new());
+        //writing to a vec can't fail, and dict keys come out of the BTreeMap already sorted
+        encoder.write_value(self).expect("a Bencode value is always encodable");
+        encoder.finish().expect("a Bencode value is always encodable")
+    }
+}
+
+/// Errors that can occur while decoding bencoded bytes. Every parse error carries the byte offset
+/// (into the original input) at which the problem was detected
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum BencodeError {
+    /// The input ended in the middle of a value
+    UnexpectedEof(usize),
+    /// A byte that can't start (or continue) a value at this position
+    UnexpectedByte(u8, usize),
+    /// An integer or string length that is malformed or does not fit
+    InvalidInteger(usize),
+    /// Leftover bytes after a complete value
+    TrailingData(usize),
+    /// Strict mode only: an integer or length with a leading zero, or -0
+    NonCanonicalInteger(usize),
+    /// Strict mode only: a dict key that sorts before the key preceding it
+    UnsortedKey(usize),
+    /// Strict mode only: a dict key that appears twice
+    DuplicateKey(usize),
+    /// Lists and dicts nested deeper than ParseOptions::max_depth
+    TooDeep(usize),
+    /// A byte string longer than ParseOptions::max_len
+    TooLong(usize),
+    /// The underlying file or stream could not be read
+    Io(io::ErrorKind)
+}
+
+impl BencodeError {
+    /// The byte offset at which decoding failed, if the error came from the parser
+    pub fn offset (&self) -> Option<usize> {
+        match *self {
+            BencodeError::UnexpectedEof(pos)
+            | BencodeError::UnexpectedByte(_, pos)
+            | BencodeError::InvalidInteger(pos)
+            | BencodeError::TrailingData(pos)
+            | BencodeError::NonCanonicalInteger(pos)
+            | BencodeError::UnsortedKey(pos)
+            | BencodeError::DuplicateKey(pos)
+            | BencodeError::TooDeep(pos)
+            | BencodeError::TooLong(pos) => Some(pos),
+            BencodeError::Io(_) => None
+        }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BencodeError::UnexpectedEof(pos) => write!(f, "unexpected end of input at byte {}", pos),
+            BencodeError::UnexpectedByte(byte, pos) => write!(f, "unexpected byte 0x{:02x} at byte {}", byte, pos),
+            BencodeError::InvalidInteger(pos) => write!(f, "invalid integer at byte {}", pos),
+            BencodeError::TrailingData(pos) => write!(f, "trailing data at byte {}", pos),
+            BencodeError::NonCanonicalInteger(pos) => write!(f, "non canonical integer at byte {}", pos),
+            BencodeError::UnsortedKey(pos) => write!(f, "dictionary key out of order at byte {}", pos),
+            BencodeError::DuplicateKey(pos) => write!(f, "duplicate dictionary key at byte {}", pos),
+            BencodeError::TooDeep(pos) => write!(f, "nesting too deep at byte {}", pos),
+            BencodeError::TooLong(pos) => write!(f, "byte string too long at byte {}", pos),
+            BencodeError::Io(ref kind) => write!(f, "unable to read input: {:?}", kind)
         }
     }
 }
 
+impl Error for BencodeError {}
+
+impl From<io::Error> for BencodeError {
+    fn from (err: io::Error) -> BencodeError {
+        BencodeError::Io(err.kind())
+    }
+}
+
 /// Opens a file and returns its contents as vector of bytes
-/// throws an exception for now if ENOENT
-pub fn open_file <P: AsRef<Path>>(path: P) -> Vec<u8> {
-    let mut fd = File::open(path).unwrap();
+pub fn open_file <P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut fd = File::open(path)?;
     let mut buffer:Vec<u8> = Vec::new();
-    let _ = fd.read_to_end(&mut buffer);
-    buffer
+    fd.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Deserializes a bencoded file. The file is decoded as it is read rather than being loaded into
+/// memory first
+pub fn deserialize_file<P: AsRef<Path>>(path: P) -> Result<Vec<Bencode>, BencodeError> {
+    let mut fd = File::open(path)?;
+    let mut decoder = Decoder::new();
+    let mut values = Vec::new();
+    let mut chunk = [0; 65536];
+    loop {
+        let bytes_read = fd.read(&mut chunk)?;
+        if bytes_read == 0 {
+            if !decoder.is_idle() {
+                return Err(BencodeError::UnexpectedEof(decoder.offset()))
+            }
+            return Ok(values)
+        }
+        let mut rest = &chunk[..bytes_read];
+        while !rest.is_empty() {
+            match decoder.push(rest)? {
+                Decoded::Complete(value, used) => {
+                    values.push(value);
+                    rest = &rest[used..];
+                },
+                Decoded::NeedMore => break
+            }
+        }
+    }
 }
 
-/// Deserializes a bencoded file
-pub fn deserialize_file<P: AsRef<Path>>(path: P) -> Option<Vec<Bencode>> {
-    deserialize(&open_file(path))
+/// Controls how forgiving the parser is and how much it is willing to take on. The default is
+/// lenient (anything that decodes unambiguously is accepted) with a generous nesting limit
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ParseOptions {
+    /// Only accept canonical bencode: no leading zeros or -0, and dict keys strictly sorted
+    /// (which also rules out duplicates). Needed wherever a value is signed or hashed, e.g. DHT
+    /// and BEP 44
+    pub strict: bool,
+    /// How deeply lists and dicts may nest. The parser recurses, so this bounds stack usage
+    pub max_depth: usize,
+    /// The longest byte string accepted
+    pub max_len: usize
 }
 
-/// Takes an input (vector of bytes) and returns the deserialized form as a vector of Bencode(d)
-/// objects. Wrapped in an Option.
-///
-/// there's probably an argument that this should be a Result as opposed to an Option, as you
-/// could potentially be losing error pertinent information in an Option. not going to change it over
-/// right now
-pub fn deserialize (byte_vector: &[u8]) -> Option<Vec<Bencode>> {
-    //hack to coerce ascii byte values to rust array sliceof char (UTF-8). necessary to avoid substantial
-    //writing of existing combine parser builtins
-    let as_string: Vec<char> = byte_vector.iter().map(|x| *x as char).collect();
-    match many::<Vec<Bencode>, _>(bencode_any()).parse(&as_string[..]) {
-        Ok((result, _)) => Some(result),
-        Err(_) => None
+impl Default for ParseOptions {
+    fn default () -> ParseOptions {
+        ParseOptions {
+            strict: false,
+            max_depth: 256,
+            max_len: usize::MAX
+        }
+    }
+}
+
+impl ParseOptions {
+    /// Default limits with canonical encoding enforced
+    pub fn strict () -> ParseOptions {
+        ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        }
+    }
+
+    pub fn deserialize (&self, byte_vector: &[u8]) -> Result<Vec<Bencode>, BencodeError> {
+        let values = self.deserialize_ref(byte_vector)?;
+        Ok(values.iter().map(|x| x.to_bencode()).collect())
+    }
+
+    pub fn deserialize_ref<'a> (&self, byte_vector: &'a [u8]) -> Result<Vec<BencodeRef<'a>>, BencodeError> {
+        let mut values = Vec::new();
+        let mut pos = 0;
+        while pos < byte_vector.len() {
+            let (value, next) = bencode_any(byte_vector, pos, self, 0)?;
+            values.push(value);
+            pos = next;
+        }
+        Ok(values)
+    }
+
+    pub fn deserialize_with_spans (&self, byte_vector: &[u8]) -> Result<Vec<SpannedBencode>, BencodeError> {
+        let mut values = Vec::new();
+        let mut pos = 0;
+        while pos < byte_vector.len() {
+            let (value, next) = spanned_any(byte_vector, pos, self, 0)?;
+            values.push(value);
+            pos = next;
+        }
+        Ok(values)
+    }
+
+    pub fn deserialize_value (&self, byte_vector: &[u8]) -> Result<Bencode, BencodeError> {
+        self.deserialize_value_ref(byte_vector).map(|x| x.to_bencode())
+    }
+
+    pub fn deserialize_value_ref<'a> (&self, byte_vector: &'a [u8]) -> Result<BencodeRef<'a>, BencodeError> {
+        let (value, pos) = bencode_any(byte_vector, 0, self, 0)?;
+        if pos < byte_vector.len() {
+            return Err(BencodeError::TrailingData(pos))
+        }
+        Ok(value)
     }
 }
 
+/// Takes an input (slice of bytes) and returns the deserialized form as a vector of Bencode(d)
+/// objects. Every byte of the input has to belong to a value
+pub fn deserialize (byte_vector: &[u8]) -> Result<Vec<Bencode>, BencodeError> {
+    ParseOptions::default().deserialize(byte_vector)
+}
+
+/// Like deserialize, but without copying: byte strings in the result point into the input
+pub fn deserialize_ref (byte_vector: &[u8]) -> Result<Vec<BencodeRef<'_>>, BencodeError> {
+    ParseOptions::default().deserialize_ref(byte_vector)
+}
+
+/// Like deserialize, but each value keeps the byte range of the input it was decoded from
+pub fn deserialize_with_spans (byte_vector: &[u8]) -> Result<Vec<SpannedBencode>, BencodeError> {
+    ParseOptions::default().deserialize_with_spans(byte_vector)
+}
+
+/// Deserializes exactly one value, e.g. a torrent file or a tracker response. Anything after the
+/// value is reported as trailing data (in either mode)
+pub fn deserialize_value (byte_vector: &[u8]) -> Result<Bencode, BencodeError> {
+    ParseOptions::default().deserialize_value(byte_vector)
+}
+
+/// Like deserialize_value, but without copying
+pub fn deserialize_value_ref (byte_vector: &[u8]) -> Result<BencodeRef<'_>, BencodeError> {
+    ParseOptions::default().deserialize_value_ref(byte_vector)
+}
+
 /// Provides typesafe getters for a collection
 pub trait TypedMethods {
     fn get_int(&self, key: &str) -> Option<i64>;
     fn get_string(&self, key: &str) -> Option<&Vec<u8>>;
     fn get_owned_string(&self, key: &str) -> Option<Vec<u8>>;
-    fn get_dict(&self, key: &str) -> Option<&HashMap<String, Bencode>>;
+    fn get_dict(&self, key: &str) -> Option<&BTreeMap<Vec<u8>, Bencode>>;
     fn get_list(&self, key: &str) -> Option<&Vec<Bencode>>;
 }
 
-impl TypedMethods for HashMap<String, Bencode> {
+impl TypedMethods for BTreeMap<Vec<u8>, Bencode> {
     fn get_int (&self, key: &str) -> Option <i64> {
-        match self.get(key) {
+        match self.get(key.as_bytes()) {
             Some(&Bencode::Int(a)) => Some(a),
             _ => None
         }
@@ -149,162 +410,372 @@ impl TypedMethods for HashMap<String, Bencode> {
 
     //yeah... that oversight is killing me. its actually a byte vector
     fn get_string (&self, key: &str) -> Option <&Vec<u8>> {
-        match self.get(key) {
-            Some(&Bencode::ByteString(ref a)) => Some(a),
+        match self.get(key.as_bytes()) {
+            Some(Bencode::ByteString(a)) => Some(a),
             _ => None
         }
     }
 
     fn get_owned_string(&self, key: &str) -> Option <Vec<u8>> {
-        match self.get(key) {
-            Some(&Bencode::ByteString(ref a)) => Some(a.to_owned()),
+        match self.get(key.as_bytes()) {
+            Some(Bencode::ByteString(a)) => Some(a.to_owned()),
             _ => None
         }
     }
 
-    fn get_dict (&self, key: &str) -> Option <&HashMap<String, Bencode>> {
-        match self.get(key) {
-            Some(&Bencode::Dict(ref a)) => Some(a),
+    fn get_dict (&self, key: &str) -> Option <&BTreeMap<Vec<u8>, Bencode>> {
+        match self.get(key.as_bytes()) {
+            Some(Bencode::Dict(a)) => Some(a),
             _ => None
         }
     }
 
     fn get_list (&self, key: &str) -> Option <&Vec<Bencode>> {
-        match self.get(key) {
-            Some(&Bencode::List(ref a)) => Some(a),
+        match self.get(key.as_bytes()) {
+            Some(Bencode::List(a)) => Some(a),
             _ => None
         }
     }
 }
 
-fn bencode_integer<I>(input: State<I>) -> ParseResult<i64, I> where I: Stream<Item=char> {
-    let (open, close) = (char('i'), char('e'));
-    let mut int = between(open, close, many1::<String, _>(digit())).map(|x| {
-        x.parse::<i64>().unwrap()
-    });
-    int.parse_state(input)
+//the parsers below all take the complete input and the offset to start at, and return the parsed
+//value along with the offset just past it. keeping the whole input around (instead of advancing
+//a slice) means errors can always report an absolute byte offset
+
+#[inline]
+fn peek (bytes: &[u8], pos: usize) -> Result<u8, BencodeError> {
+    match bytes.get(pos) {
+        Some(byte) => Ok(*byte),
+        None => Err(BencodeError::UnexpectedEof(pos))
+    }
 }
 
-fn bencode_string<I>(input: State<I>) -> ParseResult<Vec<u8>, I> where I: Stream<Item=char> {
-    let (len, input_) = try!(bencode_string_length_prefix(input));
-    input_.combine(|input__| take(len).parse_state(input__))
+#[inline]
+fn expect (bytes: &[u8], pos: usize, expected: u8) -> Result<usize, BencodeError> {
+    match peek(bytes, pos)? {
+        byte if byte == expected => Ok(pos + 1),
+        byte => Err(BencodeError::UnexpectedByte(byte, pos))
+    }
 }
 
-fn bencode_string_length_prefix<I>(input: State<I>) -> ParseResult<i32, I> where I:Stream<Item=char> {
-    let many_digit = many1::<String, _>(digit());
-    let mut get_len = (many_digit, char(':')).map(|(length, _)| length.parse::<i32>().unwrap());
-    get_len.parse_state(input)
+/// Reads a run of ascii digits starting at pos, returning the digits and the offset after them
+fn digits (bytes: &[u8], pos: usize) -> Result<(&[u8], usize), BencodeError> {
+    let len = bytes[pos..].iter().take_while(|b| (**b as char).is_ascii_digit()).count();
+    match (len, bytes.get(pos + len)) {
+        (_, None) => Err(BencodeError::UnexpectedEof(pos + len)),
+        (0, Some(_)) => Err(BencodeError::InvalidInteger(pos)),
+        _ => Ok((&bytes[pos..pos + len], pos + len))
+    }
 }
 
-fn bencode_list<I>(input: State<I>) -> ParseResult<Vec<Bencode>, I> where I: Stream<Item=char> {
-    let (open, close) = (char('l'), char('e'));
-    let list_contents = many::<Vec<Bencode>, _>(bencode_any());
-    let mut list = between(open, close, list_contents);
-    list.parse_state(input)
+/// Leading zeros and -0 are tolerated unless parsing strictly, there's only one canonical way to
+/// write each integer
+fn is_canonical (digits: &[u8], negative: bool) -> bool {
+    match digits {
+        b"0" => !negative,
+        _ => digits.first() != Some(&b'0')
+    }
 }
 
-fn bencode_dict<I>(input: State<I>) -> ParseResult<HashMap<String, Bencode>, I> where I: Stream<Item=char> {
-    let (open, close) = (char('d'), char('e'));
-    let pairs = (parser(bencode_string), bencode_any());
-    let mut dict = between(open, close, many(pairs)).map(|entries:Vec<(Vec<u8>, Bencode)>|{
-        let mut hash_map = HashMap::new();
-        for (k, v) in entries {
-            // this is a dangerous assumption
-            let key_as_string = str::from_utf8(&k).unwrap().to_string();
-            hash_map.insert(key_as_string, v);
-        }
-        hash_map
-    });
-    dict.parse_state(input)
-}
-
-fn bencode_any<I>() -> FnParser<I, fn (State<I>) -> ParseResult<Bencode, I>> where I: Stream<Item=char> {
-    fn bencode_any_<I>(input: State<I>) -> ParseResult<Bencode, I> where I: Stream<Item=char> {
-        parser(bencode_integer).map(Bencode::Int)
-            .or(parser(bencode_string).map(Bencode::ByteString))
-            .or(parser(bencode_list).map(Bencode::List))
-            .or(parser(bencode_dict).map(Bencode::Dict)).parse_state(input)
-    }
-    parser(bencode_any_)
-}
-
-fn take <I> (num: i32) -> SizedBuffer<I> where I: Stream<Item=char> {
-    SizedBuffer(num, PhantomData)
-}
-
-#[derive(Clone)]
-struct SizedBuffer <I>(i32, PhantomData<I>);
-impl <I> Parser for SizedBuffer<I> where I: Stream<Item=char> {
-    type Input = I;
-    type Output = Vec<u8>;
-    fn parse_lazy(&mut self, mut input: State<I>) -> ParseResult<Vec<u8>, I> {
-        let start = input.position;
-        let mut vec_buf:Vec<char> = Vec::new();
-        for _ in 0..self.0 {
-            match input.uncons() {
-                Ok((other, rest)) => {
-                    vec_buf.push(other);
-                    input = rest.into_inner();
-                }
-                Err(error) => {
-                    return error.combine(|mut error| {
-                        error.position = start;
-                        Err(Consumed::Consumed(error))
-                    })
-                }
-            };
-        }
-        Ok((vec_buf.clone().iter().map(|x| *x as u8).collect::<Vec<u8>>(), Consumed::Consumed(input)))
+/// Converts ascii digits to an integer, None if it would overflow
+fn int_from_digits (digits: &[u8], negative: bool) -> Option<i64> {
+    //accumulate negatively so i64::MIN is representable
+    let mut int: i64 = 0;
+    for digit in digits {
+        int = int.checked_mul(10)?.checked_sub((digit - b'0') as i64)?;
+    }
+    if negative { Some(int) } else { int.checked_neg() }
+}
+
+/// Converts ascii digits to a string length, None if it would overflow
+fn len_from_digits (digits: &[u8]) -> Option<usize> {
+    let mut len: usize = 0;
+    for digit in digits {
+        len = len.checked_mul(10)?.checked_add((digit - b'0') as usize)?;
+    }
+    Some(len)
+}
+
+fn bencode_integer (bytes: &[u8], pos: usize, options: &ParseOptions) -> Result<(i64, usize), BencodeError> {
+    let start = expect(bytes, pos, b'i')?;
+    let negative = peek(bytes, start)? == b'-';
+    let (num, end) = digits(bytes, if negative { start + 1 } else { start })?;
+    let next = expect(bytes, end, b'e')?;
+    if options.strict && !is_canonical(num, negative) {
+        return Err(BencodeError::NonCanonicalInteger(pos))
+    }
+    match int_from_digits(num, negative) {
+        Some(int) => Ok((int, next)),
+        None => Err(BencodeError::InvalidInteger(pos))
+    }
+}
+
+fn bencode_string<'a> (bytes: &'a [u8], pos: usize, options: &ParseOptions) -> Result<(&'a [u8], usize), BencodeError> {
+    let (len, start) = bencode_string_length_prefix(bytes, pos, options)?;
+    match start.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok((&bytes[start..end], end)),
+        _ => Err(BencodeError::UnexpectedEof(bytes.len()))
+    }
+}
+
+fn bencode_string_length_prefix (bytes: &[u8], pos: usize, options: &ParseOptions) -> Result<(usize, usize), BencodeError> {
+    let (num, end) = digits(bytes, pos)?;
+    let next = expect(bytes, end, b':')?;
+    if options.strict && !is_canonical(num, false) {
+        return Err(BencodeError::NonCanonicalInteger(pos))
+    }
+    match len_from_digits(num) {
+        Some(len) if len > options.max_len => Err(BencodeError::TooLong(pos)),
+        Some(len) => Ok((len, next)),
+        None => Err(BencodeError::InvalidInteger(pos))
+    }
+}
+
+/// Reads the next dict key, checking it sorts after the previous one if parsing strictly
+fn bencode_key<'a> (bytes: &'a [u8], pos: usize, options: &ParseOptions, previous: Option<&[u8]>) -> Result<(&'a [u8], usize), BencodeError> {
+    let (key, next) = bencode_string(bytes, pos, options)?;
+    match previous {
+        Some(previous) if options.strict && key == previous => Err(BencodeError::DuplicateKey(pos)),
+        Some(previous) if options.strict && key < previous => Err(BencodeError::UnsortedKey(pos)),
+        _ => Ok((key, next))
+    }
+}
+
+/// Enters a list or dict, checking the nesting limit
+#[inline]
+fn open (bytes: &[u8], pos: usize, options: &ParseOptions, depth: usize, expected: u8) -> Result<usize, BencodeError> {
+    if depth >= options.max_depth {
+        return Err(BencodeError::TooDeep(pos))
+    }
+    expect(bytes, pos, expected)
+}
+
+fn bencode_list<'a> (bytes: &'a [u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(Vec<BencodeRef<'a>>, usize), BencodeError> {
+    let mut pos = open(bytes, pos, options, depth, b'l')?;
+    let mut list = Vec::new();
+    while peek(bytes, pos)? != b'e' {
+        let (item, next) = bencode_any(bytes, pos, options, depth + 1)?;
+        list.push(item);
+        pos = next;
+    }
+    Ok((list, pos + 1))
+}
+
+fn bencode_dict<'a> (bytes: &'a [u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(BTreeMap<&'a [u8], BencodeRef<'a>>, usize), BencodeError> {
+    let mut pos = open(bytes, pos, options, depth, b'd')?;
+    let mut dict = BTreeMap::new();
+    let mut previous = None;
+    while peek(bytes, pos)? != b'e' {
+        let (key, value_start) = bencode_key(bytes, pos, options, previous)?;
+        let (value, next) = bencode_any(bytes, value_start, options, depth + 1)?;
+        dict.insert(key, value);
+        previous = Some(key);
+        pos = next;
+    }
+    Ok((dict, pos + 1))
+}
+
+fn bencode_any<'a> (bytes: &'a [u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(BencodeRef<'a>, usize), BencodeError> {
+    match peek(bytes, pos)? {
+        b'i' => bencode_integer(bytes, pos, options).map(|(x, next)| (BencodeRef::Int(x), next)),
+        b'l' => bencode_list(bytes, pos, options, depth).map(|(x, next)| (BencodeRef::List(x), next)),
+        b'd' => bencode_dict(bytes, pos, options, depth).map(|(x, next)| (BencodeRef::Dict(x), next)),
+        b'0'..=b'9' => bencode_string(bytes, pos, options).map(|(x, next)| (BencodeRef::ByteString(x), next)),
+        byte => Err(BencodeError::UnexpectedByte(byte, pos))
+    }
+}
+
+//same shape as the above, but keeping track of spans (and owning the strings, since the only
+//reason to want spans is to go back to the input later)
+fn spanned_list (bytes: &[u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(Vec<SpannedBencode>, usize), BencodeError> {
+    let mut pos = open(bytes, pos, options, depth, b'l')?;
+    let mut list = Vec::new();
+    while peek(bytes, pos)? != b'e' {
+        let (item, next) = spanned_any(bytes, pos, options, depth + 1)?;
+        list.push(item);
+        pos = next;
+    }
+    Ok((list, pos + 1))
+}
+
+fn spanned_dict (bytes: &[u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(BTreeMap<Vec<u8>, SpannedBencode>, usize), BencodeError> {
+    let mut pos = open(bytes, pos, options, depth, b'd')?;
+    let mut dict = BTreeMap::new();
+    let mut previous = None;
+    while peek(bytes, pos)? != b'e' {
+        let (key, value_start) = bencode_key(bytes, pos, options, previous)?;
+        let (value, next) = spanned_any(bytes, value_start, options, depth + 1)?;
+        dict.insert(key.to_vec(), value);
+        previous = Some(key);
+        pos = next;
+    }
+    Ok((dict, pos + 1))
+}
+
+fn spanned_any (bytes: &[u8], pos: usize, options: &ParseOptions, depth: usize) -> Result<(SpannedBencode, usize), BencodeError> {
+    let span = |end| Span {start: pos, end};
+    match peek(bytes, pos)? {
+        b'i' => bencode_integer(bytes, pos, options).map(|(x, next)| (SpannedBencode::Int(x, span(next)), next)),
+        b'l' => spanned_list(bytes, pos, options, depth).map(|(x, next)| (SpannedBencode::List(x, span(next)), next)),
+        b'd' => spanned_dict(bytes, pos, options, depth).map(|(x, next)| (SpannedBencode::Dict(x, span(next)), next)),
+        b'0'..=b'9' => bencode_string(bytes, pos, options).map(|(x, next)| (SpannedBencode::ByteString(x.to_vec(), span(next)), next)),
+        byte => Err(BencodeError::UnexpectedByte(byte, pos))
     }
 }
 
 #[test]
 fn test_integer() {
-    let result = parser(bencode_integer).parse("i57e");
-    assert_eq!(result, Ok((57,"")));
+    let options = ParseOptions::default();
+    assert_eq!(bencode_integer(b"i57e", 0, &options), Ok((57, 4)));
+    assert_eq!(bencode_integer(b"i-57e", 0, &options), Ok((-57, 5)));
+    assert_eq!(bencode_integer(b"i-9223372036854775808e", 0, &options), Ok((i64::MIN, 22)));
 }
 
 #[test]
 fn test_string() {
-    let result = parser(bencode_string).parse("5:abcde");
-    assert_eq!(result, Ok(("abcde".to_string(), "")));;
+    let result = bencode_string(b"5:abcde", 0, &ParseOptions::default());
+    assert_eq!(result, Ok((&b"abcde"[..], 7)));
 }
 
 #[test]
 fn test_list() {
-    let homogenous_int_list = parser(bencode_list).parse("li57ei32ee");
-    assert_eq!(homogenous_int_list, Ok((vec![Bencode::Int(57), Bencode::Int(32)], "")));
+    let homogenous_int_list = deserialize_value(b"li57ei32ee");
+    assert_eq!(homogenous_int_list, Ok(Bencode::List(vec![Bencode::Int(57), Bencode::Int(32)])));
 
-    let homogenous_str_list = parser(bencode_list).parse("l3:abc5:defghe");
-    assert_eq!(homogenous_str_list, Ok((vec![Bencode::ByteString("abc".to_string()), Bencode::ByteString("defgh".to_string())], "")));
+    let homogenous_str_list = deserialize_value(b"l3:abc5:defghe");
+    assert_eq!(homogenous_str_list, Ok(Bencode::List(vec![Bencode::ByteString(b"abc".to_vec()), Bencode::ByteString(b"defgh".to_vec())])));
 
-    let hetero_list = parser(bencode_list).parse("li32e3:abce");
-    assert_eq!(hetero_list, Ok((vec![Bencode::Int(32), Bencode::ByteString("abc".to_string())], "")));
+    let hetero_list = deserialize_value(b"li32e3:abce");
+    assert_eq!(hetero_list, Ok(Bencode::List(vec![Bencode::Int(32), Bencode::ByteString(b"abc".to_vec())])));
 }
 
 #[test]
 fn test_dict() {
-    let (dict_result, _) = parser(bencode_dict).parse("d3:abci4e4:andyli5eee").unwrap();
-    assert_eq!(*dict_result.get("abc").unwrap(), Bencode::Int(4));
-    assert_eq!(*dict_result.get("andy").unwrap(), Bencode::List(vec![Bencode::Int(5)]));
+    let (dict_result, _) = bencode_dict(b"d3:abci4e4:andyli5eee", 0, &ParseOptions::default(), 0).unwrap();
+    assert_eq!(dict_result.get(&b"abc"[..]), Some(&BencodeRef::Int(4)));
+    assert_eq!(dict_result.get(&b"andy"[..]), Some(&BencodeRef::List(vec![BencodeRef::Int(5)])));
     assert_eq!(dict_result.len(), 2);
 }
 
+#[test]
+fn test_borrowed() {
+    //a torrent sized pieces string should come back as a view of the input, not a copy
+    let pieces = vec![b'x'; 5 * 1024 * 1024];
+    let mut input = format!("d6:pieces{}:", pieces.len()).into_bytes();
+    input.extend(pieces.iter());
+    input.push(b'e');
+
+    let value = deserialize_value_ref(&input).unwrap();
+    let parsed = value.get("pieces").and_then(|x| x.as_bytes()).unwrap();
+    assert_eq!(parsed.len(), pieces.len());
+    assert_eq!(parsed.as_ptr(), input[input.len() - 1 - pieces.len()..].as_ptr());
+
+    let mut owned = BTreeMap::new();
+    owned.insert(b"pieces".to_vec(), Bencode::ByteString(pieces));
+    assert_eq!(value.to_bencode(), Bencode::Dict(owned));
+}
+
+#[test]
+fn test_binary_keys() {
+    let value = deserialize_value(b"d2:\xff\xfei2e1:ai1ee").unwrap();
+    let dict = match value {
+        Bencode::Dict(ref dict) => dict,
+        _ => panic!("expected a dict")
+    };
+    assert_eq!(dict.get(&b"\xff\xfe"[..]), Some(&Bencode::Int(2)));
+    assert_eq!(dict.get_int("a"), Some(1));
+    //serialized in raw byte order, so the binary key comes last
+    assert_eq!(&value.to_bencode_string()[..], &b"d1:ai1e2:\xff\xfei2ee"[..]);
+}
+
+#[test]
+fn test_spans() {
+    //deliberately non canonical: unsorted keys and a leading zero
+    let input = b"d4:spami01e4:infod1:bi2e1:ai1eee";
+    let values = deserialize_with_spans(input).unwrap();
+    assert_eq!(values[0].span(), Span {start: 0, end: input.len()});
+
+    let info = values[0].get("info").unwrap();
+    assert_eq!(info.span().of(input), b"d1:bi2e1:ai1ee");
+    assert_eq!(info.get("a").unwrap().span().of(input), b"i1e");
+    assert_eq!(values[0].get("spam").unwrap().span().of(input), b"i01e");
+}
+
 #[test]
 fn test_many() {
-    let input_string = "i1ei2ei3ei10el3:abc4:defged1:ai10ee";
-    let (result, _) = many::<Vec<Bencode>, _>(bencode_any()).parse(input_string).unwrap();
-    let mut my_map = HashMap::new();
-    my_map.insert("a".to_string(), Bencode::Int(10));
+    let input_string = b"i1ei2ei3ei10el3:abc4:defged1:ai10ee";
+    let result = deserialize(input_string).unwrap();
+    let mut my_map = BTreeMap::new();
+    my_map.insert(b"a".to_vec(), Bencode::Int(10));
     assert_eq!(result, vec![
         Bencode::Int(1),
         Bencode::Int(2),
         Bencode::Int(3),
         Bencode::Int(10),
         Bencode::List(vec![
-            Bencode::ByteString("abc".to_string()),
-            Bencode::ByteString("defg".to_string())
+            Bencode::ByteString(b"abc".to_vec()),
+            Bencode::ByteString(b"defg".to_vec())
             ]),
         Bencode::Dict(my_map)
     ]);
 }
+
+#[test]
+fn test_errors() {
+    assert_eq!(deserialize(b"li1ei2e"), Err(BencodeError::UnexpectedEof(7)));
+    assert_eq!(deserialize(b"10:abc"), Err(BencodeError::UnexpectedEof(6)));
+    assert_eq!(deserialize(b"i12a4e"), Err(BencodeError::UnexpectedByte(b'a', 3)));
+    assert_eq!(deserialize(b"ie"), Err(BencodeError::InvalidInteger(1)));
+    assert_eq!(deserialize(b"i99999999999999999999e"), Err(BencodeError::InvalidInteger(0)));
+    assert_eq!(deserialize(b"i1ex"), Err(BencodeError::UnexpectedByte(b'x', 3)));
+    assert_eq!(deserialize_value(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+    assert_eq!(deserialize_value(b"i1e"), Ok(Bencode::Int(1)));
+}
+
+#[test]
+fn test_serialize_nested_list() {
+    let mut file = BTreeMap::new();
+    file.insert(b"length".to_vec(), Bencode::Int(3));
+    file.insert(b"path".to_vec(), Bencode::List(vec![Bencode::ByteString(b"a".to_vec()), Bencode::ByteString(b"b".to_vec())]));
+    let mut info = BTreeMap::new();
+    info.insert(b"files".to_vec(), Bencode::List(vec![Bencode::Dict(file), Bencode::List(vec![])]));
+
+    let encoded = Bencode::Dict(info).to_bencode_string();
+    assert_eq!(&encoded[..], &b"d5:filesld6:lengthi3e4:pathl1:a1:beeleee"[..]);
+}
+
+#[test]
+fn test_strict() {
+    let strict = ParseOptions::strict();
+    //lenient mode takes all of these
+    for input in [&b"i03e"[..], b"i-0e", b"i-03e", b"03:abc", b"d1:bi1e1:ai2ee", b"d1:ai1e1:ai2ee"].iter() {
+        assert!(deserialize_value(input).is_ok());
+    }
+    assert_eq!(strict.deserialize_value(b"i03e"), Err(BencodeError::NonCanonicalInteger(0)));
+    assert_eq!(strict.deserialize_value(b"i-0e"), Err(BencodeError::NonCanonicalInteger(0)));
+    assert_eq!(strict.deserialize_value(b"li1e03:abce"), Err(BencodeError::NonCanonicalInteger(4)));
+    assert_eq!(strict.deserialize_value(b"d1:bi1e1:ai2ee"), Err(BencodeError::UnsortedKey(7)));
+    assert_eq!(strict.deserialize_value(b"d1:ai1e1:ai2ee"), Err(BencodeError::DuplicateKey(7)));
+    assert_eq!(strict.deserialize_value(b"i1ei2e"), Err(BencodeError::TrailingData(3)));
+
+    assert_eq!(strict.deserialize_value(b"i-42e"), Ok(Bencode::Int(-42)));
+    assert_eq!(strict.deserialize_value(b"i0e"), Ok(Bencode::Int(0)));
+    assert!(strict.deserialize_value(b"d1:ai1e2:aai2e1:bi3ee").is_ok());
+}
+
+#[test]
+fn test_limits() {
+    let options = ParseOptions {max_depth: 3, max_len: 4, ..ParseOptions::default()};
+    assert!(options.deserialize_value(b"llleee").is_ok());
+    assert_eq!(options.deserialize_value(b"lllleeee"), Err(BencodeError::TooDeep(3)));
+    assert_eq!(options.deserialize_value(b"l5:abcdee"), Err(BencodeError::TooLong(1)));
+
+    //the default limit keeps hostile nesting from blowing the stack
+    let deep = vec![b'l'; 100000];
+    assert_eq!(deserialize(&deep), Err(BencodeError::TooDeep(256)));
+}
+
+#[test]
+fn test_missing_file() {
+    assert_eq!(deserialize_file("no/such/file.torrent"), Err(BencodeError::Io(io::ErrorKind::NotFound)));
+}
@@ -0,0 +1,463 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use super::Bencode;
+
+//conversions between Bencode and plain rust types, so consumers can declare what a dictionary
+//looks like (see bencode_struct!) instead of walking it by hand with TypedMethods
+
+/// Why a Bencode value couldn't be converted. Each variant carries the path of dict keys (or
+/// list indices) leading from the outermost value to the offending one
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum FromBencodeError {
+    /// A required key is absent
+    MissingKey(Vec<String>),
+    /// The value isn't of the expected shape, e.g. a list where an integer was expected
+    WrongType(&'static str, Vec<String>),
+    /// A byte string that should have been text isn't valid UTF-8
+    InvalidUtf8(Vec<String>),
+    /// An integer that doesn't fit the target type
+    OutOfRange(Vec<String>)
+}
+
+impl FromBencodeError {
+    pub fn path (&self) -> &[String] {
+        match *self {
+            FromBencodeError::MissingKey(ref path)
+            | FromBencodeError::WrongType(_, ref path)
+            | FromBencodeError::InvalidUtf8(ref path)
+            | FromBencodeError::OutOfRange(ref path) => path
+        }
+    }
+
+    /// Prefixes the path with the key the failing value was found under
+    pub fn at (mut self, key: &str) -> FromBencodeError {
+        match self {
+            FromBencodeError::MissingKey(ref mut path)
+            | FromBencodeError::WrongType(_, ref mut path)
+            | FromBencodeError::InvalidUtf8(ref mut path)
+            | FromBencodeError::OutOfRange(ref mut path) => path.insert(0, key.to_string())
+        }
+        self
+    }
+}
+
+impl fmt::Display for FromBencodeError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path().join("/");
+        match *self {
+            FromBencodeError::MissingKey(_) => write!(f, "missing key {}", path),
+            FromBencodeError::WrongType(expected, _) => write!(f, "expected {} at {}", expected, path),
+            FromBencodeError::InvalidUtf8(_) => write!(f, "invalid UTF-8 at {}", path),
+            FromBencodeError::OutOfRange(_) => write!(f, "integer out of range at {}", path)
+        }
+    }
+}
+
+impl Error for FromBencodeError {}
+
+/// Why a value couldn't be written as Bencode, with the path to it as in FromBencodeError
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ToBencodeError {
+    /// An unsigned integer above i64::MAX, the largest integer Bencode holds
+    OutOfRange(Vec<String>),
+    /// A None anywhere but a struct field, where it would leave the key out
+    MissingValue(Vec<String>)
+}
+
+impl ToBencodeError {
+    pub fn path (&self) -> &[String] {
+        match *self {
+            ToBencodeError::OutOfRange(ref path)
+            | ToBencodeError::MissingValue(ref path) => path
+        }
+    }
+
+    /// Prefixes the path with the key the failing value was stored under
+    pub fn at (mut self, key: &str) -> ToBencodeError {
+        match self {
+            ToBencodeError::OutOfRange(ref mut path)
+            | ToBencodeError::MissingValue(ref mut path) => path.insert(0, key.to_string())
+        }
+        self
+    }
+}
+
+impl fmt::Display for ToBencodeError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path().join("/");
+        match *self {
+            ToBencodeError::OutOfRange(_) => write!(f, "integer out of range at {}", path),
+            ToBencodeError::MissingValue(_) => write!(f, "no value at {}", path)
+        }
+    }
+}
+
+impl Error for ToBencodeError {}
+
+pub trait ToBencode {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError>;
+
+    /// What to store under a dict key for this value. None leaves the key out entirely, which is
+    /// how Option fields disappear when they're None
+    fn to_bencode_entry (&self) -> Result<Option<Bencode>, ToBencodeError> {
+        self.to_bencode().map(Some)
+    }
+}
+
+pub trait FromBencode: Sized {
+    fn from_bencode (value: &Bencode) -> Result<Self, FromBencodeError>;
+
+    /// What to use when a dict key for this value is absent. None makes the key required
+    fn from_missing () -> Option<Self> {
+        None
+    }
+}
+
+/// Reads a dict entry, used by bencode_struct!. Errors get the key prepended to their path
+pub fn from_dict_entry <T: FromBencode> (dict: &BTreeMap<Vec<u8>, Bencode>, key: &str) -> Result<T, FromBencodeError> {
+    match dict.get(key.as_bytes()) {
+        Some(value) => T::from_bencode(value).map_err(|e| e.at(key)),
+        None => T::from_missing().ok_or_else(|| FromBencodeError::MissingKey(vec![key.to_string()]))
+    }
+}
+
+impl ToBencode for Bencode {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(self.clone())
+    }
+}
+
+impl FromBencode for Bencode {
+    fn from_bencode (value: &Bencode) -> Result<Bencode, FromBencodeError> {
+        Ok(value.clone())
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($int:ty),*) => {
+        $(
+            impl ToBencode for $int {
+                fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+                    if *self as i128 > i64::MAX as i128 {
+                        return Err(ToBencodeError::OutOfRange(vec![]))
+                    }
+                    Ok(Bencode::Int(*self as i64))
+                }
+            }
+
+            impl FromBencode for $int {
+                fn from_bencode (value: &Bencode) -> Result<$int, FromBencodeError> {
+                    match *value {
+                        Bencode::Int(int) if int >= <$int>::MIN as i64 && int as i128 <= <$int>::MAX as i128 => Ok(int as $int),
+                        Bencode::Int(_) => Err(FromBencodeError::OutOfRange(vec![])),
+                        _ => Err(FromBencodeError::WrongType("integer", vec![]))
+                    }
+                }
+            }
+        )*
+    }
+}
+
+//u8 is left out on purpose, Vec<u8> is a byte string rather than a list of integers
+integer_conversions!(i64, i32, i16, i8, u64, u32, u16, usize);
+
+/// Flags like `private` are integers that are either 0 or 1
+impl ToBencode for bool {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(Bencode::Int(*self as i64))
+    }
+}
+
+impl FromBencode for bool {
+    fn from_bencode (value: &Bencode) -> Result<bool, FromBencodeError> {
+        match *value {
+            Bencode::Int(0) => Ok(false),
+            Bencode::Int(1) => Ok(true),
+            Bencode::Int(_) => Err(FromBencodeError::OutOfRange(vec![])),
+            _ => Err(FromBencodeError::WrongType("integer", vec![]))
+        }
+    }
+}
+
+impl ToBencode for Vec<u8> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(Bencode::ByteString(self.clone()))
+    }
+}
+
+impl FromBencode for Vec<u8> {
+    fn from_bencode (value: &Bencode) -> Result<Vec<u8>, FromBencodeError> {
+        match *value {
+            Bencode::ByteString(ref string) => Ok(string.clone()),
+            _ => Err(FromBencodeError::WrongType("byte string", vec![]))
+        }
+    }
+}
+
+/// Fixed size byte strings: info hashes, peer ids
+impl ToBencode for [u8; 20] {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(Bencode::ByteString(self.to_vec()))
+    }
+}
+
+impl FromBencode for [u8; 20] {
+    fn from_bencode (value: &Bencode) -> Result<[u8; 20], FromBencodeError> {
+        match *value {
+            Bencode::ByteString(ref string) if string.len() == 20 => {
+                let mut array = [0; 20];
+                array.copy_from_slice(string);
+                Ok(array)
+            },
+            _ => Err(FromBencodeError::WrongType("20 byte string", vec![]))
+        }
+    }
+}
+
+impl ToBencode for String {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(Bencode::ByteString(self.as_bytes().to_vec()))
+    }
+}
+
+impl FromBencode for String {
+    fn from_bencode (value: &Bencode) -> Result<String, FromBencodeError> {
+        match *value {
+            Bencode::ByteString(ref string) => String::from_utf8(string.clone()).map_err(|_| FromBencodeError::InvalidUtf8(vec![])),
+            _ => Err(FromBencodeError::WrongType("byte string", vec![]))
+        }
+    }
+}
+
+impl <T: ToBencode> ToBencode for Vec<T> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        self.iter().enumerate()
+            .map(|(i, x)| x.to_bencode().map_err(|e| e.at(&i.to_string())))
+            .collect::<Result<Vec<Bencode>, ToBencodeError>>()
+            .map(Bencode::List)
+    }
+}
+
+impl <T: FromBencode> FromBencode for Vec<T> {
+    fn from_bencode (value: &Bencode) -> Result<Vec<T>, FromBencodeError> {
+        match *value {
+            Bencode::List(ref list) => {
+                list.iter().enumerate()
+                    .map(|(i, x)| T::from_bencode(x).map_err(|e| e.at(&i.to_string())))
+                    .collect()
+            },
+            _ => Err(FromBencodeError::WrongType("list", vec![]))
+        }
+    }
+}
+
+/// None has no bencode representation of its own. As a struct field the key is left out; anywhere
+/// else, such as in a list, it is an error
+impl <T: ToBencode> ToBencode for Option<T> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        match *self {
+            Some(ref value) => value.to_bencode(),
+            None => Err(ToBencodeError::MissingValue(vec![]))
+        }
+    }
+
+    fn to_bencode_entry (&self) -> Result<Option<Bencode>, ToBencodeError> {
+        match *self {
+            Some(ref value) => value.to_bencode().map(Some),
+            None => Ok(None)
+        }
+    }
+}
+
+impl <T: FromBencode> FromBencode for Option<T> {
+    fn from_bencode (value: &Bencode) -> Result<Option<T>, FromBencodeError> {
+        T::from_bencode(value).map(Some)
+    }
+
+    fn from_missing () -> Option<Option<T>> {
+        Some(None)
+    }
+}
+
+impl <T: ToBencode> ToBencode for BTreeMap<Vec<u8>, T> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        self.iter()
+            .map(|(k, v)| v.to_bencode().map(|x| (k.clone(), x)).map_err(|e| e.at(&String::from_utf8_lossy(k))))
+            .collect::<Result<BTreeMap<Vec<u8>, Bencode>, ToBencodeError>>()
+            .map(Bencode::Dict)
+    }
+}
+
+impl <T: FromBencode> FromBencode for BTreeMap<Vec<u8>, T> {
+    fn from_bencode (value: &Bencode) -> Result<BTreeMap<Vec<u8>, T>, FromBencodeError> {
+        match *value {
+            Bencode::Dict(ref dict) => {
+                dict.iter()
+                    .map(|(k, v)| T::from_bencode(v).map(|x| (k.clone(), x)).map_err(|e| e.at(&String::from_utf8_lossy(k))))
+                    .collect()
+            },
+            _ => Err(FromBencodeError::WrongType("dict", vec![]))
+        }
+    }
+}
+
+impl <T: ToBencode> ToBencode for BTreeMap<String, T> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        self.iter()
+            .map(|(k, v)| v.to_bencode().map(|x| (k.as_bytes().to_vec(), x)).map_err(|e| e.at(k)))
+            .collect::<Result<BTreeMap<Vec<u8>, Bencode>, ToBencodeError>>()
+            .map(Bencode::Dict)
+    }
+}
+
+impl <T: FromBencode> FromBencode for BTreeMap<String, T> {
+    fn from_bencode (value: &Bencode) -> Result<BTreeMap<String, T>, FromBencodeError> {
+        let dict: BTreeMap<Vec<u8>, T> = FromBencode::from_bencode(value)?;
+        dict.into_iter()
+            .map(|(k, v)| match String::from_utf8(k) {
+                Ok(k) => Ok((k, v)),
+                Err(e) => Err(FromBencodeError::InvalidUtf8(vec![String::from_utf8_lossy(e.as_bytes()).into_owned()]))
+            })
+            .collect()
+    }
+}
+
+impl <T: ToBencode> ToBencode for HashMap<String, T> {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        self.iter()
+            .map(|(k, v)| v.to_bencode().map(|x| (k.as_bytes().to_vec(), x)).map_err(|e| e.at(k)))
+            .collect::<Result<BTreeMap<Vec<u8>, Bencode>, ToBencodeError>>()
+            .map(Bencode::Dict)
+    }
+}
+
+impl <T: FromBencode> FromBencode for HashMap<String, T> {
+    fn from_bencode (value: &Bencode) -> Result<HashMap<String, T>, FromBencodeError> {
+        let dict: BTreeMap<String, T> = FromBencode::from_bencode(value)?;
+        Ok(dict.into_iter().collect())
+    }
+}
+
+/// Declares a struct that is read from and written to a bencoded dictionary. Each field names the
+/// dict key it lives under; Option fields are optional keys, everything else is required.
+///
+/// ```
+/// #[macro_use] extern crate bencode;
+/// use bencode::{FromBencode, ToBencode, deserialize_value};
+///
+/// bencode_struct! {
+///     #[derive(Debug, PartialEq)]
+///     pub struct File {
+///         "length" => pub length: i64,
+///         "md5sum" => pub md5sum: Option<Vec<u8>>,
+///         "path" => pub path: Vec<String>
+///     }
+/// }
+///
+/// # fn main() {
+/// let value = deserialize_value(b"d6:lengthi12e4:pathl1:a1:bee").unwrap();
+/// let file = File::from_bencode(&value).unwrap();
+/// assert_eq!(file.path, vec!["a".to_string(), "b".to_string()]);
+/// assert_eq!(file.to_bencode(), Ok(value));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! bencode_struct {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_attr:meta])* $key:expr => $field_vis:vis $field:ident : $ty:ty),* $(,)*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $($(#[$field_attr])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::ToBencode for $name {
+            fn to_bencode (&self) -> Result<$crate::Bencode, $crate::ToBencodeError> {
+                let mut dict = ::std::collections::BTreeMap::new();
+                $(
+                    if let Some(value) = $crate::ToBencode::to_bencode_entry(&self.$field).map_err(|e| e.at($key))? {
+                        dict.insert($key.as_bytes().to_vec(), value);
+                    }
+                )*
+                Ok($crate::Bencode::Dict(dict))
+            }
+        }
+
+        impl $crate::FromBencode for $name {
+            fn from_bencode (value: &$crate::Bencode) -> Result<$name, $crate::FromBencodeError> {
+                match *value {
+                    $crate::Bencode::Dict(ref dict) => Ok($name {
+                        $($field: $crate::from_dict_entry(dict, $key)?),*
+                    }),
+                    _ => Err($crate::FromBencodeError::WrongType("dict", vec![]))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+bencode_struct! {
+    #[derive(Debug, PartialEq)]
+    struct Inner {
+        "piece length" => piece_length: u32,
+        "name" => name: String
+    }
+}
+
+#[cfg(test)]
+bencode_struct! {
+    #[derive(Debug, PartialEq)]
+    struct Outer {
+        "info" => info: Inner,
+        "announce-list" => announce_list: Option<Vec<Vec<String>>>,
+        "private" => private: Option<bool>
+    }
+}
+
+#[test]
+fn test_struct_roundtrip() {
+    use super::{deserialize_value, BencodeToString};
+    let input = b"d13:announce-listll1:a1:bel1:cee4:infod4:name3:abc12:piece lengthi16384eee";
+    let value = deserialize_value(input).unwrap();
+    let outer = Outer::from_bencode(&value).unwrap();
+    assert_eq!(outer, Outer {
+        info: Inner {piece_length: 16384, name: "abc".to_string()},
+        announce_list: Some(vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]]),
+        private: None
+    });
+    //private is None, so it is left out
+    assert_eq!(outer.to_bencode().unwrap().to_bencode_string(), input.to_vec());
+}
+
+#[test]
+fn test_error_paths() {
+    use super::deserialize_value;
+    let missing = deserialize_value(b"d4:infod4:name3:abcee").unwrap();
+    assert_eq!(Outer::from_bencode(&missing),
+               Err(FromBencodeError::MissingKey(vec!["info".to_string(), "piece length".to_string()])));
+
+    let wrong = deserialize_value(b"d13:announce-listll1:aei3ee4:infod4:name3:abc12:piece lengthi1eee").unwrap();
+    assert_eq!(Outer::from_bencode(&wrong),
+               Err(FromBencodeError::WrongType("list", vec!["announce-list".to_string(), "1".to_string()])));
+
+    let range = deserialize_value(b"d4:infod4:name3:abc12:piece lengthi-1eee").unwrap();
+    assert_eq!(Outer::from_bencode(&range),
+               Err(FromBencodeError::OutOfRange(vec!["info".to_string(), "piece length".to_string()])));
+}
+
+#[test]
+fn test_to_bencode_errors() {
+    assert_eq!(vec![1u64, i64::MAX as u64 + 1].to_bencode(), Err(ToBencodeError::OutOfRange(vec!["1".to_string()])));
+    assert_eq!(vec![Some(1), None].to_bencode(), Err(ToBencodeError::MissingValue(vec!["1".to_string()])));
+
+    let mut map = BTreeMap::new();
+    map.insert(b"ok".to_vec(), Bencode::Int(1));
+    map.insert(b"\xff".to_vec(), Bencode::Int(2));
+    let utf8: Result<BTreeMap<String, i64>, FromBencodeError> = FromBencode::from_bencode(&Bencode::Dict(map));
+    assert_eq!(utf8, Err(FromBencodeError::InvalidUtf8(vec!["\u{fffd}".to_string()])));
+}
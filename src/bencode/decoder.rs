@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use super::{Bencode, BencodeError, ParseOptions, int_from_digits, len_from_digits, is_canonical};
+
+//incremental (push style) decoding, for when bytes arrive a chunk at a time off a socket or a
+//file. think of it as bt_messages::try_decode for bencode, except nothing is re-parsed: the
+//decoder keeps the partially built value between chunks
+
+//no integer or length that fits in 64 bits has more digits than this, so a run of digits that
+//goes on past it is rejected on the spot rather than buffered
+const MAX_DIGITS: usize = 20;
+
+/// What came of pushing a chunk into a Decoder
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Decoded {
+    /// The value isn't complete yet, push more bytes
+    NeedMore,
+    /// A complete value, along with how many bytes of the last chunk it took. Anything after that
+    /// belongs to whatever follows the value
+    Complete(Bencode, usize)
+}
+
+//a list or dict that is still being filled in. a dict has to remember the key it read until the
+//matching value arrives
+enum Frame {
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>, Option<Vec<u8>>)
+}
+
+//the scalar currently being read, if any. each records where it started for error reporting
+enum Token {
+    Start,
+    Int {start: usize, negative: bool, digits: Vec<u8>},
+    Length {start: usize, digits: Vec<u8>},
+    Bytes {start: usize, data: Vec<u8>, remaining: usize}
+}
+
+pub struct Decoder {
+    stack: Vec<Frame>,
+    token: Token,
+    offset: usize,
+    options: ParseOptions
+}
+
+impl Default for Decoder {
+    fn default () -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new () -> Decoder {
+        Decoder::with_options(ParseOptions::default())
+    }
+
+    /// A decoder applying the given strictness and limits. max_len matters more here than for the
+    /// slice parser, it bounds how much a peer can make us buffer for a single string
+    pub fn with_options (options: ParseOptions) -> Decoder {
+        Decoder {
+            stack: vec![],
+            token: Token::Start,
+            offset: 0,
+            options
+        }
+    }
+
+    /// Total number of bytes consumed so far, across every chunk and value
+    pub fn offset (&self) -> usize {
+        self.offset
+    }
+
+    /// True if the decoder is between values, i.e. no partial value is held
+    pub fn is_idle (&self) -> bool {
+        match self.token {
+            Token::Start => self.stack.is_empty(),
+            _ => false
+        }
+    }
+
+    /// Feeds a chunk of bytes into the decoder. Once a value completes the decoder stops and
+    /// resets itself, so the remainder of the chunk (if any) should be pushed again
+    pub fn push (&mut self, chunk: &[u8]) -> Result<Decoded, BencodeError> {
+        let mut i = 0;
+        while i < chunk.len() {
+            let pos = self.offset + i;
+            let byte = chunk[i];
+            //a finished value and the offset it started at
+            let mut completed = None;
+
+            match self.token {
+                Token::Start => {
+                    let expecting_key = matches!(self.stack.last(), Some(&Frame::Dict(_, None)));
+                    match byte {
+                        b'0'..=b'9' => self.token = Token::Length {start: pos, digits: vec![byte]},
+                        b'e' => {
+                            completed = match self.stack.pop() {
+                                Some(Frame::List(list)) => Some((Bencode::List(list), pos)),
+                                Some(Frame::Dict(dict, None)) => Some((Bencode::Dict(dict), pos)),
+                                _ => return Err(BencodeError::UnexpectedByte(byte, pos))
+                            };
+                        },
+                        _ if expecting_key => return Err(BencodeError::UnexpectedByte(byte, pos)),
+                        b'i' => self.token = Token::Int {start: pos, negative: false, digits: vec![]},
+                        b'l' | b'd' if self.stack.len() >= self.options.max_depth => return Err(BencodeError::TooDeep(pos)),
+                        b'l' => self.stack.push(Frame::List(vec![])),
+                        b'd' => self.stack.push(Frame::Dict(BTreeMap::new(), None)),
+                        _ => return Err(BencodeError::UnexpectedByte(byte, pos))
+                    }
+                    i += 1;
+                },
+                Token::Int {start, ref mut negative, ref mut digits} => {
+                    match byte {
+                        b'-' if !*negative && digits.is_empty() => *negative = true,
+                        b'0'..=b'9' if digits.len() >= MAX_DIGITS => return Err(BencodeError::InvalidInteger(start)),
+                        b'0'..=b'9' => digits.push(byte),
+                        b'e' if !digits.is_empty() => {
+                            if self.options.strict && !is_canonical(digits, *negative) {
+                                return Err(BencodeError::NonCanonicalInteger(start))
+                            }
+                            match int_from_digits(digits, *negative) {
+                                Some(int) => completed = Some((Bencode::Int(int), start)),
+                                None => return Err(BencodeError::InvalidInteger(start))
+                            }
+                        },
+                        _ if digits.is_empty() => return Err(BencodeError::InvalidInteger(pos)),
+                        _ => return Err(BencodeError::UnexpectedByte(byte, pos))
+                    }
+                    i += 1;
+                },
+                Token::Length {start, ref mut digits} => {
+                    match byte {
+                        b'0'..=b'9' if digits.len() >= MAX_DIGITS => return Err(BencodeError::InvalidInteger(start)),
+                        b'0'..=b'9' => digits.push(byte),
+                        b':' => {
+                            if self.options.strict && !is_canonical(digits, false) {
+                                return Err(BencodeError::NonCanonicalInteger(start))
+                            }
+                            let len = match len_from_digits(digits) {
+                                Some(len) if len > self.options.max_len => return Err(BencodeError::TooLong(start)),
+                                Some(len) => len,
+                                None => return Err(BencodeError::InvalidInteger(start))
+                            };
+                            match len {
+                                0 => completed = Some((Bencode::ByteString(vec![]), start)),
+                                //don't trust the prefix with a huge up front allocation
+                                _ => self.token = Token::Bytes {start, data: Vec::with_capacity(len.min(65536)), remaining: len}
+                            }
+                        },
+                        _ => return Err(BencodeError::UnexpectedByte(byte, pos))
+                    }
+                    i += 1;
+                },
+                Token::Bytes {start, ref mut data, ref mut remaining} => {
+                    let take = (*remaining).min(chunk.len() - i);
+                    data.extend_from_slice(&chunk[i..i + take]);
+                    *remaining -= take;
+                    i += take;
+                    if *remaining == 0 {
+                        completed = Some((Bencode::ByteString(data.split_off(0)), start));
+                    }
+                }
+            }
+
+            if let Some((value, start)) = completed {
+                self.token = Token::Start;
+                if let Some(value) = self.add_to_parent(value, start)? {
+                    self.offset += i;
+                    return Ok(Decoded::Complete(value, i))
+                }
+            }
+        }
+        self.offset += chunk.len();
+        Ok(Decoded::NeedMore)
+    }
+
+    /// Adds a finished value to the innermost list or dict. If there is none, the value is the
+    /// top level value and is handed back
+    fn add_to_parent (&mut self, value: Bencode, start: usize) -> Result<Option<Bencode>, BencodeError> {
+        match self.stack.last_mut() {
+            None => return Ok(Some(value)),
+            Some(&mut Frame::List(ref mut list)) => list.push(value),
+            Some(&mut Frame::Dict(ref mut dict, ref mut key)) => {
+                match (key.take(), value) {
+                    (Some(k), v) => {
+                        dict.insert(k, v);
+                    },
+                    //Start only lets strings through when a key is expected
+                    (None, Bencode::ByteString(k)) => {
+                        //keys go in in order when they're canonical, so the greatest key so far is
+                        //the previous one
+                        if self.options.strict {
+                            match dict.keys().next_back() {
+                                Some(last) if *last == k => return Err(BencodeError::DuplicateKey(start)),
+                                Some(last) if *last > k => return Err(BencodeError::UnsortedKey(start)),
+                                _ => ()
+                            }
+                        }
+                        *key = Some(k)
+                    },
+                    (None, _) => unreachable!()
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Reads a single value off a reader, decoding as the bytes come in. Reads happen in chunks, so
+/// whatever was read past the end of the value is returned along with it
+pub fn read_value <R: Read> (reader: &mut R) -> Result<(Bencode, Vec<u8>), BencodeError> {
+    let mut decoder = Decoder::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let bytes_read = reader.read(&mut chunk)?;
+        if bytes_read == 0 {
+            return Err(BencodeError::UnexpectedEof(decoder.offset()))
+        }
+        if let Decoded::Complete(value, used) = decoder.push(&chunk[..bytes_read])? {
+            return Ok((value, chunk[used..bytes_read].to_vec()))
+        }
+    }
+}
+
+#[test]
+fn test_byte_at_a_time() {
+    use super::{deserialize_value, BencodeToString};
+    let input = b"d4:infod6:lengthi-12e4:name3:abce5:listsll0:eli1ei2eeee";
+    let mut decoder = Decoder::new();
+    for (i, byte) in input.iter().enumerate() {
+        let result = decoder.push(&[*byte]).unwrap();
+        if i + 1 < input.len() {
+            assert_eq!(result, Decoded::NeedMore);
+        } else {
+            let expected = deserialize_value(input).unwrap();
+            assert_eq!(result, Decoded::Complete(expected.clone(), 1));
+            assert_eq!(expected.to_bencode_string(), input.to_vec());
+        }
+    }
+    assert!(decoder.is_idle());
+}
+
+#[test]
+fn test_values_across_chunks() {
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.push(b"l5:ab"), Ok(Decoded::NeedMore));
+    assert_eq!(decoder.push(b"cdei4"), Ok(Decoded::NeedMore));
+    assert_eq!(decoder.push(b"2eei7"),
+               Ok(Decoded::Complete(Bencode::List(vec![Bencode::ByteString(b"abcde".to_vec()), Bencode::Int(42)]), 3)));
+    //the rest of the chunk starts the next value
+    assert_eq!(decoder.push(b"i7"), Ok(Decoded::NeedMore));
+    assert_eq!(decoder.push(b"e"), Ok(Decoded::Complete(Bencode::Int(7), 1)));
+}
+
+#[test]
+fn test_stream_errors() {
+    assert_eq!(Decoder::new().push(b"d"), Ok(Decoded::NeedMore));
+    assert_eq!(Decoder::new().push(b"di1ei2ee"), Err(BencodeError::UnexpectedByte(b'i', 1)));
+    assert_eq!(Decoder::new().push(b"d1:ae"), Err(BencodeError::UnexpectedByte(b'e', 4)));
+    assert_eq!(Decoder::new().push(b"le"), Ok(Decoded::Complete(Bencode::List(vec![]), 2)));
+    assert_eq!(Decoder::new().push(b"e"), Err(BencodeError::UnexpectedByte(b'e', 0)));
+    assert_eq!(Decoder::new().push(b"ie"), Err(BencodeError::InvalidInteger(1)));
+    assert_eq!(Decoder::new().push(b"i1-e"), Err(BencodeError::UnexpectedByte(b'-', 2)));
+    assert_eq!(Decoder::new().push(b"3x"), Err(BencodeError::UnexpectedByte(b'x', 1)));
+
+    let mut decoder = Decoder::new();
+    let _ = decoder.push(b"li1e");
+    assert_eq!(decoder.push(b"i99999999999999999999e"), Err(BencodeError::InvalidInteger(4)));
+
+    //an endless run of digits fails as soon as it's too long to be a number
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.push(&[b'1'; 20]), Ok(Decoded::NeedMore));
+    assert_eq!(decoder.push(b"1"), Err(BencodeError::InvalidInteger(0)));
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.push(b"i-"), Ok(Decoded::NeedMore));
+    assert_eq!(decoder.push(&[b'0'; 21]), Err(BencodeError::InvalidInteger(0)));
+}
+
+#[test]
+fn test_stream_strict() {
+    let strict = ParseOptions::strict();
+    assert_eq!(Decoder::with_options(strict).push(b"i-0e"), Err(BencodeError::NonCanonicalInteger(0)));
+    assert_eq!(Decoder::with_options(strict).push(b"l01:ae"), Err(BencodeError::NonCanonicalInteger(1)));
+    assert_eq!(Decoder::with_options(strict).push(b"d1:bi1e1:ai2ee"), Err(BencodeError::UnsortedKey(7)));
+    assert_eq!(Decoder::with_options(strict).push(b"d1:ai1e1:ai2ee"), Err(BencodeError::DuplicateKey(7)));
+    assert_eq!(Decoder::with_options(strict).push(b"i-12e"), Ok(Decoded::Complete(Bencode::Int(-12), 5)));
+
+    let limited = ParseOptions {max_depth: 2, max_len: 3, ..ParseOptions::default()};
+    assert_eq!(Decoder::with_options(limited).push(b"llle"), Err(BencodeError::TooDeep(2)));
+    assert_eq!(Decoder::with_options(limited).push(b"l4:"), Err(BencodeError::TooLong(1)));
+}
+
+#[test]
+fn test_read_value() {
+    use std::io::Cursor;
+    let mut reader = Cursor::new(b"d1:ai1eeextra".to_vec());
+    let (value, rest) = read_value(&mut reader).unwrap();
+    let mut dict = BTreeMap::new();
+    dict.insert(b"a".to_vec(), Bencode::Int(1));
+    assert_eq!(value, Bencode::Dict(dict));
+    assert_eq!(rest, b"extra".to_vec());
+
+    let mut truncated = Cursor::new(b"d1:a".to_vec());
+    assert_eq!(read_value(&mut truncated), Err(BencodeError::UnexpectedEof(4)));
+}
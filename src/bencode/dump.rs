@@ -0,0 +1,72 @@
+extern crate bencode;
+extern crate serde_json;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use bencode::{BencodeToString, Pretty, deserialize, to_json, from_json_str};
+
+const USAGE: &str = "usage: bencode-dump [--json | --from-json] [--full] [file]
+
+Prints bencoded data (a .torrent, a tracker reply) read from file, or stdin if no file is given.
+
+    --json       print as JSON instead
+    --from-json  read JSON (as printed by --json) and write it back out bencoded
+    --full       don't truncate long strings";
+
+fn main() {
+    let mut json = false;
+    let mut from_json = false;
+    let mut full = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--json" => json = true,
+            "--from-json" => from_json = true,
+            "--full" => full = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            },
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => fail(USAGE)
+        }
+    }
+
+    let mut input = vec![];
+    let read = match path {
+        Some(ref path) => File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input)
+    };
+    if let Err(e) = read {
+        fail(&format!("could not read input: {}", e));
+    }
+
+    if from_json {
+        let text = String::from_utf8(input).unwrap_or_else(|_| fail("input is not utf-8"));
+        let value = from_json_str(&text).unwrap_or_else(|e| fail(&e.to_string()));
+        let out = io::stdout();
+        if let Err(e) = out.lock().write_all(&value.to_bencode_string()) {
+            fail(&format!("could not write output: {}", e));
+        }
+        return
+    }
+
+    let values = deserialize(&input).unwrap_or_else(|e| fail(&e.to_string()));
+    for value in values.iter() {
+        if json {
+            println!("{}", serde_json::to_string_pretty(&to_json(value)).unwrap());
+        } else if full {
+            println!("{}", Pretty::with_truncate(value, None));
+        } else {
+            println!("{}", value);
+        }
+    }
+}
+
+fn fail (message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "{}", message);
+    process::exit(1)
+}
+
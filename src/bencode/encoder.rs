@@ -0,0 +1,205 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use super::Bencode;
+
+//streaming encoding: values are written out as they are produced instead of building (and
+//copying) a byte vector per node. works both for Bencode trees and for emitting directly, e.g.
+//
+//  encoder.begin_dict()?;
+//  encoder.write_bytes(b"length")?;
+//  encoder.write_int(12)?;
+//  encoder.end()?;
+
+#[derive(Debug)]
+pub enum EncodeError {
+    Io(io::Error),
+    /// A dict key that doesn't sort strictly after the previous key, i.e. out of order or a
+    /// duplicate. Canonical bencode needs keys in raw byte order
+    UnsortedKey(Vec<u8>),
+    /// Something other than a byte string was written where a dict key was expected
+    NonStringKey,
+    /// end was called with nothing open or with a dangling key, or finish was called with a list
+    /// or dict still open
+    Unbalanced
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::Io(ref err) => write!(f, "unable to write: {}", err),
+            EncodeError::UnsortedKey(ref key) => write!(f, "dict key {:?} is out of order", String::from_utf8_lossy(key)),
+            EncodeError::NonStringKey => write!(f, "dict keys must be byte strings"),
+            EncodeError::Unbalanced => write!(f, "unbalanced begin/end")
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+impl From<io::Error> for EncodeError {
+    fn from (err: io::Error) -> EncodeError {
+        EncodeError::Io(err)
+    }
+}
+
+enum Container {
+    List,
+    //the last key written (for the ordering check) and whether a key or a value comes next
+    Dict {last_key: Option<Vec<u8>>, expecting_key: bool}
+}
+
+pub struct Encoder <W> where W: Write {
+    writer: W,
+    stack: Vec<Container>
+}
+
+impl <W> Encoder <W> where W: Write {
+    pub fn new (writer: W) -> Encoder<W> {
+        Encoder {
+            writer,
+            stack: vec![]
+        }
+    }
+
+    pub fn write_int (&mut self, int: i64) -> Result<(), EncodeError> {
+        self.before_value()?;
+        write!(self.writer, "i{}e", int)?;
+        Ok(())
+    }
+
+    /// Writes a byte string. Inside a dict this alternates between being a key and a value
+    pub fn write_bytes (&mut self, bytes: &[u8]) -> Result<(), EncodeError> {
+        let is_key = match self.stack.last_mut() {
+            Some(&mut Container::Dict {ref mut last_key, ref mut expecting_key}) if *expecting_key => {
+                if let Some(ref last) = *last_key {
+                    if &last[..] >= bytes {
+                        return Err(EncodeError::UnsortedKey(bytes.to_vec()))
+                    }
+                }
+                *last_key = Some(bytes.to_vec());
+                *expecting_key = false;
+                true
+            },
+            _ => false
+        };
+        if !is_key {
+            self.before_value()?;
+        }
+        write!(self.writer, "{}:", bytes.len())?;
+        self.writer.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn begin_list (&mut self) -> Result<(), EncodeError> {
+        self.before_value()?;
+        self.writer.write_all(b"l")?;
+        self.stack.push(Container::List);
+        Ok(())
+    }
+
+    pub fn begin_dict (&mut self) -> Result<(), EncodeError> {
+        self.before_value()?;
+        self.writer.write_all(b"d")?;
+        self.stack.push(Container::Dict {last_key: None, expecting_key: true});
+        Ok(())
+    }
+
+    /// Closes the innermost list or dict
+    pub fn end (&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(Container::List) | Some(Container::Dict {expecting_key: true, ..}) => {
+                self.writer.write_all(b"e")?;
+                Ok(())
+            },
+            _ => Err(EncodeError::Unbalanced)
+        }
+    }
+
+    /// Writes out a complete value
+    pub fn write_value (&mut self, value: &Bencode) -> Result<(), EncodeError> {
+        match *value {
+            Bencode::Int(int) => self.write_int(int),
+            Bencode::ByteString(ref string) => self.write_bytes(string),
+            Bencode::List(ref list) => {
+                self.begin_list()?;
+                for item in list.iter() {
+                    self.write_value(item)?;
+                }
+                self.end()
+            },
+            Bencode::Dict(ref dict) => {
+                self.begin_dict()?;
+                for (key, val) in dict.iter() {
+                    self.write_bytes(key)?;
+                    self.write_value(val)?;
+                }
+                self.end()
+            }
+        }
+    }
+
+    /// Checks everything that was opened has been closed and hands back the writer
+    pub fn finish (self) -> Result<W, EncodeError> {
+        if !self.stack.is_empty() {
+            return Err(EncodeError::Unbalanced)
+        }
+        Ok(self.writer)
+    }
+
+    //anything but a key: inside a dict it has to be the value for the key just written
+    fn before_value (&mut self) -> Result<(), EncodeError> {
+        if let Some(&mut Container::Dict {ref mut expecting_key, ..}) = self.stack.last_mut() {
+            if *expecting_key {
+                return Err(EncodeError::NonStringKey)
+            }
+            *expecting_key = true;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_direct_emission() {
+    let mut encoder = Encoder::new(vec![]);
+    encoder.begin_dict().unwrap();
+    encoder.write_bytes(b"files").unwrap();
+    encoder.begin_list().unwrap();
+    encoder.write_int(-3).unwrap();
+    encoder.write_bytes(b"").unwrap();
+    encoder.end().unwrap();
+    encoder.write_bytes(b"name").unwrap();
+    encoder.write_bytes(b"abc").unwrap();
+    encoder.end().unwrap();
+    assert_eq!(encoder.finish().unwrap(), b"d5:filesli-3e0:e4:name3:abce".to_vec());
+}
+
+#[test]
+fn test_encoder_misuse() {
+    let mut encoder = Encoder::new(vec![]);
+    encoder.begin_dict().unwrap();
+    encoder.write_bytes(b"b").unwrap();
+    encoder.write_int(1).unwrap();
+    match encoder.write_bytes(b"a") {
+        Err(EncodeError::UnsortedKey(key)) => assert_eq!(key, b"a".to_vec()),
+        other => panic!("expected an unsorted key, got {:?}", other)
+    }
+    match encoder.write_bytes(b"b") {
+        Err(EncodeError::UnsortedKey(_)) => (),
+        other => panic!("expected a duplicate key, got {:?}", other)
+    }
+    match encoder.write_int(1) {
+        Err(EncodeError::NonStringKey) => (),
+        other => panic!("expected a non string key, got {:?}", other)
+    }
+    encoder.write_bytes(b"c").unwrap();
+    match encoder.end() {
+        Err(EncodeError::Unbalanced) => (),
+        other => panic!("expected a dangling key, got {:?}", other)
+    }
+
+    let mut unclosed = Encoder::new(vec![]);
+    unclosed.begin_list().unwrap();
+    assert!(unclosed.finish().is_err());
+}
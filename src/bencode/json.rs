@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::fmt;
+use serde_json::{self, Map, Number};
+use super::{Bencode, Json};
+
+//lossless conversion between Bencode and JSON, mostly so torrents and tracker replies can be
+//looked at and edited with everyday tools
+//
+//integers, lists and dicts map across directly. byte strings (and dict keys) that are text
+//become JSON strings; anything else is written as "hex:" followed by the bytes in hex. so that
+//this stays reversible, text that itself starts with "hex:" or "str:" is escaped by prefixing it
+//with "str:". parsing JSON text gives up past serde_json's nesting limit of 128
+
+const HEX_PREFIX: &str = "hex:";
+const STR_PREFIX: &str = "str:";
+
+#[derive(Debug)]
+pub enum JsonError {
+    Syntax(serde_json::Error),
+    /// Floats, booleans and null have no bencode equivalent
+    Unsupported(&'static str),
+    /// An integer that doesn't fit in an i64
+    OutOfRange(u64),
+    /// A "hex:" string that isn't valid hex
+    InvalidHex(String)
+}
+
+impl fmt::Display for JsonError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Syntax(ref err) => write!(f, "invalid json: {}", err),
+            JsonError::Unsupported(kind) => write!(f, "json {} has no bencode equivalent", kind),
+            JsonError::OutOfRange(int) => write!(f, "{} does not fit in a bencode integer", int),
+            JsonError::InvalidHex(ref string) => write!(f, "invalid hex string {:?}", string)
+        }
+    }
+}
+
+impl Error for JsonError {}
+
+/// Converts a value to JSON
+pub fn to_json (value: &Bencode) -> Json {
+    match *value {
+        Bencode::Int(int) => Json::Number(Number::from(int)),
+        Bencode::ByteString(ref string) => Json::String(bytes_to_json_string(string)),
+        Bencode::List(ref list) => Json::Array(list.iter().map(to_json).collect()),
+        Bencode::Dict(ref dict) => Json::Object(dict.iter().map(|(k, v)| (bytes_to_json_string(k), to_json(v))).collect::<Map<String, Json>>())
+    }
+}
+
+/// Converts JSON (as produced by to_json) back to a value
+pub fn from_json (json: &Json) -> Result<Bencode, JsonError> {
+    match *json {
+        Json::Number(ref number) => match (number.as_i64(), number.as_u64()) {
+            (Some(int), _) => Ok(Bencode::Int(int)),
+            (None, Some(int)) => Err(JsonError::OutOfRange(int)),
+            (None, None) => Err(JsonError::Unsupported("float"))
+        },
+        Json::String(ref string) => json_string_to_bytes(string).map(Bencode::ByteString),
+        Json::Array(ref list) => list.iter().map(from_json).collect::<Result<Vec<Bencode>, JsonError>>().map(Bencode::List),
+        Json::Object(ref dict) => {
+            dict.iter()
+                .map(|(k, v)| Ok((json_string_to_bytes(k)?, from_json(v)?)))
+                .collect::<Result<_, JsonError>>()
+                .map(Bencode::Dict)
+        },
+        Json::Bool(_) => Err(JsonError::Unsupported("boolean")),
+        Json::Null => Err(JsonError::Unsupported("null"))
+    }
+}
+
+/// Parses JSON text and converts it to a value
+pub fn from_json_str (json: &str) -> Result<Bencode, JsonError> {
+    match serde_json::from_str(json) {
+        Ok(json) => from_json(&json),
+        Err(err) => Err(JsonError::Syntax(err))
+    }
+}
+
+fn bytes_to_json_string (bytes: &[u8]) -> String {
+    match ::std::str::from_utf8(bytes) {
+        Ok(text) if text.starts_with(HEX_PREFIX) || text.starts_with(STR_PREFIX) => STR_PREFIX.to_string() + text,
+        Ok(text) => text.to_string(),
+        Err(_) => HEX_PREFIX.to_string() + &to_hex(bytes)
+    }
+}
+
+fn json_string_to_bytes (string: &str) -> Result<Vec<u8>, JsonError> {
+    if let Some(text) = string.strip_prefix(STR_PREFIX) {
+        Ok(text.as_bytes().to_vec())
+    } else if let Some(hex) = string.strip_prefix(HEX_PREFIX) {
+        from_hex(hex).ok_or_else(|| JsonError::InvalidHex(string.to_string()))
+    } else {
+        Ok(string.as_bytes().to_vec())
+    }
+}
+
+pub fn to_hex (bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex (hex: &str) -> Option<Vec<u8>> {
+    let bytes = hex.as_bytes();
+    if !bytes.iter().all(|b| b.is_ascii_hexdigit()) {
+        return None
+    }
+    let digit = |b: u8| (b as char).to_digit(16).unwrap() as u8;
+    //an odd digit out ends up alone in the last chunk
+    bytes.chunks(2).map(|pair| match *pair {
+        [high, low] => Some(digit(high) << 4 | digit(low)),
+        _ => None
+    }).collect()
+}
+
+#[test]
+fn test_json_roundtrip() {
+    use super::deserialize_value;
+    let value = deserialize_value(b"d4:infod6:lengthi-5e6:pieces3:\xff\x00\x01e4:list\
+                                     l4:hex:4:str:5:plaine3:\xc3\xa9\xe2lee").unwrap();
+    let json = to_json(&value);
+    //json objects are sorted as text, hence the hex key moving to the front
+    assert_eq!(json.to_string(),
+               r#"{"hex:c3a9e2":[],"info":{"length":-5,"pieces":"hex:ff0001"},"list":["str:hex:","str:str:","plain"]}"#);
+    assert_eq!(from_json(&json).unwrap(), value);
+    assert_eq!(from_json_str(&serde_json::to_string_pretty(&json).unwrap()).unwrap(), value);
+}
+
+#[test]
+fn test_json_errors() {
+    assert!(matches!(from_json_str("[1.5]"), Err(JsonError::Unsupported("float"))));
+    assert!(matches!(from_json_str("{\"a\": null}"), Err(JsonError::Unsupported("null"))));
+    assert!(matches!(from_json_str("\"hex:abc\""), Err(JsonError::InvalidHex(_))));
+    assert!(matches!(from_json_str("\"hex:+f\""), Err(JsonError::InvalidHex(_))));
+    assert!(matches!(from_json_str("18446744073709551615"), Err(JsonError::OutOfRange(_))));
+    assert!(matches!(from_json_str("[1,"), Err(JsonError::Syntax(_))));
+    //nesting that would run a recursive conversion out of stack
+    let deep = "[".repeat(100000) + &"]".repeat(100000);
+    assert!(matches!(from_json_str(&deep), Err(JsonError::Syntax(_))));
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::str;
+use super::Bencode;
+use json::to_hex;
+
+//human readable output for debugging. unlike Debug, text shows up as text and binary as hex, and
+//long strings (think pieces) are cut short
+
+/// Strings longer than this many bytes are truncated by default
+pub const DEFAULT_TRUNCATE: usize = 64;
+
+/// Displays a value over multiple indented lines
+pub struct Pretty<'a> {
+    value: &'a Bencode,
+    truncate: Option<usize>
+}
+
+impl <'a> Pretty<'a> {
+    /// Truncates strings longer than DEFAULT_TRUNCATE bytes
+    pub fn new (value: &'a Bencode) -> Pretty<'a> {
+        Pretty::with_truncate(value, Some(DEFAULT_TRUNCATE))
+    }
+
+    /// Truncates strings longer than the given number of bytes, or never if None
+    pub fn with_truncate (value: &'a Bencode, truncate: Option<usize>) -> Pretty<'a> {
+        Pretty {
+            value,
+            truncate
+        }
+    }
+
+    fn write_string (&self, f: &mut fmt::Formatter, bytes: &[u8], truncate: Option<usize>) -> fmt::Result {
+        let shown = match truncate {
+            Some(max) if bytes.len() > max => &bytes[..max],
+            _ => bytes
+        };
+        match str::from_utf8(bytes) {
+            Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+                //cutting can land in the middle of a character, only show whole ones
+                let shown = match str::from_utf8(shown) {
+                    Ok(shown) => shown,
+                    Err(e) => &text[..e.valid_up_to()]
+                };
+                write!(f, "{:?}", shown)?;
+            },
+            _ => write!(f, "<{}>", to_hex(shown))?
+        }
+        if shown.len() < bytes.len() {
+            write!(f, "... ({} bytes)", bytes.len())?;
+        }
+        Ok(())
+    }
+
+    fn write_value (&self, f: &mut fmt::Formatter, value: &Bencode, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match *value {
+            Bencode::Int(int) => write!(f, "{}", int),
+            Bencode::ByteString(ref string) => self.write_string(f, string, self.truncate),
+            Bencode::List(ref list) if list.is_empty() => write!(f, "[]"),
+            Bencode::Dict(ref dict) if dict.is_empty() => write!(f, "{{}}"),
+            Bencode::List(ref list) => {
+                writeln!(f, "[")?;
+                for item in list.iter() {
+                    write!(f, "{}", pad)?;
+                    self.write_value(f, item, indent + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{}]", &pad[2..])
+            },
+            Bencode::Dict(ref dict) => {
+                writeln!(f, "{{")?;
+                for (key, val) in dict.iter() {
+                    write!(f, "{}", pad)?;
+                    self.write_string(f, key, None)?;
+                    write!(f, ": ")?;
+                    self.write_value(f, val, indent + 1)?;
+                    writeln!(f)?;
+                }
+                write!(f, "{}}}", &pad[2..])
+            }
+        }
+    }
+}
+
+impl <'a> fmt::Display for Pretty<'a> {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_value(f, self.value, 0)
+    }
+}
+
+impl fmt::Display for Bencode {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Pretty::new(self).fmt(f)
+    }
+}
+
+#[test]
+fn test_pretty() {
+    use super::deserialize_value;
+    let mut input = b"d4:infod6:lengthi12e4:name5:a\xc3\xa9b\n6:pieces100:".to_vec();
+    input.extend((0..100).map(|x| x as u8));
+    input.extend(b"e4:listlee".iter());
+    let value = deserialize_value(&input).unwrap();
+
+    assert_eq!(Pretty::with_truncate(&value, Some(4)).to_string(),
+               "{\n  \"info\": {\n    \"length\": 12\n    \"name\": \"a\u{e9}b\"... (5 bytes)\n    \
+                \"pieces\": <00010203>... (100 bytes)\n  }\n  \"list\": []\n}");
+    assert!(Pretty::with_truncate(&value, None).to_string().contains(&to_hex(&(0..100).collect::<Vec<u8>>())));
+    assert_eq!(Bencode::Int(3).to_string(), "3");
+}
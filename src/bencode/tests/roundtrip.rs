@@ -0,0 +1,94 @@
+//! Property tests checking that anything we serialize can be read back unchanged
+
+extern crate bencode;
+extern crate quickcheck;
+
+use std::collections::BTreeMap;
+use quickcheck::{quickcheck, Arbitrary, Gen};
+use bencode::{Bencode, BencodeToString, Decoder, Decoded, ParseOptions, deserialize, deserialize_value};
+
+/// Bencode is defined in another crate, so it needs a local wrapper to be Arbitrary
+#[derive(Clone, Debug)]
+struct ArbitraryBencode(Bencode);
+
+fn arbitrary_bytes<G: Gen>(g: &mut G) -> Vec<u8> {
+    let len = g.gen_range(0, g.size() + 1);
+    (0..len).map(|_| g.gen::<u8>()).collect()
+}
+
+/// Generates a value, nesting at most `depth` levels of lists and dicts so trees stay finite
+fn arbitrary_bencode<G: Gen>(g: &mut G, depth: usize) -> Bencode {
+    let kinds = if depth == 0 { 2 } else { 4 };
+    match g.gen_range(0, kinds) {
+        0 => Bencode::Int(g.gen()),
+        1 => Bencode::ByteString(arbitrary_bytes(g)),
+        2 => {
+            let len = g.gen_range(0, 5);
+            Bencode::List((0..len).map(|_| arbitrary_bencode(g, depth - 1)).collect())
+        },
+        _ => {
+            let len = g.gen_range(0, 5);
+            Bencode::Dict((0..len).map(|_| (arbitrary_bytes(g), arbitrary_bencode(g, depth - 1)))
+                                  .collect::<BTreeMap<Vec<u8>, Bencode>>())
+        }
+    }
+}
+
+impl Arbitrary for ArbitraryBencode {
+    fn arbitrary<G: Gen>(g: &mut G) -> ArbitraryBencode {
+        ArbitraryBencode(arbitrary_bencode(g, 4))
+    }
+}
+
+#[test]
+fn roundtrip_value() {
+    fn prop(value: ArbitraryBencode) -> bool {
+        deserialize_value(&value.0.to_bencode_string()) == Ok(value.0)
+    }
+    quickcheck(prop as fn(ArbitraryBencode) -> bool);
+}
+
+#[test]
+fn roundtrip_concatenated_values() {
+    fn prop(values: Vec<ArbitraryBencode>) -> bool {
+        let values: Vec<Bencode> = values.into_iter().map(|x| x.0).collect();
+        let bytes: Vec<u8> = values.iter().flat_map(|x| x.to_bencode_string()).collect();
+        deserialize(&bytes) == Ok(values)
+    }
+    quickcheck(prop as fn(Vec<ArbitraryBencode>) -> bool);
+}
+
+#[test]
+fn roundtrip_is_stable() {
+    //serializing what we read back gives the same bytes, i.e. our output is canonical
+    fn prop(value: ArbitraryBencode) -> bool {
+        let bytes = value.0.to_bencode_string();
+        deserialize_value(&bytes).map(|x| x.to_bencode_string()) == Ok(bytes)
+    }
+    quickcheck(prop as fn(ArbitraryBencode) -> bool);
+}
+
+#[test]
+fn serialized_is_strict() {
+    fn prop(value: ArbitraryBencode) -> bool {
+        ParseOptions::strict().deserialize_value(&value.0.to_bencode_string()) == Ok(value.0)
+    }
+    quickcheck(prop as fn(ArbitraryBencode) -> bool);
+}
+
+#[test]
+fn roundtrip_decoder_in_chunks() {
+    fn prop(value: ArbitraryBencode, chunk_size: usize) -> bool {
+        let bytes = value.0.to_bencode_string();
+        let mut decoder = Decoder::new();
+        let mut result = Ok(Decoded::NeedMore);
+        for chunk in bytes.chunks(chunk_size % 16 + 1) {
+            result = decoder.push(chunk);
+        }
+        match result {
+            Ok(Decoded::Complete(decoded, _)) => decoded == value.0,
+            _ => false
+        }
+    }
+    quickcheck(prop as fn(ArbitraryBencode, usize) -> bool);
+}
@@ -13,7 +13,10 @@ pub enum Message {
     Request {index: u32, begin: u32, length: u32},
     Piece {index: u32, begin: u32, block: Vec<u8>},
     Cancel {index: u32, begin: u32, length: u32},
-    Port(u16)
+    Port(u16),
+    //extension protocol (BEP 10). id 0 is the extended handshake, the rest are whatever ids the
+    //receiving side assigned to its extensions
+    Extended {id: u8, payload: Vec<u8>}
 }
 
 //this is kind of lazy. but it also makes reading/writing Messages symmetrical...
@@ -67,6 +70,14 @@ impl Message {
                 fixed_part.extend(l_b.iter());
                 fixed_part
             },
+            &Message::Extended{id, ref payload} => {
+                let len: [u8; 4] = unsafe {transmute((payload.len() as u32 + 2).to_be())};
+                let mut message = len.to_vec();
+                message.push(20);
+                message.push(id);
+                message.extend(payload.iter());
+                message
+            },
             _ => {
                 vec![]
             }
@@ -121,6 +132,10 @@ pub fn try_decode (bytes: &[u8]) -> Option<(Message, usize)> {
                     Message::Cancel{index: index, begin: begin, length: length}
                 },
                 9 => Message::Port(u8_2_to_u16(&rest[1..3])),
+                20 if len >= 2 => Message::Extended{id: rest[1], payload: (&rest[2..len as usize]).to_owned()},
+                //an extended message without its id. it's all here, so skip it rather than wait
+                //for bytes that aren't coming
+                20 => Message::KeepAlive,
                 _ => return None
             };
 
@@ -168,6 +183,28 @@ fn test_request_message () {
     assert_eq!(message, a_message);
 }
 
+#[test]
+fn test_extended_message () {
+    let a_message = Message::Extended{id: 3, payload: b"d8:msg_typei0e5:piecei0ee".to_vec()};
+    let mut a = a_message.to_byte_array();
+
+    assert_eq!(&a[..6], &[0, 0, 0, 27, 20, 3]);
+
+    a.extend(vec![0, 0, 0, 0].iter());
+    let (message, consumed) = try_decode(&a).unwrap();
+
+    assert_eq!(message, a_message);
+    assert_eq!(consumed, 31);
+}
+
+#[test]
+fn test_extended_message_without_id () {
+    let (message, consumed) = try_decode(&[0, 0, 0, 1, 20, 0, 0, 0, 0]).unwrap();
+
+    assert_eq!(message, Message::KeepAlive);
+    assert_eq!(consumed, 5);
+}
+
 /*#[test]
 fn test_decode () {
     use std::io::{Result, Read};
@@ -19,28 +19,30 @@ impl <T> BufferedReader <T> where T:Read {
 
     pub fn wait_for_message(&mut self) -> Result<Message> {
         loop {
+            //a previous read may have brought in more than one message
+            if let Some(message) = self.take_buffered() {
+                return Ok(message)
+            }
             let mut i_buff = [0; 1024];
             match self.readable.read(&mut i_buff) {
                 Ok(0) => return Err(Error::new(ErrorKind::Other, "graceful disconnect")),
                 Ok(bytes_read) => {
                     self.buffer.extend(i_buff[0..bytes_read].iter());
-                    if self.buffer.len() >= 4 {
-                        //try_decode calls read which is a blocking operation, should probably
-                        //combine bt_messages with this
-                        match try_decode(&self.buffer) {
-                            None => continue,
-                            Some((protocol_message, bytes_consumed)) => {
-                                //TODO: might be able to use self.spare as a slice
-                                self.buffer = (&self.buffer[bytes_consumed..]).to_owned();
-                                return Ok(protocol_message)
-                            }
-                        }
-                    }
                 },
                 Err(err) => return Err(err)
             };
         }
     }
+
+    fn take_buffered (&mut self) -> Option<Message> {
+        if self.buffer.len() < 4 {
+            return None
+        }
+        let (protocol_message, bytes_consumed) = try_decode(&self.buffer)?;
+        //TODO: might be able to use self.spare as a slice
+        self.buffer = (&self.buffer[bytes_consumed..]).to_owned();
+        Some(protocol_message)
+    }
 }
 
 impl BufferedReader <TcpStream> {
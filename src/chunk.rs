@@ -63,14 +63,16 @@ impl Piece {
         Self::new(Position::new(a, b), Position::new(c, d))
     }
 
-    pub fn num_bytes(&self, block_size: &usize) -> usize {
-        (self.end.index * block_size + self.end.offset)
-            - (self.start.index * block_size + self.start.offset)
+    /// Byte count covered by this piece. Done in 64 bits as absolute offsets into large torrents
+    /// don't fit in a usize on 32 bit platforms
+    pub fn num_bytes(&self, block_size: &usize) -> u64 {
+        let absolute = |pos: &Position| pos.index as u64 * *block_size as u64 + pos.offset as u64;
+        absolute(&self.end) - absolute(&self.start)
     }
 
     //start is inclusive, end is exclusive
-    pub fn from (piece_length: usize, index: usize, offset: usize, bytes: usize) -> Piece {
-        let bytes_to_fill = piece_length - offset;
+    pub fn from (piece_length: usize, index: usize, offset: usize, bytes: u64) -> Piece {
+        let bytes_to_fill = (piece_length - offset) as u64;
 
         if bytes < bytes_to_fill {
             Piece {
@@ -80,7 +82,7 @@ impl Piece {
                 },
                 end: Position {
                     index: index,
-                    offset: offset + bytes
+                    offset: offset + bytes as usize
                 }
             }
         } else {
@@ -90,11 +92,11 @@ impl Piece {
             };
 
             let rem_bytes = bytes - bytes_to_fill;
-            let overflow = rem_bytes % piece_length;
-            let index_o = rem_bytes / piece_length;
+            let overflow = rem_bytes % piece_length as u64;
+            let index_o = rem_bytes / piece_length as u64;
 
-            temp_end.index += index_o;
-            temp_end.offset = overflow;
+            temp_end.index += index_o as usize;
+            temp_end.offset = overflow as usize;
 
             Piece {
                 start: Position {
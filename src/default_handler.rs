@@ -4,12 +4,50 @@ use bt_messages::{Message, try_decode};
 use buffered_reader::BufferedReader;
 use chunk::{Position, Piece};
 use peer::{Peer, SendPeerMessage};
-use std::net::TcpStream;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use std::sync::mpsc::Sender;
 use std::ops::{Deref, DerefMut};
 use rand::{Rng, thread_rng};
-use metadata::Metadata;
+use metadata::{Metadata, MetadataError};
+use magnet::MagnetLink;
+use ut_metadata::{ExtendedHandshake, MetadataDownload, MetadataMessage, respond, EXTENDED_HANDSHAKE_ID, UT_METADATA_ID, UT_METADATA};
+use web_seed::{WebSeed, WebSeedError};
+use announce::TransferStats;
+use storage;
+use tracker::UNKNOWN_LEFT;
 const BLOCK_LENGTH:usize = 16384; //block length in bytes
+const MAX_WEB_SEED_FAILURES:usize = 5;
+const WEB_SEED_BACKOFF:i64 = 10; //seconds, doubled on each failure in a row
+
+//a web seed's download thread and the piece it's on
+struct WebSeedSource {
+    seed: WebSeed,
+    tx: Sender<usize>,
+    piece: Option<usize>,
+    not_before: i64,
+    failures: usize
+}
+
+/// What became of a whole piece handed to complete_piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceOutcome {
+    /// It matched its hash and is ours now, written out if there's a download directory
+    Verified,
+    /// It didn't match its hash
+    HashFailed,
+    /// It matched its hash but couldn't be written, so it will be downloaded again
+    WriteFailed
+}
+
+//a piece being put together from blocks, with the ranges of it that are in so far
+struct PartialPiece {
+    data: Vec<u8>,
+    received: Vec<Piece>
+}
 
 pub struct GlobalState {
     gpc: Vec<u16>,
@@ -19,12 +57,44 @@ pub struct GlobalState {
     pub requests: Vec<(Piece, i64)>,
     s_request_map: Vec<u8>,
     piece_length: usize,
+    total_length: u64,
     pieces_hash: Vec<u8>,
-    peer_list: Vec<(Arc<RwLock<Peer>>, TcpStream, i64, Vec<u8>)>
+    peer_list: Vec<(Arc<RwLock<Peer>>, TcpStream, i64, Vec<u8>)>,
+    pub info_hash: [u8; 20],
+    /// Missing when starting from a magnet link, until it's fetched from peers
+    pub metadata: Option<Metadata>,
+    metadata_download: Option<MetadataDownload>,
+    //set when fetched metadata matches the info hash but is unusable, which ends the download
+    metadata_error: Option<MetadataError>,
+    //trackers from the magnet link, for the metadata once it's fetched
+    magnet_trackers: Vec<String>,
+    web_seeds: Vec<WebSeedSource>,
+    partial_pieces: BTreeMap<usize, PartialPiece>,
+    //where verified pieces are written, if anywhere
+    download_dir: Option<PathBuf>,
+    //payload bytes sent and received this session, bad pieces included
+    uploaded: u64,
+    downloaded: u64,
+    //set on shutdown, peers that finish connecting after are hung up on
+    closed: bool
 }
 
 impl GlobalState {
     pub fn new (metadata: &Metadata) -> GlobalState {
+        let mut state = GlobalState::without_metadata(metadata.info_hash);
+        state.set_metadata(metadata.clone());
+        state
+    }
+
+    /// Starts a session knowing only the info hash. No pieces are requested until the metadata
+    /// is set
+    pub fn from_magnet (magnet: &MagnetLink) -> GlobalState {
+        let mut state = GlobalState::without_metadata(magnet.info_hash);
+        state.magnet_trackers = magnet.trackers.clone();
+        state
+    }
+
+    fn without_metadata (info_hash: [u8; 20]) -> GlobalState {
         GlobalState {
             gpc: vec![],
             owned: vec![],
@@ -33,16 +103,308 @@ impl GlobalState {
             requests: vec![],
             s_request_map: vec![],
             peer_list: vec![],
-            piece_length: metadata.piece_length as usize,
-            pieces_hash: metadata.pieces.clone()
+            piece_length: 0,
+            total_length: 0,
+            pieces_hash: vec![],
+            info_hash: info_hash,
+            metadata: None,
+            metadata_download: None,
+            metadata_error: None,
+            magnet_trackers: vec![],
+            web_seeds: vec![],
+            partial_pieces: BTreeMap::new(),
+            download_dir: None,
+            uploaded: 0,
+            downloaded: 0,
+            closed: false
+        }
+    }
+
+    pub fn set_metadata (&mut self, metadata: Metadata) {
+        self.piece_length = metadata.piece_length as usize;
+        self.total_length = metadata.total_length();
+        self.pieces_hash = metadata.pieces.clone();
+        self.metadata = Some(metadata);
+    }
+
+    /// Writes verified pieces to their files under dir from now on
+    pub fn set_download_dir (&mut self, dir: PathBuf) {
+        self.download_dir = Some(dir);
+    }
+
+    pub fn has_metadata (&self) -> bool {
+        self.metadata.is_some()
+    }
+
+    /// Why the metadata fetched for a magnet link couldn't be used, if it couldn't. It matched the
+    /// info hash, so no peer can give us anything better
+    pub fn metadata_error (&self) -> Option<&MetadataError> {
+        self.metadata_error.as_ref()
+    }
+
+    /// Our extended handshake, offering our info dict if we have it
+    pub fn extended_handshake (&self) -> Message {
+        ExtendedHandshake::ours(self.metadata.as_ref().map(|x| x.info_bytes().len() as u64)).to_message()
+    }
+
+    /// Sends a message to one of the connected peers
+    pub fn send_to (&mut self, peer: &Peer, message: Message) {
+        //peer ids are kept as strings with a char per byte
+        let id = peer.id.chars().map(|x| x as u8).collect::<Vec<u8>>();
+        if let Some(tup) = self.peer_list.iter_mut().find(|x| x.3 == id) {
+            if let Message::Piece{ref block, ..} = message {
+                self.uploaded += block.len() as u64;
+            }
+            tup.1.send_message(message);
+        }
+    }
+
+    /// Handles a ut_metadata message from a peer
+    fn on_metadata_message (&mut self, message: MetadataMessage, peer: &Peer) {
+        let peer_ut_id = peer.state.extensions.get(UT_METADATA).cloned();
+        match message {
+            MetadataMessage::Request{piece} => {
+                let response = respond(self.metadata.as_ref().map(|x| x.info_bytes()), piece);
+                if let Some(id) = peer_ut_id {
+                    self.send_to(peer, response.to_message(id));
+                }
+            },
+            MetadataMessage::Data{piece, total_size, data} => {
+                let result = match self.metadata_download {
+                    Some(ref mut download) if download.size() as u64 == total_size => download.add_piece(piece, data),
+                    _ => None
+                };
+                match result {
+                    Some(Ok(mut metadata)) => {
+                        println!("fetched metadata for {}", metadata.name());
+                        if metadata.announce.is_empty() && !self.magnet_trackers.is_empty() {
+                            metadata.announce = self.magnet_trackers[0].clone();
+                            metadata.announce_list = self.magnet_trackers.iter().map(|x| vec![x.clone()]).collect();
+                        }
+                        self.metadata_download = None;
+                        self.set_metadata(metadata);
+                    },
+                    Some(Err(MetadataError::InfoHashMismatch)) => {
+                        //the size we went by may have been a lie too, so hang up on this peer and
+                        //start over with the size another one gives
+                        println!("discarding fetched metadata: {}", MetadataError::InfoHashMismatch);
+                        self.metadata_download = None;
+                        let id = peer.id.chars().map(|x| x as u8).collect::<Vec<u8>>();
+                        self.disconnect_peer(&id);
+                        let others = self.peer_list.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+                        for other in others {
+                            let other = other.read().unwrap();
+                            self.start_metadata_download(&other);
+                        }
+                    },
+                    Some(Err(e)) => {
+                        println!("unable to use fetched metadata: {}", e);
+                        self.metadata_download = None;
+                        self.metadata_error = Some(e);
+                    },
+                    None => {}
+                }
+            },
+            MetadataMessage::Reject{..} => {}
+        }
+    }
+
+    /// Fetches the info dict from the peer, at the size it gave unless a download is already under
+    /// way. Nothing happens once the metadata is known or has turned out unusable
+    fn start_metadata_download (&mut self, peer: &Peer) {
+        if self.has_metadata() || self.metadata_error.is_some() || !peer.state.extensions.contains_key(UT_METADATA) {
+            return
+        }
+        if self.metadata_download.is_none() {
+            self.metadata_download = peer.state.metadata_size.and_then(|size| MetadataDownload::new(self.info_hash, size));
+        }
+        self.request_metadata(peer);
+    }
+
+    /// Asks the peer for every piece of the info dict we're missing
+    fn request_metadata (&mut self, peer: &Peer) {
+        let id = match peer.state.extensions.get(UT_METADATA) {
+            Some(&id) => id,
+            None => return
+        };
+        let requests = match self.metadata_download {
+            Some(ref download) => download.requests(),
+            None => return
+        };
+        for request in requests {
+            self.send_to(peer, request.to_message(id));
         }
     }
 
+    /// Adds a web seed as a source of pieces. The spinner sends the indices of pieces for it to
+    /// download over tx, one at a time, and the results come back through web_seed_finished
+    pub fn add_web_seed (&mut self, seed: WebSeed, tx: Sender<usize>) {
+        self.web_seeds.push(WebSeedSource {
+            seed: seed,
+            tx: tx,
+            piece: None,
+            not_before: 0,
+            failures: 0
+        });
+    }
+
+    /// The web seeds still in use. Ones that keep failing are dropped
+    pub fn web_seeds (&self) -> Vec<&WebSeed> {
+        self.web_seeds.iter().map(|x| &x.seed).collect()
+    }
+
+    /// Takes the outcome of a web seed's download of piece index, which is stored like any other
+    /// piece once verified. A busy or failing seed is left alone for a while before it gets
+    /// another piece
+    pub fn web_seed_finished (&mut self, url: &str, index: usize, result: Result<Vec<u8>, WebSeedError>) {
+        let outcome = match result {
+            Ok(ref data) => {
+                self.downloaded += data.len() as u64;
+                Some(self.complete_piece(index, data))
+            },
+            Err(_) => {
+                self.release_piece(index);
+                None
+            }
+        };
+        let now = time::get_time().sec;
+        if let Some(source) = self.web_seeds.iter_mut().find(|x| x.seed.url == url) {
+            source.piece = None;
+            match result {
+                Ok(_) if outcome == Some(PieceOutcome::Verified) => source.failures = 0,
+                //the seed did its part, it's our disk that didn't
+                Ok(_) if outcome == Some(PieceOutcome::WriteFailed) => {},
+                Err(WebSeedError::Retry(secs)) => source.not_before = now + secs as i64,
+                _ => {
+                    println!("web seed {} failed on piece {}", url, index);
+                    source.not_before = now + (WEB_SEED_BACKOFF << cmp::min(source.failures, 6));
+                    source.failures += 1;
+                }
+            }
+        }
+        //dropping a seed's sender ends its thread
+        self.web_seeds.retain(|x| x.failures < MAX_WEB_SEED_FAILURES);
+    }
+
+    /// Stores a block of a piece from a peer. Once the piece is whole it goes through
+    /// complete_piece, whose outcome is returned
+    pub fn add_block (&mut self, index: usize, begin: usize, block: &[u8]) -> Option<PieceOutcome> {
+        let size = match self.metadata {
+            Some(ref metadata) if index < metadata.piece_count() => metadata.piece_size(index) as usize,
+            _ => return None
+        };
+        if block.is_empty() || begin + block.len() > size {
+            return None
+        }
+        self.downloaded += block.len() as u64;
+        let done = {
+            let partial = self.partial_pieces.entry(index).or_insert_with(|| PartialPiece {
+                data: vec![0; size],
+                received: vec![]
+            });
+            //offsets within the piece, as the piece index doesn't matter here
+            match Piece::add_to_boundary_vec(&mut partial.received, Piece::create((0, begin), (0, begin + block.len()))) {
+                Ok(i) => Piece::compact_if_possible(&mut partial.received, i),
+                Err(_) => return None //we have (some of) it already
+            }
+            partial.data[begin..begin + block.len()].copy_from_slice(block);
+            partial.received == [Piece::create((0, 0), (0, size))]
+        };
+        if !done {
+            return None
+        }
+        let partial = self.partial_pieces.remove(&index).unwrap();
+        Some(self.complete_piece(index, &partial.data))
+    }
+
+    /// Checks a whole piece against its hash, however it was downloaded, and marks it as owned if
+    /// it's good. Outstanding requests for it are dropped either way, so a bad piece gets
+    /// requested again. With a download directory set, good pieces are written out first and the
+    /// files they complete finished off; one that can't be written isn't marked as owned, and
+    /// comes back as WriteFailed rather than Verified
+    pub fn complete_piece (&mut self, index: usize, data: &[u8]) -> PieceOutcome {
+        let valid = match self.metadata {
+            Some(ref metadata) => metadata.verify_piece(index, data),
+            None => false
+        };
+        self.release_piece(index);
+        if !valid {
+            return PieceOutcome::HashFailed
+        }
+        if let (Some(dir), Some(metadata)) = (self.download_dir.as_ref(), self.metadata.as_ref()) {
+            if let Err(e) = storage::write_piece(metadata, dir, index, data) {
+                println!("unable to write piece {}: {}", index, e);
+                return PieceOutcome::WriteFailed
+            }
+        }
+        if let Ok(i) = Piece::add_to_boundary_vec(&mut self.owned_pieces, Piece::create((index, 0), (index + 1, 0))) {
+            Piece::compact_if_possible(&mut self.owned_pieces, i);
+        }
+        if let (Some(dir), Some(metadata)) = (self.download_dir.as_ref(), self.metadata.as_ref()) {
+            let owned = &self.owned_pieces;
+            let owns = |i: usize| owned.iter().any(|x| x.start <= Position::new(i, 0) && x.end >= Position::new(i + 1, 0));
+            for file in storage::completed_files(metadata, index, owns) {
+                if let Err(e) = storage::finish_file(&file, dir) {
+                    println!("unable to finish {}: {}", file.path.join("/"), e);
+                }
+            }
+        }
+        PieceOutcome::Verified
+    }
+
+    //forgets requests that lie within piece index
+    fn release_piece (&mut self, index: usize) {
+        let (start, end) = (Position::new(index, 0), Position::new(index + 1, 0));
+        self.requests.retain(|&(ref x, _)| !(x.start >= start && x.end <= end));
+    }
+
     pub fn add_new_peer (&mut self, peer: Arc<RwLock<Peer>>, stream: TcpStream, peer_id: Vec<u8>) {
+        if self.closed {
+            let _ = stream.shutdown(Shutdown::Both);
+            return
+        }
         let last_checkin = time::get_time().sec;
         self.peer_list.push((peer, stream, last_checkin, peer_id));
     }
 
+    /// Whether disconnect_all has been called
+    pub fn is_closed (&self) -> bool {
+        self.closed
+    }
+
+    /// Hangs up on every peer, so their threads stop feeding the sink, and on any that connect
+    /// later
+    pub fn disconnect_all (&mut self) {
+        self.closed = true;
+        for tup in self.peer_list.drain(..) {
+            let _ = tup.1.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Bytes of the torrent we have, going by the pieces marked as owned. The last piece is
+    /// usually short, hence the cap
+    pub fn bytes_owned (&self) -> u64 {
+        let owned = self.owned_pieces.iter().fold(0, |a, b| a + b.num_bytes(&self.piece_length));
+        cmp::min(owned, self.total_length)
+    }
+
+    /// What to report to trackers. How much is left isn't known until the metadata is
+    pub fn transfer_stats (&self) -> TransferStats {
+        TransferStats {
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: if self.has_metadata() { self.total_length - self.bytes_owned() } else { UNKNOWN_LEFT }
+        }
+    }
+
+    /// Hangs up on a peer. Its thread notices and removes it
+    pub fn disconnect_peer (&mut self, id: &[u8]) {
+        if let Some(tup) = self.peer_list.iter().find(|x| &x.3[..] == id) {
+            let _ = tup.1.shutdown(Shutdown::Both);
+        }
+        self.remove_peer(id);
+    }
+
     pub fn remove_peer(&mut self, id: &[u8]) {
         self.peer_list.retain(|x| {
             &x.3[..] != id
@@ -149,7 +511,8 @@ impl Spin for GlobalState {
                     *timestamp = now;
                 }
 
-                if self.requests.len() < WANT_LIMIT {
+                //nothing can be requested before we know how pieces are laid out
+                if self.requests.len() < WANT_LIMIT && self.metadata.is_some() {
                      let peer = match rw_lock_peer.try_read() {
                         Ok(a) => a,
                         Err(_) => continue//do nothing. it's locked
@@ -184,6 +547,27 @@ impl Spin for GlobalState {
             }
         }
 
+        //web seeds have everything, give each idle one a whole piece nobody else is after
+        if let Some(piece_count) = self.metadata.as_ref().map(|x| x.piece_count()) {
+            let now = time::get_time().sec;
+            let everything = vec![Piece::create((0, 0), (piece_count, 0))];
+            for source in self.web_seeds.iter_mut().filter(|x| x.piece.is_none() && x.not_before <= now) {
+                let index = match first_whole_piece(&Piece::complement(&everything, &exclude)) {
+                    Some(index) => index,
+                    None => break
+                };
+                if source.tx.send(index).is_err() {
+                    continue
+                }
+                let piece = Piece::create((index, 0), (index + 1, 0));
+                source.piece = Some(index);
+                self.requests.push((piece.clone(), now));
+                if let Ok(i) = Piece::add_to_boundary_vec(&mut exclude, piece) {
+                    Piece::compact_if_possible(&mut exclude, i);
+                }
+            }
+        }
+
         if self.requests.len() >= WANT_LIMIT {
 
             let mut num_to_expire = WANT_LIMIT/EXPIRE_FACTOR;
@@ -201,13 +585,21 @@ impl Spin for GlobalState {
     }
 }
 
+//the first piece that lies wholly within one of the ranges
+fn first_whole_piece (ranges: &[Piece]) -> Option<usize> {
+    ranges.iter().flat_map(|range| {
+        let index = if range.start.offset == 0 { range.start.index } else { range.start.index + 1 };
+        if Position::new(index + 1, 0) <= range.end { Some(index) } else { None }
+    }).next()
+}
+
 fn slice_piece (pieces: &[Piece], piece_length: &usize, block_size: &usize) -> Piece {
     let &Piece {
         ref start,
         ref end
     } = pieces.first().unwrap();
 
-    let piece = Piece::from(piece_length.to_owned(), start.index, start.offset, block_size.to_owned());
+    let piece = Piece::from(piece_length.to_owned(), start.index, start.offset, block_size.to_owned() as u64);
 
     if end < &piece.end {
         Piece::new(start.clone(), end.clone())
@@ -235,7 +627,10 @@ impl Handler for DefaultHandler {
                 let i = index as usize;
                 global.gpc_incr(i, 1);
                 //peer.state.set_have(i);
-                let piece = Piece::from(global.piece_length, i, 0, global.piece_length);
+                if !global.has_metadata() {
+                    return
+                }
+                let piece = Piece::from(global.piece_length, i, 0, global.piece_length as u64);
                 match Piece::add_to_boundary_vec(&mut peer.state.pieces, piece) {
                     Ok(i_index) => Piece::compact_if_possible(&mut peer.state.pieces, i_index),
                     Err(e) => {
@@ -253,6 +648,30 @@ impl Handler for DefaultHandler {
             &Message::Interested => {
                 peer.state.set_us_interested(true);
             },
+            &Message::Extended{id: EXTENDED_HANDSHAKE_ID, ref payload} => {
+                let handshake = match ExtendedHandshake::from_payload(payload) {
+                    Some(handshake) => handshake,
+                    None => return
+                };
+                peer.state.extensions = handshake.extensions.iter()
+                    .filter(|&(_, &id)| id > 0 && id < 256)
+                    .map(|(name, &id)| (name.clone(), id as u8))
+                    .collect();
+                peer.state.metadata_size = handshake.metadata_size;
+                global.start_metadata_download(peer);
+            },
+            &Message::Extended{id: UT_METADATA_ID, ref payload} => {
+                if let Some(message) = MetadataMessage::from_payload(payload) {
+                    global.on_metadata_message(message, peer);
+                }
+            },
+            &Message::Piece{index, begin, ref block} => {
+                match global.add_block(index as usize, begin as usize, block) {
+                    Some(PieceOutcome::HashFailed) => println!("piece {} failed its hash check", index),
+                    Some(PieceOutcome::WriteFailed) => println!("piece {} was good but couldn't be stored", index),
+                    _ => {}
+                }
+            },
             &Message::Bitfield(ref bitfield) => {
                 for (index, byte) in bitfield.iter().enumerate() {
                     for i in 0..8 { //cast up so i don't have to deal with overflows
@@ -1,3 +1,4 @@
+#[macro_use]
 extern crate bencode;
 extern crate url;
 extern crate crypto;
@@ -8,7 +9,14 @@ pub mod buffered_reader;
 pub mod bt_messages;
 pub mod querystring;
 pub mod metadata;
+pub mod merkle;
+pub mod magnet;
 pub mod tracker;
+pub mod udp_tracker;
+pub mod announce;
 pub mod peer;
+pub mod ut_metadata;
 pub mod default_handler;
+pub mod web_seed;
+pub mod storage;
 pub mod chunk;
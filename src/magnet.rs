@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use querystring::QueryString;
+use tracker::Address;
+use metadata::truncate_info_hash;
+
+/// Contains functionality to read magnet links, which identify a torrent by its info hash alone
+
+const BTIH_PREFIX: &'static str = "urn:btih:";
+//v2 info hashes are multihashes, 0x12 for SHA-256 followed by the length 0x20
+const BTMH_PREFIX: &'static str = "urn:btmh:1220";
+const BASE32_ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MagnetError {
+    /// Doesn't start with magnet:?
+    NotAMagnet,
+    /// There is neither an xt=urn:btih: nor an xt=urn:btmh: parameter
+    MissingInfoHash,
+    /// The info hash is neither 40 hex characters nor 32 base32 characters, or for v2 not 64 hex
+    /// characters
+    InvalidInfoHash(String),
+    /// A parameter has a malformed percent escape or isn't text
+    InvalidEncoding(String),
+    /// A so parameter that isn't a list of indices and ranges
+    InvalidSelection(String)
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MagnetError::NotAMagnet => write!(f, "not a magnet link"),
+            MagnetError::MissingInfoHash => write!(f, "magnet link has no bittorrent info hash"),
+            MagnetError::InvalidInfoHash(ref hash) => write!(f, "invalid info hash {}", hash),
+            MagnetError::InvalidEncoding(ref param) => write!(f, "invalid encoding in {}", param),
+            MagnetError::InvalidSelection(ref so) => write!(f, "invalid file selection {}", so)
+        }
+    }
+}
+
+impl Error for MagnetError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    /// The v1 info hash, or for v2 only links the v2 one truncated to 20 bytes
+    pub info_hash: [u8; 20],
+    /// The SHA-256 info hash of v2 and hybrid torrents (BEP 52)
+    pub info_hash_v2: Option<[u8; 32]>,
+    /// dn, the name to show until the metadata is known
+    pub display_name: Option<String>,
+    /// tr, in the order given
+    pub trackers: Vec<String>,
+    /// x.pe, peers to connect to directly as host:port
+    pub peers: Vec<String>,
+    /// ws, web seed urls
+    pub web_seeds: Vec<String>,
+    /// so (BEP 53), ranges of indices of the files to download, ends included. Empty means all
+    /// of them
+    pub select_only: Vec<(usize, usize)>
+}
+
+impl MagnetLink {
+    pub fn parse (uri: &str) -> Result<MagnetLink, MagnetError> {
+        let query = match uri.find("?") {
+            Some(i) if uri[..i].eq_ignore_ascii_case("magnet:") => &uri[i+1..],
+            _ => return Err(MagnetError::NotAMagnet)
+        };
+
+        let mut link = MagnetLink {
+            info_hash: [0; 20],
+            info_hash_v2: None,
+            display_name: None,
+            trackers: vec![],
+            peers: vec![],
+            web_seeds: vec![],
+            select_only: vec![]
+        };
+        let mut info_hash = None;
+
+        for param in query.split('&').filter(|x| !x.is_empty()) {
+            let (key, raw) = match param.find('=') {
+                Some(i) => (&param[..i], &param[i+1..]),
+                None => (param, "")
+            };
+            let value = QueryString::decode_component(raw)
+                .and_then(|x| String::from_utf8(x).ok())
+                .ok_or_else(|| MagnetError::InvalidEncoding(key.to_string()))?;
+
+            //keys can carry a numeric suffix when repeated, as in tr.1 and tr.2
+            let key = match key.rfind('.') {
+                Some(i) if key[i+1..].chars().all(|c| c.is_digit(10)) => &key[..i],
+                _ => key
+            };
+
+            match key {
+                "xt" if info_hash.is_none() && value.starts_with(BTIH_PREFIX) => {
+                    info_hash = Some(decode_info_hash(&value[BTIH_PREFIX.len()..])?);
+                },
+                "xt" if link.info_hash_v2.is_none() && value.starts_with(BTMH_PREFIX) => {
+                    link.info_hash_v2 = Some(decode_info_hash_v2(&value[BTMH_PREFIX.len()..])?);
+                },
+                "dn" => link.display_name = Some(value),
+                "tr" => link.trackers.push(value),
+                "x.pe" => link.peers.push(value),
+                "ws" => link.web_seeds.push(value),
+                "so" => link.select_only = decode_selection(&value)?,
+                _ => {}
+            }
+        }
+
+        link.info_hash = match (info_hash, link.info_hash_v2) {
+            (Some(hash), _) => hash,
+            (None, Some(ref hash)) => truncate_info_hash(hash),
+            (None, None) => return Err(MagnetError::MissingInfoHash)
+        };
+        Ok(link)
+    }
+
+    /// Resolves the x.pe peers, IPv6 ones written as [address]:port. Peers that can't be resolved
+    /// are left out
+    pub fn peer_addresses (&self) -> Vec<Address> {
+        self.peers.iter().flat_map(|peer| {
+            peer.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+        }).map(Address::from).collect()
+    }
+
+    /// Whether the file at index is one to download
+    pub fn is_selected (&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|&(start, end)| start <= index && index <= end)
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str (uri: &str) -> Result<MagnetLink, MagnetError> {
+        MagnetLink::parse(uri)
+    }
+}
+
+/// Info hashes come as 40 hex characters, or in older links as 32 base32 characters
+fn decode_info_hash (encoded: &str) -> Result<[u8; 20], MagnetError> {
+    let bytes = match encoded.len() {
+        40 => decode_hex(encoded),
+        32 => decode_base32(encoded),
+        _ => None
+    };
+    match bytes {
+        Some(bytes) => {
+            let mut hash = [0; 20];
+            hash.copy_from_slice(&bytes);
+            Ok(hash)
+        },
+        None => Err(MagnetError::InvalidInfoHash(encoded.to_string()))
+    }
+}
+
+//None unless every character is a hex digit, signs and multibyte characters included
+fn decode_hex (encoded: &str) -> Option<Vec<u8>> {
+    let bytes = encoded.as_bytes();
+    if bytes.len() % 2 != 0 || !bytes.iter().all(|x| x.is_ascii_hexdigit()) {
+        return None
+    }
+    let digit = |x: u8| (x as char).to_digit(16).unwrap() as u8;
+    Some(bytes.chunks(2).map(|x| digit(x[0]) << 4 | digit(x[1])).collect())
+}
+
+fn decode_base32 (encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&x| x == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+fn decode_info_hash_v2 (encoded: &str) -> Result<[u8; 32], MagnetError> {
+    let bytes = match encoded.len() {
+        64 => decode_hex(encoded),
+        _ => None
+    };
+    match bytes {
+        Some(bytes) => {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes);
+            Ok(hash)
+        },
+        None => Err(MagnetError::InvalidInfoHash(encoded.to_string()))
+    }
+}
+
+/// Reads a list like 0,2,4-6 into the ranges it covers, (0, 0), (2, 2) and (4, 6). Ranges are
+/// kept as they are rather than expanded, 0-18446744073709551615 being a valid one
+fn decode_selection (so: &str) -> Result<Vec<(usize, usize)>, MagnetError> {
+    let invalid = || MagnetError::InvalidSelection(so.to_string());
+    let index = |x: &str| if !x.is_empty() && x.bytes().all(|x| x.is_ascii_digit()) { x.parse::<usize>().ok() } else { None };
+    let mut ranges = vec![];
+    for part in so.split(',') {
+        let mut bounds = part.splitn(2, '-').map(&index);
+        match (bounds.next(), bounds.next()) {
+            (Some(Some(index)), None) => ranges.push((index, index)),
+            (Some(Some(start)), Some(Some(end))) if start <= end => ranges.push((start, end)),
+            _ => return Err(invalid())
+        }
+    }
+    Ok(ranges)
+}
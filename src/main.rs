@@ -2,17 +2,30 @@ extern crate bencode;
 extern crate bittorrent;
 extern crate time;
 
-use std::{env, thread};
+use std::{env, io, process, thread};
+use std::net::{TcpListener, TcpStream};
 use std::thread::{JoinHandle};
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
 use std::ops::{Deref, DerefMut};
-use bittorrent::metadata::{Metadata, MetadataDict};
-use bencode::{Bencode, deserialize_file};
+use std::path::PathBuf;
+use std::time::Duration;
+use bittorrent::metadata::Metadata;
+use bittorrent::magnet::MagnetLink;
+use bencode::open_file;
 use bittorrent::bt_messages::Message;
-use bittorrent::tracker::{get_http_tracker_peers, PEER_ID_PREFIX};
-use bittorrent::peer::{connect_to_peer, gen_rand_peer_id, Peer, SendPeerMessage};
+use bittorrent::tracker::{local_addresses, Address, TrackerList, PEER_ID_PREFIX};
+use bittorrent::announce::AnnounceScheduler;
+use bittorrent::peer::{accept_peer, connect_to_peer, gen_rand_peer_id, Handshake, Peer, SendPeerMessage};
+use bittorrent::buffered_reader::BufferedReader;
 use bittorrent::default_handler::{Handler, DefaultHandler, GlobalState, Spin};
+use bittorrent::web_seed::{start_web_seeds, WebSeed, WebSeedKind};
+
+//the port we take connections from peers on, as announced to trackers
+const LISTEN_PORT: u16 = 6887;
+//how long shutdown waits for the tracker to hear we're stopping, in seconds. a UDP tracker that
+//has gone quiet takes minutes to give up on
+const STOP_TIMEOUT: u64 = 10;
 
 // Sets up a sink pool. it functions similarly to an Actor
 /// atm, rust doesn't support HKTs
@@ -22,7 +35,11 @@ fn init (global_arc: Arc<Mutex<GlobalState>>, mut handler: DefaultHandler) -> (S
     let (tx, rx) = channel();
     let sink = thread::spawn(move|| {
         loop {
-            let (message, cell): (Message, Arc<RwLock<Peer>>) = rx.recv().unwrap();
+            //every sender gone means we're shutting down
+            let (message, cell): (Message, Arc<RwLock<Peer>>) = match rx.recv() {
+                Ok(x) => x,
+                Err(_) => break
+            };
 
             let mut gs_guard = (&global_arc).lock().unwrap();
             {
@@ -51,60 +68,41 @@ fn init (global_arc: Arc<Mutex<GlobalState>>, mut handler: DefaultHandler) -> (S
     (tx, sink)
 }
 
-/// Sets up a transmission based on a single torrent
-fn init_torrent (tx: &Sender<(Message, Arc<RwLock<Peer>>)>, metadata: &Metadata, listen_port: u32, bytes_dled: u32, global_arc: Arc<Mutex<GlobalState>>) {
-    let peer_id = gen_rand_peer_id(PEER_ID_PREFIX);
-    let peers = match get_http_tracker_peers(&peer_id, metadata, listen_port, bytes_dled) {
-        Some(peers) => peers,
-        _ => panic!("cannot get peers from tracker")
-    };
-    println!("got {} peers", peers.len());
+/// Sets up a transmission based on a single torrent. Peers given up front (from a magnet link)
+/// are connected to right away, those from the trackers as announces come back. The announcer
+/// runs until stop gets a message or hangs up, then tells the tracker we're leaving. The returned
+/// receiver hears once it's done
+fn init_torrent (tx: Sender<(Message, Arc<RwLock<Peer>>)>, info_hash: [u8; 20], peer_id: String, trackers: TrackerList, peers: Vec<Address>, global_arc: Arc<Mutex<GlobalState>>, stop: Receiver<()>) -> Receiver<()> {
+    let mut scheduler = AnnounceScheduler::new(trackers, info_hash, peer_id.clone(), LISTEN_PORT);
+    let (ipv4, ipv6) = local_addresses();
+    scheduler.set_local_addresses(ipv4, ipv6);
+    let mut known = peers.clone();
+    connect_peers(&tx, peers, info_hash, &peer_id, &global_arc);
 
+    let (done_tx, done_rx) = channel();
+    thread::spawn(move || {
+        let ga = global_arc.clone();
+        scheduler.run(global_arc, stop, |peers| {
+            let new_peers = peers.into_iter().filter(|x| !known.contains(x)).collect::<Vec<Address>>();
+            println!("got {} new peers", new_peers.len());
+            known.extend(new_peers.iter().cloned());
+            connect_peers(&tx, new_peers, info_hash, &peer_id, &ga);
+        });
+        let _ = done_tx.send(());
+    });
+    done_rx
+}
+
+/// Connects to each of the peers on a thread of its own, which feeds their messages to the sink
+fn connect_peers (tx: &Sender<(Message, Arc<RwLock<Peer>>)>, peers: Vec<Address>, info_hash: [u8; 20], peer_id: &String, global_arc: &Arc<Mutex<GlobalState>>) {
     for peer in peers {
-        let child_meta = metadata.clone();
         let peer_id = peer_id.clone();
         let tx = tx.clone();
         let ga = global_arc.clone();
 
         thread::spawn(move || {
-            match connect_to_peer(peer, &child_meta, &peer_id) {
-                Ok((peer_id, mut reader)) => {
-                    let peer_id_str = peer_id.iter().map(|x| *x as char).collect::<String>();
-                    let mut peer = Peer::new(peer_id_str);
-                    peer.state.set_us_interested(true);
-
-                    let peer_cell = RwLock::new(peer);
-                    let arc = Arc::new(peer_cell);
-                    let mut pstream = reader.clone_stream();
-
-                    pstream.send_message(Message::Interested);
-
-                    { //add to the global peer list
-                        let _ga = ga.clone();
-                        let mut _y = (&_ga).lock().unwrap();
-                        let mut _x = _y.deref_mut();
-                        _x.add_new_peer(arc.clone(), pstream, peer_id.to_owned());
-                    } //release da lock
-
-                    loop {
-                        //we can't just block read in a loop - we'll never have a chance to send out
-                        //outgoing messages over TCP
-                        match reader.wait_for_message() {
-                            Ok(message) => tx.send((message, arc.clone())),
-                            Err(e) => {
-                                println!("error waiting for message: {:?}", e);
-                                let _ga = ga.clone();
-                                let mut gstate =  _ga.deref().lock().unwrap();
-
-                                gstate.remove_peer(&peer_id);
-
-                                //TODO: need to signal the handler thread that the client has
-                                //disconnected
-                                break;
-                            }
-                        };
-                    }
-                },
+            match connect_to_peer(peer, &info_hash, &peer_id) {
+                Ok((handshake, reader)) => run_peer(tx, ga, handshake, reader),
                 Err(e) => {
                     println!("{:?}", e);
                 }
@@ -113,26 +111,126 @@ fn init_torrent (tx: &Sender<(Message, Arc<RwLock<Peer>>)>, metadata: &Metadata,
     }
 }
 
+/// Takes connections from peers on listen_port, serving each on a thread of its own just like
+/// the ones we make. Stops once the session is shut down
+fn listen_for_peers (tx: &Sender<(Message, Arc<RwLock<Peer>>)>, listen_port: u16, info_hash: [u8; 20], peer_id: &String, global_arc: &Arc<Mutex<GlobalState>>) -> JoinHandle<()> {
+    let (tx, peer_id, ga) = (tx.clone(), peer_id.clone(), global_arc.clone());
+    thread::spawn(move || {
+        let listener = match TcpListener::bind(("0.0.0.0", listen_port)).and_then(|x| x.set_nonblocking(true).map(|_| x)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("unable to listen on port {}: {}", listen_port, e);
+                return
+            }
+        };
+        //polled rather than blocked on, as the tx we hold would keep the sink from shutting down
+        while !ga.lock().unwrap().is_closed() {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    println!("connection from {}", address);
+                    let _ = stream.set_nonblocking(false);
+                    let (tx, peer_id, ga) = (tx.clone(), peer_id.clone(), ga.clone());
+                    thread::spawn(move || {
+                        match accept_peer(stream, &info_hash, &peer_id) {
+                            Ok((handshake, reader)) => run_peer(tx, ga, handshake, reader),
+                            Err(e) => {
+                                println!("{:?}", e);
+                            }
+                        };
+                    });
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(e) => println!("unable to accept peer: {}", e)
+            }
+        }
+    })
+}
+
+/// Adds a peer we've exchanged handshakes with to the global peer list and feeds its messages to
+/// the sink until it goes away
+fn run_peer (tx: Sender<(Message, Arc<RwLock<Peer>>)>, ga: Arc<Mutex<GlobalState>>, handshake: Handshake, mut reader: BufferedReader<TcpStream>) {
+    let peer_id = handshake.peer_id.clone();
+    let peer_id_str = peer_id.iter().map(|x| *x as char).collect::<String>();
+    let mut peer = Peer::new(peer_id_str);
+    peer.state.set_us_interested(true);
+
+    let peer_cell = RwLock::new(peer);
+    let arc = Arc::new(peer_cell);
+    let mut pstream = reader.clone_stream();
+
+    pstream.send_message(Message::Interested);
+    if handshake.supports_extensions() {
+        let _ga = ga.clone();
+        let extended_handshake = _ga.lock().unwrap().extended_handshake();
+        pstream.send_message(extended_handshake);
+    }
+
+    { //add to the global peer list
+        let _ga = ga.clone();
+        let mut _y = (&_ga).lock().unwrap();
+        let mut _x = _y.deref_mut();
+        _x.add_new_peer(arc.clone(), pstream, peer_id.to_owned());
+    } //release da lock
+
+    loop {
+        //we can't just block read in a loop - we'll never have a chance to send out
+        //outgoing messages over TCP
+        match reader.wait_for_message() {
+            Ok(message) => tx.send((message, arc.clone())),
+            Err(e) => {
+                println!("error waiting for message: {:?}", e);
+                let _ga = ga.clone();
+                let mut gstate =  _ga.deref().lock().unwrap();
+
+                gstate.remove_peer(&peer_id);
+
+                //TODO: need to signal the handler thread that the client has
+                //disconnected
+                break;
+            }
+        };
+    }
+}
+
 fn main () {
     let path = env::args().nth(1)
-                          .unwrap_or_else(||panic!("no path to torrent provided"));
+                          .unwrap_or_else(||panic!("no path to torrent or magnet link provided"));
 
-    let content = deserialize_file(path).unwrap_or_else(||panic!("unable to parse bencoded metadata"));
-
-    assert_eq!(content.len(), 1);
+    //a magnet link only gets us the info hash, the metadata has to come from peers
+    let (mut global_state, trackers, peers, web_seeds) = if path.starts_with("magnet:") {
+        let magnet = MagnetLink::parse(&path).unwrap_or_else(|e| {
+            println!("invalid magnet link {}: {}", path, e);
+            process::exit(1)
+        });
+        let web_seeds = magnet.web_seeds.iter().map(|url| WebSeed {url: url.clone(), kind: WebSeedKind::GetRight}).collect();
+        (GlobalState::from_magnet(&magnet), TrackerList::from_magnet(&magnet), magnet.peer_addresses(), web_seeds)
+    } else {
+        let content = open_file(&path).unwrap_or_else(|e| {
+            println!("unable to read torrent file {}: {}", path, e);
+            process::exit(1)
+        });
 
-    let metadata = match content.first() {
-        Some(&Bencode::Dict(ref dict)) => dict.to_metadata(),
-        _ => panic!("no valid information in torrent file")
-    }.unwrap();
+        let metadata = Metadata::from_bytes(&content).unwrap_or_else(|e| {
+            println!("invalid torrent file {}: {}", path, e);
+            process::exit(1)
+        });
+        (GlobalState::new(&metadata), TrackerList::from_metadata(&metadata), vec![], WebSeed::from_metadata(&metadata))
+    };
 
-    let global_state = GlobalState::new(&metadata);
+    //files go in the directory given after the torrent, or the current one
+    global_state.set_download_dir(env::args().nth(2).map(PathBuf::from).unwrap_or_else(|| PathBuf::from(".")));
+    let info_hash = global_state.info_hash;
     let global_arc = Arc::new(Mutex::new(global_state));
 
     let (tx, sink) = init(global_arc.clone(), DefaultHandler);
+    start_web_seeds(&global_arc, web_seeds);
 
     //for now initialize torrents inline with main
-    init_torrent(&tx, &metadata, 6887, 0, global_arc.clone());
+    let (stop_tx, stop_rx) = channel();
+    let peer_id = gen_rand_peer_id(PEER_ID_PREFIX);
+    let listener = listen_for_peers(&tx, LISTEN_PORT, info_hash, &peer_id, &global_arc);
+    let announcer = init_torrent(tx.clone(), info_hash, peer_id, trackers, peers, global_arc.clone(), stop_rx);
+    let shutdown_arc = global_arc.clone();
 
     let spin_thread = thread::spawn(move || {
         loop {
@@ -147,7 +245,22 @@ fn main () {
         }
     });
 
-    //block until the sink shuts down
+    //a line on stdin ends the session. without a terminal to read from, run until killed
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => {
+            println!("stdin is closed, running until killed without telling the trackers when we stop");
+            loop { thread::park() }
+        },
+        Ok(_) => {}
+    }
+
+    //tell the trackers we're leaving while the peers are still up, then let the sink drain
+    let _ = stop_tx.send(());
+    let _ = announcer.recv_timeout(Duration::from_secs(STOP_TIMEOUT));
+    shutdown_arc.lock().unwrap().disconnect_all();
+    let _ = listener.join();
+    drop(tx);
     let _ = sink.join();
     //test();
 }
@@ -0,0 +1,77 @@
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+
+/// Contains the SHA-256 merkle trees of BitTorrent v2 (BEP 52). Every file is hashed on its own in
+/// 16KiB blocks, the leaves of a binary tree whose width is padded out to a power of two with
+/// zero hashes
+
+pub const BLOCK_SIZE: usize = 16384;
+pub const ZERO_HASH: [u8; 32] = [0; 32];
+
+/// The SHA-256 digest of bytes
+pub fn sha256 (bytes: &[u8]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.input(bytes);
+    let mut hash = [0; 32];
+    sha.result(&mut hash);
+    hash
+}
+
+/// The hash of a parent node
+pub fn join (left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.input(left);
+    sha.input(right);
+    let mut hash = [0; 32];
+    sha.result(&mut hash);
+    hash
+}
+
+/// Hashes data in 16KiB blocks. The last block may be shorter and is hashed as it is
+pub fn block_hashes (data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+/// The root of a tree with leaves on the bottom, filled up to width (a power of two) with pad
+pub fn root (leaves: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = leaves.to_vec();
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|pair| join(&pair[0], &pair[1])).collect();
+    }
+    layer[0]
+}
+
+/// The root of a subtree of width zero leaves, which is what the piece layer is padded with
+pub fn pad_hash (width: usize) -> [u8; 32] {
+    root(&[], width, ZERO_HASH)
+}
+
+/// The uncle hashes needed to check leaf index against the root, from the bottom up
+pub fn proof (leaves: &[[u8; 32]], width: usize, index: usize) -> Vec<[u8; 32]> {
+    let mut layer = leaves.to_vec();
+    layer.resize(width.max(1), ZERO_HASH);
+    let mut index = index;
+    let mut uncles = vec![];
+    while layer.len() > 1 {
+        uncles.push(layer[index ^ 1]);
+        layer = layer.chunks(2).map(|pair| join(&pair[0], &pair[1])).collect();
+        index /= 2;
+    }
+    uncles
+}
+
+/// Checks that leaf sits at index in the tree with the given root, using the uncle hashes from
+/// proof
+pub fn verify (root: &[u8; 32], index: usize, leaf: &[u8; 32], proof: &[[u8; 32]]) -> bool {
+    if proof.len() < 64 && index >> proof.len() != 0 {
+        return false
+    }
+    let hash = proof.iter().enumerate().fold(*leaf, |hash, (height, uncle)| {
+        match (index >> height) & 1 {
+            0 => join(&hash, uncle),
+            _ => join(uncle, &hash)
+        }
+    });
+    hash == *root
+}
@@ -1,20 +1,118 @@
-use std::collections::HashMap;
-use std::str;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use crypto::sha1::Sha1;
 use crypto::digest::Digest;
-use bencode::{Bencode, TypedMethods, BencodeToString};
+use merkle::{self, BLOCK_SIZE, ZERO_HASH};
+use bencode::{Bencode, BencodeError, SpannedBencode, BencodeToString, ToBencode, ToBencodeError, FromBencode, FromBencodeError, Encoder, EncodeError, from_dict_entry, deserialize_with_spans};
 
-#[derive(Clone, Debug)]
-pub struct SingleFileInfo {
-    length: i64,
-    md5sum: Option<Vec<u8>>
+/// The attr string of a file (BEP 47). Letters this doesn't know are ignored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileAttributes {
+    /// p, padding that keeps the next file on a piece boundary. Pad files are all zeroes and
+    /// never go to disk
+    pub pad: bool,
+    /// x
+    pub executable: bool,
+    /// h
+    pub hidden: bool,
+    /// l, the file is a symlink to its symlink path
+    pub symlink: bool
+}
+
+impl FileAttributes {
+    pub fn parse (attr: &str) -> FileAttributes {
+        FileAttributes {
+            pad: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l')
+        }
+    }
+
+    pub fn is_empty (&self) -> bool {
+        *self == FileAttributes::default()
+    }
+}
+
+impl fmt::Display for FileAttributes {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [(self.executable, 'x'), (self.hidden, 'h'), (self.pad, 'p'), (self.symlink, 'l')];
+        write!(f, "{}", flags.iter().filter(|x| x.0).map(|x| x.1).collect::<String>())
+    }
+}
+
+impl ToBencode for FileAttributes {
+    fn to_bencode (&self) -> Result<Bencode, ToBencodeError> {
+        Ok(Bencode::ByteString(self.to_string().into_bytes()))
+    }
+
+    fn to_bencode_entry (&self) -> Result<Option<Bencode>, ToBencodeError> {
+        if self.is_empty() { Ok(None) } else { self.to_bencode().map(Some) }
+    }
+}
+
+impl FromBencode for FileAttributes {
+    fn from_bencode (value: &Bencode) -> Result<FileAttributes, FromBencodeError> {
+        String::from_bencode(value).map(|attr| FileAttributes::parse(&attr))
+    }
+
+    fn from_missing () -> Option<FileAttributes> {
+        Some(FileAttributes::default())
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct FileInfo {
-    length: i64,
+pub struct SingleFileInfo {
+    length: u64,
     md5sum: Option<Vec<u8>>,
-    path: Vec<String>
+    attributes: FileAttributes,
+    sha1: Option<Vec<u8>>
+}
+
+bencode_struct! {
+    #[derive(Clone, Debug)]
+    pub struct FileInfo {
+        "attr" => attributes: FileAttributes,
+        "length" => length: u64,
+        "md5sum" => md5sum: Option<Vec<u8>>,
+        "path" => path: Vec<String>,
+        "sha1" => sha1: Option<Vec<u8>>,
+        "symlink path" => symlink_path: Option<Vec<String>>
+    }
+}
+
+bencode_struct! {
+    //a torrent as it's laid out. which keys are needed depends on the version and the number of
+    //files, so those are optional here and checked in to_metadata_with_hash
+    struct TorrentDict {
+        "announce-list" => announce_list: Option<Vec<Vec<String>>>,
+        "announce" => announce: Option<String>,
+        //a single url or a list of them
+        "url-list" => url_list: Option<Bencode>,
+        "httpseeds" => http_seeds: Option<Vec<String>>,
+        "info" => info: InfoDict,
+        "piece layers" => piece_layers: Option<BTreeMap<Vec<u8>, Bencode>>
+    }
+}
+
+bencode_struct! {
+    struct InfoDict {
+        "name" => name: String,
+        "piece length" => piece_length: u64,
+        "meta version" => meta_version: Option<u64>,
+        "file tree" => file_tree: Option<BTreeMap<Vec<u8>, Bencode>>,
+        "files" => files: Option<Vec<FileInfo>>,
+        "length" => length: Option<u64>,
+        "md5sum" => md5sum: Option<Vec<u8>>,
+        "attr" => attributes: FileAttributes,
+        "sha1" => sha1: Option<Vec<u8>>,
+        "pieces" => pieces: Option<Vec<u8>>
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -28,93 +126,1053 @@ pub enum FileMode {
     MultiFile(MultiFileInfo)
 }
 
+/// A file of the torrent, as it will be laid out on disk
+#[derive(Clone, Debug, PartialEq)]
+pub struct TorrentFile {
+    /// Path components relative to the download directory. For multi-file torrents this starts
+    /// with the torrent name, which is the directory the files go in
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Where the file starts in the torrent, treating all files as one concatenated stream
+    pub offset: u64,
+    pub attributes: FileAttributes,
+    /// What a symlink points to, in path components relative to the download directory like path
+    pub symlink_path: Option<Vec<String>>
+}
+
+impl TorrentFile {
+    /// Where the file goes under the download directory dir
+    pub fn local_path (&self, dir: &Path) -> PathBuf {
+        self.path.iter().fold(dir.to_path_buf(), |a, b| a.join(b))
+    }
+
+    /// Applies the attributes that only make sense once the file is complete: a symlink is put
+    /// in place of the file and executables get an x bit wherever they have an r bit. Hidden
+    /// files are left alone, on unix that is down to the name
+    #[cfg(unix)]
+    pub fn apply_attributes (&self, dir: &Path) -> io::Result<()> {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let path = self.local_path(dir);
+        if let Some(ref target) = self.symlink_path {
+            //links are relative to where they sit so the download can be moved. keep them from
+            //pointing outside of it
+            if target.iter().any(|x| x.is_empty() || x == "." || x == ".." || x.contains('/')) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid symlink path {}", target.join("/"))))
+            }
+            let parent = &self.path[..self.path.len() - 1];
+            let common = parent.iter().zip(target.iter()).take_while(|&(a, b)| a == b).count();
+            let up = (common..parent.len()).fold(PathBuf::new(), |a, _| a.join(".."));
+            if fs::symlink_metadata(&path).is_ok() {
+                fs::remove_file(&path)?;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            return symlink(target[common..].iter().fold(up, |a, b| a.join(b)), &path)
+        }
+        if self.attributes.executable {
+            let mut permissions = fs::metadata(&path)?.permissions();
+            let mode = permissions.mode();
+            permissions.set_mode(mode | (mode & 0o444) >> 2);
+            fs::set_permissions(&path, permissions)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply_attributes (&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A file from the file tree of a v2 torrent (BEP 52)
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeFile {
+    /// Path components within the file tree, which for a single file torrent is just its name
+    pub path: Vec<String>,
+    pub length: u64,
+    /// The root of the file's merkle tree. Empty files have none
+    pub pieces_root: Option<[u8; 32]>,
+    pub attributes: FileAttributes,
+    pub symlink_path: Option<Vec<String>>
+}
+
+/// Which versions of the protocol a torrent can be shared with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TorrentVersion {
+    V1,
+    V2,
+    /// Both v1 pieces and a v2 file tree, describing the same data
+    Hybrid
+}
+
+impl SingleFileInfo {
+    pub fn length (&self) -> u64 {
+        self.length
+    }
+
+    pub fn md5sum (&self) -> Option<&[u8]> {
+        self.md5sum.as_ref().map(|x| &x[..])
+    }
+
+    pub fn attributes (&self) -> FileAttributes {
+        self.attributes
+    }
+
+    /// The SHA1 of the whole file, if the torrent gives it
+    pub fn sha1 (&self) -> Option<&[u8]> {
+        self.sha1.as_ref().map(|x| &x[..])
+    }
+}
+
+impl FileInfo {
+    pub fn length (&self) -> u64 {
+        self.length
+    }
+
+    pub fn md5sum (&self) -> Option<&[u8]> {
+        self.md5sum.as_ref().map(|x| &x[..])
+    }
+
+    /// Path components relative to the torrent's directory
+    pub fn path (&self) -> &[String] {
+        &self.path
+    }
+
+    pub fn attributes (&self) -> FileAttributes {
+        self.attributes
+    }
+
+    pub fn is_pad (&self) -> bool {
+        self.attributes.pad
+    }
+
+    /// The SHA1 of the whole file, if the torrent gives it
+    pub fn sha1 (&self) -> Option<&[u8]> {
+        self.sha1.as_ref().map(|x| &x[..])
+    }
+
+    /// What a symlink points to, relative to the torrent's directory
+    pub fn symlink_path (&self) -> Option<&[String]> {
+        self.symlink_path.as_ref().map(|x| &x[..])
+    }
+}
+
+impl MultiFileInfo {
+    pub fn files (&self) -> &[FileInfo] {
+        &self.files
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Metadata {
+    /// Empty for trackerless torrents
     pub announce: String,
+    /// Tiers of trackers from announce-list (BEP 12), empty if the torrent has none
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seeds serving the files themselves, from url-list (BEP 19)
+    pub url_list: Vec<String>,
+    /// Web seeds serving pieces through a script, from httpseeds (BEP 17)
+    pub http_seeds: Vec<String>,
+    /// The hash used in handshakes and tracker requests. The SHA1 of the info dict for v1 and
+    /// hybrid torrents, the SHA-256 cut down to 20 bytes for v2 only ones
     pub info_hash: [u8; 20],
+    /// The full SHA-256 of the info dict, for v2 and hybrid torrents
+    pub info_hash_v2: Option<[u8; 32]>,
     name: String,
-    pub piece_length: i64,
+    pub piece_length: u64,
+    /// SHA1 piece hashes, empty for v2 only torrents
     pub pieces: Vec<u8>,
     mode_info: FileMode,
+    file_tree: Vec<TreeFile>,
+    //piece layers by pieces root. these sit outside the info dict so they're missing when it
+    //came from peers
+    piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+    //the info dict exactly as hashed, kept to hand out to peers fetching metadata (BEP 9)
+    info_bytes: Vec<u8>
+}
+
+/// Why a torrent couldn't be read. Key paths lead from the top level dictionary to the offending
+/// value, with list entries given by their index, e.g. ["info", "files", "3", "path"]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataError {
+    /// The file isn't valid bencode
+    Bencode(BencodeError),
+    /// The file holds something other than exactly one dictionary
+    NotADict,
+    MissingKey(Vec<String>),
+    /// The value has the wrong type, the expected type is given first
+    WrongType(&'static str, Vec<String>),
+    InvalidUtf8(Vec<String>),
+    /// An integer that is negative or too large where it shouldn't be
+    OutOfRange(Vec<String>),
+    /// The pieces string isn't made up of a 20 byte hash for each piece, its length is given
+    InvalidPieces(usize),
+    /// A pieces root or piece layer of a v2 torrent that is malformed or doesn't add up
+    InvalidHashTree(Vec<String>),
+    /// A file name or path component that is empty, . or .., or has a path separator in it, any
+    /// of which could put a file outside of the download directory
+    InvalidPath(Vec<String>),
+    /// The info dictionary doesn't hash to the info hash it was fetched for
+    InfoHashMismatch
+}
+
+impl MetadataError {
+    /// The key path of the offending value, if the error concerns one
+    pub fn path (&self) -> Option<&[String]> {
+        match *self {
+            MetadataError::MissingKey(ref path)
+            | MetadataError::WrongType(_, ref path)
+            | MetadataError::InvalidUtf8(ref path)
+            | MetadataError::OutOfRange(ref path)
+            | MetadataError::InvalidHashTree(ref path)
+            | MetadataError::InvalidPath(ref path) => Some(path),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MetadataError::Bencode(ref e) => write!(f, "not a valid bencoded file: {}", e),
+            MetadataError::NotADict => write!(f, "torrent is not a single dictionary"),
+            MetadataError::MissingKey(ref path) => write!(f, "missing key {}", path.join("/")),
+            MetadataError::WrongType(expected, ref path) => write!(f, "expected {} at {}", expected, path.join("/")),
+            MetadataError::InvalidUtf8(ref path) => write!(f, "invalid utf-8 at {}", path.join("/")),
+            MetadataError::OutOfRange(ref path) => write!(f, "value out of range at {}", path.join("/")),
+            MetadataError::InvalidPieces(len) => write!(f, "pieces has length {}, which is not 20 bytes for each piece", len),
+            MetadataError::InvalidHashTree(ref path) => write!(f, "invalid hash tree at {}", path.join("/")),
+            MetadataError::InvalidPath(ref path) => write!(f, "invalid file path at {}", path.join("/")),
+            MetadataError::InfoHashMismatch => write!(f, "info dictionary does not match the info hash")
+        }
+    }
+}
+
+impl Error for MetadataError {}
+
+impl From<BencodeError> for MetadataError {
+    fn from (e: BencodeError) -> MetadataError {
+        MetadataError::Bencode(e)
+    }
+}
+
+impl From<FromBencodeError> for MetadataError {
+    fn from (e: FromBencodeError) -> MetadataError {
+        match e {
+            FromBencodeError::MissingKey(path) => MetadataError::MissingKey(path),
+            FromBencodeError::WrongType(expected, path) => MetadataError::WrongType(expected, path),
+            FromBencodeError::InvalidUtf8(path) => MetadataError::InvalidUtf8(path),
+            FromBencodeError::OutOfRange(path) => MetadataError::OutOfRange(path)
+        }
+    }
 }
 
 impl Metadata {
-    pub fn get_total_length (&self) -> u32 {
-        let len = match self.mode_info {
-            FileMode::SingleFile(ref sf) => sf.length,
-            FileMode::MultiFile(ref mf) => mf.files.iter().fold(0, |a:i64, b:&FileInfo| a + b.length)
+    /// Reads a torrent from the raw bytes of a .torrent file. Unlike to_metadata on an already
+    /// decoded dictionary, the info hash is taken over the exact bytes of the info dictionary as
+    /// they appear in the file, so torrents that aren't canonically encoded still hash correctly
+    pub fn from_bytes (bytes: &[u8]) -> Result<Metadata, MetadataError> {
+        let mut values = deserialize_with_spans(bytes)?;
+        let torrent = match (values.pop(), values.len()) {
+            (Some(value @ SpannedBencode::Dict(..)), 0) => value,
+            _ => return Err(MetadataError::NotADict)
         };
-        len as u32
-    }
-}
-
-fn to_file_list (list: &Vec<Bencode>) -> Option<Vec<FileInfo>> {
-    //TODO: figure out how exception handling works
-    Some(list.iter().map(|item| {
-        match item {
-            &Bencode::Dict(ref hm) => {
-                let path_list_bencode = hm.get_list("path")
-                                          .unwrap_or_else(||panic!("unable to get key path"))
-                                          .iter()
-                                          .map(|x| match x {
-                                                    &Bencode::ByteString(ref path) => {
-                                                        //path.to_string(),
-                                                        match str::from_utf8(path) {
-                                                            Ok(v) => v.to_string(),
-                                                            Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                                                        }
-                                                    }
-                                                    _ => panic!("unexpected type")
-                                          }).collect::<Vec<String>>();
-                FileInfo {
-                    length: hm.get_int("length").unwrap_or_else(|| panic!("no length in file")),
-                    md5sum: hm.get_owned_string("md5sum"),
-                    path: path_list_bencode
-                }
+        let info_span = match torrent.get("info") {
+            Some(info @ &SpannedBencode::Dict(..)) => info.span(),
+            Some(_) => return Err(MetadataError::WrongType("dict", vec!["info".to_string()])),
+            None => return Err(MetadataError::MissingKey(vec!["info".to_string()]))
+        };
+
+        let info_bytes = info_span.of(bytes);
+        match torrent.into_bencode() {
+            Bencode::Dict(dict) => dict.to_metadata_with_info(info_bytes.to_vec()),
+            _ => Err(MetadataError::NotADict)
+        }
+    }
+
+    /// The bencoded info dictionary, as it hashes to info_hash
+    pub fn info_bytes (&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    pub fn version (&self) -> TorrentVersion {
+        match (self.info_hash_v2, self.pieces.is_empty()) {
+            (None, _) => TorrentVersion::V1,
+            (Some(_), true) => TorrentVersion::V2,
+            (Some(_), false) => TorrentVersion::Hybrid
+        }
+    }
+
+    /// The files of a v2 or hybrid torrent in the order of the file tree. Empty for v1 torrents
+    pub fn file_tree (&self) -> &[TreeFile] {
+        &self.file_tree
+    }
+
+    /// The piece hashes of the file with the given pieces root, 32 bytes each, if the torrent
+    /// came with them. Files no longer than a piece have none, the root covers them
+    pub fn piece_layer (&self, pieces_root: &[u8]) -> Option<&[u8]> {
+        self.piece_layers.get(pieces_root).map(|x| &x[..])
+    }
+
+    /// The file name for single file torrents, or the directory name for multi-file ones
+    pub fn name (&self) -> &str {
+        &self.name
+    }
+
+    pub fn mode_info (&self) -> &FileMode {
+        &self.mode_info
+    }
+
+    /// All trackers in tiers, falling back to a single tier holding announce
+    pub fn trackers (&self) -> Vec<Vec<String>> {
+        if self.announce_list.iter().any(|tier| !tier.is_empty()) {
+            self.announce_list.iter().filter(|tier| !tier.is_empty()).cloned().collect()
+        } else if self.announce.is_empty() {
+            vec![]
+        } else {
+            vec![vec![self.announce.clone()]]
+        }
+    }
+
+    pub fn is_multi_file (&self) -> bool {
+        match self.mode_info {
+            FileMode::SingleFile(_) => false,
+            FileMode::MultiFile(_) => true
+        }
+    }
+
+    /// Lists the files in the order their data appears in the torrent. Pad files are left out,
+    /// though the offsets of the files after them still count them
+    pub fn files (&self) -> Vec<TorrentFile> {
+        match self.mode_info {
+            FileMode::SingleFile(ref sf) => vec![TorrentFile {
+                path: vec![self.name.clone()],
+                length: sf.length,
+                offset: 0,
+                attributes: sf.attributes,
+                symlink_path: None
+            }],
+            FileMode::MultiFile(ref mf) => {
+                //without v1 pieces, and the pad files that come with them, every file starts on
+                //a piece boundary
+                let aligned = self.version() == TorrentVersion::V2;
+                let mut offset = 0;
+                mf.files.iter().flat_map(|file| {
+                    if aligned {
+                        offset = pieces_in(offset, self.piece_length) * self.piece_length;
+                    }
+                    let with_name = |path: &[String]| {
+                        let mut full = vec![self.name.clone()];
+                        full.extend(path.iter().cloned());
+                        full
+                    };
+                    let torrent_file = TorrentFile {
+                        path: with_name(&file.path),
+                        length: file.length,
+                        offset: offset,
+                        attributes: file.attributes,
+                        symlink_path: file.symlink_path.as_ref().map(|x| with_name(x))
+                    };
+                    offset += file.length;
+                    if file.is_pad() { None } else { Some(torrent_file) }
+                }).collect()
+            }
+        }
+    }
+
+    pub fn piece_count (&self) -> usize {
+        match self.version() {
+            TorrentVersion::V2 => self.file_tree.iter().fold(0, |a, b| a + pieces_in(b.length, self.piece_length)) as usize,
+            _ => self.pieces.len() / 20
+        }
+    }
+
+    /// The SHA1 hash of piece number index, if there is such a piece
+    pub fn piece_hash (&self, index: usize) -> Option<&[u8]> {
+        let start = index.checked_mul(20)?;
+        self.pieces.get(start..start.checked_add(20)?)
+    }
+
+    /// The merkle root covering piece number index, for v2 and hybrid torrents. None if its file
+    /// is longer than a piece and the piece layers aren't known
+    pub fn piece_hash_v2 (&self, index: usize) -> Option<[u8; 32]> {
+        let (file, piece) = self.v2_piece(index)?;
+        let root = file.pieces_root?;
+        if file.length <= self.piece_length {
+            return Some(root)
+        }
+        self.piece_layer(&root)
+            .and_then(|layer| layer.get(piece * 32..(piece + 1) * 32))
+            .map(to_hash)
+    }
+
+    /// Checks the data of a whole piece. v1 and hybrid torrents use the SHA1 piece hashes, v2
+    /// ones the merkle tree of the piece's file
+    pub fn verify_piece (&self, index: usize, data: &[u8]) -> bool {
+        if let Some(hash) = self.piece_hash(index) {
+            return sha1(data)[..] == *hash
+        }
+        match (self.v2_piece(index), self.piece_hash_v2(index)) {
+            (Some((file, _)), Some(hash)) => merkle::root(&merkle::block_hashes(data), self.piece_width(file), ZERO_HASH) == hash,
+            _ => false
+        }
+    }
+
+    /// Checks a single 16KiB block of a piece of a v2 or hybrid torrent, with the uncle hashes
+    /// leading up to the piece's hash from the bottom
+    pub fn verify_block (&self, index: usize, block: usize, data: &[u8], proof: &[[u8; 32]]) -> bool {
+        match (self.v2_piece(index), self.piece_hash_v2(index)) {
+            (Some((file, _)), Some(hash)) => {
+                data.len() <= BLOCK_SIZE
+                    && proof.len() == self.piece_width(file).trailing_zeros() as usize
+                    && merkle::verify(&hash, block, &merkle::sha256(data), proof)
             },
-            _ => panic!("not a bencode list of dicts")
+            _ => false
+        }
+    }
+
+    //the file piece number index falls in, and which of its pieces it is
+    fn v2_piece (&self, index: usize) -> Option<(&TreeFile, usize)> {
+        let mut index = index as u64;
+        for file in self.file_tree.iter() {
+            let count = pieces_in(file.length, self.piece_length);
+            if index < count {
+                return Some((file, index as usize))
+            }
+            index -= count;
         }
-    }).collect::<Vec<FileInfo>>())
+        None
+    }
+
+    //how many blocks the tree under a piece hash of the file spans
+    fn piece_width (&self, file: &TreeFile) -> usize {
+        if file.length <= self.piece_length {
+            (pieces_in(file.length, BLOCK_SIZE as u64) as usize).next_power_of_two()
+        } else {
+            self.piece_length as usize / BLOCK_SIZE
+        }
+    }
+
+    /// The length of piece number index in bytes. All pieces are piece_length long except the
+    /// last one, and in v2 torrents the last one of each file
+    pub fn piece_size (&self, index: usize) -> u64 {
+        match self.version() {
+            TorrentVersion::V2 => match self.v2_piece(index) {
+                Some((file, piece)) => cmp::min(self.piece_length, file.length - piece as u64 * self.piece_length),
+                None => 0
+            },
+            _ => cmp::min(self.piece_length, self.total_length().saturating_sub((index as u64).saturating_mul(self.piece_length)))
+        }
+    }
+
+    /// The combined length of all files in bytes, pad files included
+    pub fn total_length (&self) -> u64 {
+        match self.mode_info {
+            FileMode::SingleFile(ref sf) => sf.length,
+            FileMode::MultiFile(ref mf) => mf.files.iter().fold(0, |a, b| a + b.length)
+        }
+    }
+
+    /// How many bytes are still missing once downloaded bytes have been fetched
+    pub fn bytes_left (&self, downloaded: u64) -> u64 {
+        self.total_length().saturating_sub(downloaded)
+    }
 }
 
 pub trait MetadataDict {
-    fn to_metadata (&self) -> Option<Metadata>;
-}
-
-impl MetadataDict for HashMap<String, Bencode> {
-    /// Extracts information from this HashMap into a Metadata instance, if valid. Currently if it
-    /// is invalid, it will just throw a runtime exception
-    fn to_metadata (&self) -> Option<Metadata> {
-        let announce = self.get_string("announce").unwrap_or_else(||panic!("no key found for announce"));
-        let info_dict = self.get_dict("info").unwrap_or_else(||panic!("no key found for info")).to_owned();
-        let mut sha = Sha1::new();
-        let info_as_text = Bencode::Dict(info_dict.clone()).to_bencode_string();
-        // println!("info_dict: {}", info_as_text);
-        sha.input(&Bencode::Dict(info_dict.clone()).to_bencode_string());
-        let mut info_hash:[u8; 20] = [0; 20];
-        let _ = sha.result(&mut info_hash);
-
-        println!("info_hash: {:?}", info_hash);
-
-        let mode_info = match info_dict.get_list("files") {
-            Some(flist) => {
-                FileMode::MultiFile(MultiFileInfo {
-                    files: to_file_list(flist).unwrap_or_else(|| panic!("unable to deserialize filelist"))
-                })
-            },
-            None => FileMode::SingleFile(SingleFileInfo {
-                length: info_dict.get_int("length").unwrap_or_else(||panic!("no key found for length")),
-                md5sum: info_dict.get_owned_string("md5sum")})
-        };
+    fn to_metadata (&self) -> Result<Metadata, MetadataError>;
+
+    /// Like to_metadata, for when the raw bytes of the info dictionary are at hand. These are
+    /// hashed and kept as they are rather than re-encoded
+    fn to_metadata_with_info (&self, info_bytes: Vec<u8>) -> Result<Metadata, MetadataError>;
+}
 
-        //for now only handle single file mode
-        Some(Metadata {
-            announce: str::from_utf8(&announce).unwrap().to_string(),
-            info_hash: info_hash,
-            piece_length: info_dict.get_int("piece length").unwrap_or_else(||panic!("no key found for piece length")),
-            pieces: info_dict.get_owned_string("pieces").unwrap(),
-            name: str::from_utf8(info_dict.get_string("name").unwrap_or_else(||panic!("no key found for name"))).unwrap().to_string(),
-            mode_info: mode_info
+impl MetadataDict for BTreeMap<Vec<u8>, Bencode> {
+    /// Extracts information from this dictionary into a Metadata instance, if valid
+    ///
+    /// The info hash is computed by re-encoding the info dictionary, which only matches the
+    /// original file if it was canonically encoded. Prefer Metadata::from_bytes when the raw
+    /// torrent is available
+    fn to_metadata (&self) -> Result<Metadata, MetadataError> {
+        let info_dict = from_dict_entry::<BTreeMap<Vec<u8>, Bencode>>(self, "info")?;
+        let info_bytes = Bencode::Dict(info_dict).to_bencode_string();
+        self.to_metadata_with_info(info_bytes)
+    }
+
+    fn to_metadata_with_info (&self, info_bytes: Vec<u8>) -> Result<Metadata, MetadataError> {
+        to_metadata_with_hash(self, info_bytes)
+    }
+}
+
+/// The SHA1 digest of bytes, as used for info hashes and piece hashes
+pub fn sha1 (bytes: &[u8]) -> [u8; 20] {
+    let mut sha = Sha1::new();
+    sha.input(bytes);
+    let mut hash:[u8; 20] = [0; 20];
+    let _ = sha.result(&mut hash);
+    hash
+}
+
+/// The info hash a v2 torrent goes by on the wire, its SHA-256 cut down to 20 bytes
+pub fn truncate_info_hash (info_hash_v2: &[u8; 32]) -> [u8; 20] {
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&info_hash_v2[..20]);
+    hash
+}
+
+fn to_hash (bytes: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(bytes);
+    hash
+}
+
+//the number of pieces length bytes take up
+fn pieces_in (length: u64, piece_length: u64) -> u64 {
+    length / piece_length + if length % piece_length == 0 { 0 } else { 1 }
+}
+
+//a key of the info dict that this kind of torrent can't do without
+fn missing_info_key (key: &str) -> MetadataError {
+    MetadataError::MissingKey(vec!["info".to_string(), key.to_string()])
+}
+
+fn to_metadata_with_hash (dict: &BTreeMap<Vec<u8>, Bencode>, info_bytes: Vec<u8>) -> Result<Metadata, MetadataError> {
+    let torrent = TorrentDict::from_bencode(&Bencode::Dict(dict.clone()))?;
+    let announce_list = torrent.announce_list.unwrap_or_default();
+    //with an announce-list, announce is only there for older clients and may be left out. with
+    //neither the torrent is trackerless, as are info dicts fetched from peers
+    let announce = match torrent.announce {
+        Some(announce) => announce,
+        None => announce_list.iter().flat_map(|tier| tier.first()).next().cloned().unwrap_or_default()
+    };
+    //url-list may be a single url instead of a list
+    let url_list: Vec<String> = match torrent.url_list {
+        Some(ref urls @ Bencode::List(_)) => FromBencode::from_bencode(urls).map_err(|e: FromBencodeError| e.at("url-list"))?,
+        Some(ref url) => vec![String::from_bencode(url).map_err(|e| e.at("url-list"))?],
+        None => vec![]
+    };
+    let http_seeds = torrent.http_seeds.unwrap_or_default();
+    let info = torrent.info;
+    let name = info.name;
+    if !is_valid_component(&name) {
+        return Err(MetadataError::InvalidPath(vec!["info".to_string(), "name".to_string()]))
+    }
+    let piece_length = info.piece_length;
+    if piece_length == 0 {
+        return Err(MetadataError::OutOfRange(vec!["info".to_string(), "piece length".to_string()]))
+    }
+
+    let v2 = match info.meta_version {
+        None | Some(1) => false,
+        Some(2) => true,
+        Some(_) => return Err(MetadataError::OutOfRange(vec!["info".to_string(), "meta version".to_string()]))
+    };
+    let (info_hash_v2, file_tree, piece_layers) = if v2 {
+        if piece_length < BLOCK_SIZE as u64 || !piece_length.is_power_of_two() {
+            return Err(MetadataError::OutOfRange(vec!["info".to_string(), "piece length".to_string()]))
+        }
+        let tree = info.file_tree.as_ref().ok_or_else(|| missing_info_key("file tree"))?;
+        let mut files = vec![];
+        read_file_tree(tree, &mut vec![], &mut files)?;
+        let layers = torrent.piece_layers.unwrap_or_default();
+        (Some(merkle::sha256(&info_bytes)), files.clone(), read_piece_layers(&layers, &files, piece_length)?)
+    } else {
+        (None, vec![], BTreeMap::new())
+    };
+
+    //hybrid torrents carry everything a v1 torrent does, v2 only ones have the file tree alone
+    let v1 = !v2 || info.pieces.is_some();
+    let mode_info = match info.files {
+        _ if !v1 => match file_tree.first() {
+            Some(file) if file_tree.len() == 1 && file.path == [name.clone()] => FileMode::SingleFile(SingleFileInfo {
+                length: file.length,
+                md5sum: None,
+                attributes: file.attributes,
+                sha1: None
+            }),
+            _ => FileMode::MultiFile(MultiFileInfo {
+                files: file_tree.iter().map(|file| FileInfo {
+                    attributes: file.attributes,
+                    length: file.length,
+                    md5sum: None,
+                    path: file.path.clone(),
+                    sha1: None,
+                    symlink_path: file.symlink_path.clone()
+                }).collect()
+            })
+        },
+        Some(files) => {
+            for (i, file) in files.iter().enumerate() {
+                let at = |key: &str| vec!["info".to_string(), "files".to_string(), i.to_string(), key.to_string()];
+                check_path(&file.path, at("path"))?;
+                if let Some(ref target) = file.symlink_path {
+                    check_path(target, at("symlink path"))?;
+                }
+            }
+            FileMode::MultiFile(MultiFileInfo {
+                files: files
+            })
+        },
+        None => FileMode::SingleFile(SingleFileInfo {
+            length: info.length.ok_or_else(|| missing_info_key("length"))?,
+            md5sum: info.md5sum,
+            attributes: info.attributes,
+            sha1: info.sha1
         })
+    };
+
+    //lengths get added up into offsets further on, so the total has to fit. files of v2 only
+    //torrents each start on a piece boundary
+    let (lengths, length_key) = match mode_info {
+        FileMode::SingleFile(ref sf) => (vec![sf.length], if v1 { "length" } else { "file tree" }),
+        FileMode::MultiFile(ref mf) => (mf.files.iter().map(|x| x.length).collect(), if v1 { "files" } else { "file tree" })
+    };
+    let mut total_length = 0u64;
+    for length in lengths {
+        let padded = if v1 { Some(length) } else { pieces_in(length, piece_length).checked_mul(piece_length) };
+        total_length = match padded.and_then(|x| total_length.checked_add(x)) {
+            Some(total) => total,
+            None => return Err(MetadataError::OutOfRange(vec!["info".to_string(), length_key.to_string()]))
+        };
+    }
+
+    let pieces = if v1 { info.pieces.ok_or_else(|| missing_info_key("pieces"))? } else { vec![] };
+    if pieces.len() % 20 != 0 || (v1 && (pieces.len() / 20) as u64 != pieces_in(total_length, piece_length)) {
+        return Err(MetadataError::InvalidPieces(pieces.len()))
+    }
+
+    let info_hash = match info_hash_v2 {
+        Some(ref hash) if !v1 => truncate_info_hash(hash),
+        _ => sha1(&info_bytes)
+    };
+
+    Ok(Metadata {
+        announce: announce,
+        announce_list: announce_list,
+        url_list: url_list.into_iter().filter(|x| !x.is_empty()).collect(),
+        http_seeds: http_seeds,
+        info_hash: info_hash,
+        info_hash_v2: info_hash_v2,
+        piece_length: piece_length,
+        pieces: pieces,
+        name: name,
+        mode_info: mode_info,
+        file_tree: file_tree,
+        piece_layers: piece_layers,
+        info_bytes: info_bytes
+    })
+}
+
+//a file or directory name that stays where it's put
+fn is_valid_component (name: &str) -> bool {
+    !(name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\\') || name.contains('\0'))
+}
+
+//checks each component of a path found at key path at, an absolute path having an empty first one
+fn check_path (path: &[String], mut at: Vec<String>) -> Result<(), MetadataError> {
+    match path.iter().position(|x| !is_valid_component(x)) {
+        Some(i) => {
+            at.push(i.to_string());
+            Err(MetadataError::InvalidPath(at))
+        },
+        None if path.is_empty() => Err(MetadataError::InvalidPath(at)),
+        None => Ok(())
+    }
+}
+
+//key path to a value in the file tree
+fn tree_path (path: &[String], keys: &[&str]) -> Vec<String> {
+    let mut full = vec!["info".to_string(), "file tree".to_string()];
+    full.extend(path.iter().cloned());
+    full.extend(keys.iter().map(|x| x.to_string()));
+    full
+}
+
+/// Flattens a v2 file tree into its files, in path order. Directories map names to what they
+/// hold, files are a dictionary under the empty name
+fn read_file_tree (tree: &BTreeMap<Vec<u8>, Bencode>, path: &mut Vec<String>, files: &mut Vec<TreeFile>) -> Result<(), MetadataError> {
+    for (key, value) in tree.iter() {
+        let dict = match *value {
+            Bencode::Dict(ref dict) => dict,
+            _ => return Err(MetadataError::WrongType("dict", tree_path(path, &[&String::from_utf8_lossy(key)])))
+        };
+        if !key.is_empty() {
+            let name = String::from_utf8(key.clone()).map_err(|_| MetadataError::InvalidUtf8(tree_path(path, &[])))?;
+            if !is_valid_component(&name) {
+                return Err(MetadataError::InvalidPath(tree_path(path, &[&name])))
+            }
+            path.push(name);
+            read_file_tree(dict, path, files)?;
+            path.pop();
+            continue
+        }
+
+        //a file needs a name, so one can't sit right at the top
+        if path.is_empty() {
+            return Err(MetadataError::WrongType("directory", tree_path(path, &[])))
+        }
+        let at_file = |e: FromBencodeError| MetadataError::from(path.iter().rev().fold(e.at(""), |e, x| e.at(x)).at("file tree").at("info"));
+        let length = from_dict_entry(dict, "length").map_err(&at_file)?;
+        let attributes = from_dict_entry(dict, "attr").map_err(&at_file)?;
+        let symlink_path: Option<Vec<String>> = from_dict_entry(dict, "symlink path").map_err(&at_file)?;
+        if let Some(ref target) = symlink_path {
+            check_path(target, tree_path(path, &["", "symlink path"]))?;
+        }
+        let pieces_root = match from_dict_entry::<Option<Vec<u8>>>(dict, "pieces root") {
+            Ok(Some(ref root)) if root.len() == 32 => Some(to_hash(root)),
+            Ok(None) if length == 0 => None,
+            _ => return Err(MetadataError::InvalidHashTree(tree_path(path, &["", "pieces root"])))
+        };
+        files.push(TreeFile {
+            path: path.clone(),
+            length: length,
+            pieces_root: pieces_root,
+            attributes: attributes,
+            symlink_path: symlink_path
+        });
+    }
+    Ok(())
+}
+
+/// Reads the piece layers of the files longer than a piece, checking each against its pieces
+/// root. Layers of other files are ignored
+fn read_piece_layers (layers: &BTreeMap<Vec<u8>, Bencode>, files: &[TreeFile], piece_length: u64) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, MetadataError> {
+    let pad = merkle::pad_hash(piece_length as usize / BLOCK_SIZE);
+    let mut checked = BTreeMap::new();
+    for file in files.iter().filter(|file| file.length > piece_length) {
+        let root = match file.pieces_root {
+            Some(ref root) => root,
+            None => continue
+        };
+        let invalid = || MetadataError::InvalidHashTree(vec!["piece layers".to_string(), to_hex(root)]);
+        let layer = match layers.get(&root[..]) {
+            Some(&Bencode::ByteString(ref layer)) => layer,
+            Some(_) => return Err(invalid()),
+            None => continue
+        };
+        let count = pieces_in(file.length, piece_length) as usize;
+        if layer.len() != count * 32 {
+            return Err(invalid())
+        }
+        let hashes = layer.chunks(32).map(to_hash).collect::<Vec<[u8; 32]>>();
+        if merkle::root(&hashes, count.next_power_of_two(), pad) != *root {
+            return Err(invalid())
+        }
+        checked.insert(root.to_vec(), layer.clone());
+    }
+    Ok(checked)
+}
+
+fn to_hex (bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+const MIN_PIECE_LENGTH: u64 = 1 << 14;
+const MAX_PIECE_LENGTH: u64 = 1 << 24;
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// Picks a piece length for a torrent of total bytes: the smallest power of two from 16KiB up to
+/// 16MiB that keeps the piece count around 1500 or below
+pub fn auto_piece_length (total: u64) -> u64 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while total / piece_length > TARGET_PIECE_COUNT && piece_length < MAX_PIECE_LENGTH {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+/// Why a torrent couldn't be created
+#[derive(Debug)]
+pub enum BuildError {
+    Io(io::Error),
+    Encode(EncodeError),
+    /// There are no files to put in the torrent
+    Empty(PathBuf),
+    /// Paths in a torrent are text, this one isn't
+    NonUtf8Path(PathBuf),
+    InvalidPieceLength(u64),
+    /// A file's size changed between listing and hashing it
+    SizeChanged(PathBuf)
+}
+
+impl fmt::Display for BuildError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Io(ref e) => write!(f, "{}", e),
+            BuildError::Encode(ref e) => write!(f, "{}", e),
+            BuildError::Empty(ref path) => write!(f, "no files found in {}", path.display()),
+            BuildError::NonUtf8Path(ref path) => write!(f, "path {} is not valid utf-8", path.display()),
+            BuildError::InvalidPieceLength(len) => write!(f, "invalid piece length {}", len),
+            BuildError::SizeChanged(ref path) => write!(f, "{} changed size while being hashed", path.display())
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+impl From<io::Error> for BuildError {
+    fn from (e: io::Error) -> BuildError {
+        BuildError::Io(e)
+    }
+}
+
+impl From<EncodeError> for BuildError {
+    fn from (e: EncodeError) -> BuildError {
+        BuildError::Encode(e)
+    }
+}
+
+/// Creates a .torrent for a file or a directory. Directories are walked recursively and their
+/// files added in path order. Symlinks inside a directory are left out
+///
+/// ```no_run
+/// use bittorrent::metadata::TorrentBuilder;
+///
+/// let torrent = TorrentBuilder::new("release/")
+///     .announce("http://tracker.example.com/announce")
+///     .comment("nightly")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    piece_length: Option<u64>,
+    url_list: Vec<String>
+}
+
+impl TorrentBuilder {
+    pub fn new <P: AsRef<Path>> (path: P) -> TorrentBuilder {
+        TorrentBuilder {
+            path: path.as_ref().to_path_buf(),
+            announce: None,
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+            piece_length: None,
+            url_list: vec![]
+        }
+    }
+
+    pub fn announce (mut self, url: &str) -> TorrentBuilder {
+        self.announce = Some(url.to_string());
+        self
+    }
+
+    /// Tiers of trackers as in BEP 12. Without an explicit announce the first tracker is used
+    pub fn announce_list (mut self, tiers: Vec<Vec<String>>) -> TorrentBuilder {
+        self.announce_list = tiers;
+        self
+    }
+
+    pub fn comment (mut self, comment: &str) -> TorrentBuilder {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by (mut self, created_by: &str) -> TorrentBuilder {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    /// Creation time in seconds since the unix epoch
+    pub fn creation_date (mut self, timestamp: i64) -> TorrentBuilder {
+        self.creation_date = Some(timestamp);
+        self
+    }
+
+    pub fn private (mut self, private: bool) -> TorrentBuilder {
+        self.private = private;
+        self
+    }
+
+    /// Web seeds hosting the files (BEP 19)
+    pub fn url_list (mut self, urls: Vec<String>) -> TorrentBuilder {
+        self.url_list = urls;
+        self
+    }
+
+    /// Sets the piece length instead of picking one with auto_piece_length. It has to be a power
+    /// of two from 16KiB to 16MiB
+    pub fn piece_length (mut self, piece_length: u64) -> TorrentBuilder {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Reads and hashes the files, returning the bencoded torrent
+    pub fn build (&self) -> Result<Vec<u8>, BuildError> {
+        self.write(Vec::new())
+    }
+
+    /// Reads and hashes the files, writing the bencoded torrent out as it goes
+    pub fn write <W: Write> (&self, writer: W) -> Result<W, BuildError> {
+        //paths like "." have no name of their own
+        let name = match self.path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => fs::canonicalize(&self.path)?.file_name().map(|x| x.to_os_string()).unwrap_or_default()
+        };
+        let name = name.into_string().map_err(|_| BuildError::NonUtf8Path(self.path.clone()))?;
+        let multi_file = fs::metadata(&self.path)?.is_dir();
+        let mut files = vec![];
+        if multi_file {
+            walk(&self.path, &mut vec![], &mut files)?;
+        } else {
+            files.push((vec![], fs::metadata(&self.path)?.len()));
+        }
+        if files.is_empty() {
+            return Err(BuildError::Empty(self.path.clone()))
+        }
+
+        let total = files.iter().fold(0, |a, &(_, len)| a + len);
+        let piece_length = match self.piece_length {
+            Some(len) if len < MIN_PIECE_LENGTH || len > MAX_PIECE_LENGTH || !len.is_power_of_two() => return Err(BuildError::InvalidPieceLength(len)),
+            Some(len) => len,
+            None => auto_piece_length(total)
+        };
+        let pieces = self.hash_pieces(&files, piece_length)?;
+
+        let announce = self.announce.clone()
+            .or_else(|| self.announce_list.iter().flat_map(|tier| tier.first()).next().cloned());
+
+        //keys go out in sorted order, the encoder checks
+        let mut enc = Encoder::new(writer);
+        enc.begin_dict()?;
+        if let Some(ref announce) = announce {
+            enc.write_bytes(b"announce")?;
+            enc.write_bytes(announce.as_bytes())?;
+        }
+        if !self.announce_list.is_empty() {
+            enc.write_bytes(b"announce-list")?;
+            enc.begin_list()?;
+            for tier in self.announce_list.iter() {
+                enc.begin_list()?;
+                for url in tier.iter() {
+                    enc.write_bytes(url.as_bytes())?;
+                }
+                enc.end()?;
+            }
+            enc.end()?;
+        }
+        if let Some(ref comment) = self.comment {
+            enc.write_bytes(b"comment")?;
+            enc.write_bytes(comment.as_bytes())?;
+        }
+        if let Some(ref created_by) = self.created_by {
+            enc.write_bytes(b"created by")?;
+            enc.write_bytes(created_by.as_bytes())?;
+        }
+        if let Some(date) = self.creation_date {
+            enc.write_bytes(b"creation date")?;
+            enc.write_int(date)?;
+        }
+
+        enc.write_bytes(b"info")?;
+        enc.begin_dict()?;
+        if multi_file {
+            enc.write_bytes(b"files")?;
+            enc.begin_list()?;
+            for &(ref path, length) in files.iter() {
+                enc.begin_dict()?;
+                enc.write_bytes(b"length")?;
+                enc.write_int(length as i64)?;
+                enc.write_bytes(b"path")?;
+                enc.begin_list()?;
+                for component in path.iter() {
+                    enc.write_bytes(component.as_bytes())?;
+                }
+                enc.end()?;
+                enc.end()?;
+            }
+            enc.end()?;
+        } else {
+            enc.write_bytes(b"length")?;
+            enc.write_int(total as i64)?;
+        }
+        enc.write_bytes(b"name")?;
+        enc.write_bytes(name.as_bytes())?;
+        enc.write_bytes(b"piece length")?;
+        enc.write_int(piece_length as i64)?;
+        enc.write_bytes(b"pieces")?;
+        enc.write_bytes(&pieces)?;
+        if self.private {
+            enc.write_bytes(b"private")?;
+            enc.write_int(1)?;
+        }
+        enc.end()?;
+        if !self.url_list.is_empty() {
+            enc.write_bytes(b"url-list")?;
+            enc.begin_list()?;
+            for url in self.url_list.iter() {
+                enc.write_bytes(url.as_bytes())?;
+            }
+            enc.end()?;
+        }
+        enc.end()?;
+        Ok(enc.finish()?)
+    }
+
+    /// Hashes the files as one stream, so pieces run across file boundaries. Each file has to
+    /// be exactly as long as when it was listed, or the lengths in the torrent would be wrong
+    fn hash_pieces (&self, files: &[(Vec<String>, u64)], piece_length: u64) -> Result<Vec<u8>, BuildError> {
+        let mut pieces = vec![];
+        let mut buffer = vec![0; piece_length as usize];
+        let mut filled = 0;
+        for &(ref path, length) in files.iter() {
+            let full_path = path.iter().fold(self.path.clone(), |a, b| a.join(b));
+            let mut file = File::open(&full_path)?;
+            let mut remaining = length;
+            loop {
+                let read = (&mut file).take(remaining).read(&mut buffer[filled..])?;
+                if read == 0 {
+                    break
+                }
+                remaining -= read as u64;
+                filled += read;
+                if filled == buffer.len() {
+                    pieces.extend(sha1(&buffer).iter());
+                    filled = 0;
+                }
+            }
+            if remaining > 0 || file.read(&mut [0])? > 0 {
+                return Err(BuildError::SizeChanged(full_path))
+            }
+        }
+        if filled > 0 {
+            pieces.extend(sha1(&buffer[..filled]).iter());
+        }
+        Ok(pieces)
+    }
+}
+
+/// Collects the files under dir with their path components relative to the top directory.
+/// Symlinks aren't followed, a link back up the tree would have us walking forever, and
+/// anything that is neither a file nor a directory, like a FIFO, is left out
+fn walk (dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<(Vec<String>, u64)>) -> Result<(), BuildError> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<fs::DirEntry>, io::Error>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if !metadata.is_file() && !metadata.is_dir() {
+            continue
+        }
+        let name = entry.file_name().into_string().map_err(|_| BuildError::NonUtf8Path(entry.path()))?;
+        prefix.push(name);
+        if metadata.is_dir() {
+            walk(&entry.path(), prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len()));
+        }
+        prefix.pop();
     }
+    Ok(())
 }
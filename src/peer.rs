@@ -1,7 +1,7 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
-use std::net::{TcpStream, SocketAddrV4};
+use std::net::TcpStream;
 use rand::{Rng, thread_rng};
-use metadata::Metadata;
 use buffered_reader::BufferedReader;
 use bt_messages::Message;
 use tracker::{Address, PEER_ID_LENGTH};
@@ -46,7 +46,11 @@ pub struct State {
     //the intention is that eventually we will support growable files. so going with vector
     pub bitfield: Vec<u8>,
 
-    pub pieces: Vec<Piece>
+    pub pieces: Vec<Piece>,
+    //extension names to the message ids the peer wants them sent with, from its extended handshake
+    pub extensions: BTreeMap<String, u8>,
+    //the size of the info dict the peer says it has, if it offers one
+    pub metadata_size: Option<u64>
 }
 
 impl State {
@@ -57,7 +61,9 @@ impl State {
             is_choked: true,
             is_interested: false,
             bitfield: vec![],
-            pieces: vec![]
+            pieces: vec![],
+            extensions: BTreeMap::new(),
+            metadata_size: None
         }
     }
 
@@ -122,37 +128,75 @@ pub fn decode_handshake(resp: &[u8]) -> (&[u8], &[u8], &[u8], &[u8], &[u8]){
     (protocol, reserved, info_hash, peer_id, remainder)
 }
 
+/// What we learned from a peer's handshake
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub peer_id: Vec<u8>
+}
+
+impl Handshake {
+    /// Whether the peer speaks the extension protocol (BEP 10)
+    pub fn supports_extensions (&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
+    }
+}
+
+const PROTOCOL: &'static str = "BitTorrent protocol";
+//the reserved bit advertising the extension protocol
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+
 //this seems overly verbose (the signature)
-pub fn connect_to_peer (address: Address, metadata: &Metadata, peer_id: &String) -> Result<(Vec<u8>, BufferedReader<TcpStream>), String> {
+pub fn connect_to_peer (address: Address, info_hash: &[u8; 20], peer_id: &String) -> Result<(Handshake, BufferedReader<TcpStream>), String> {
     println!("connecting to {:?}", address);
-    let (ip, port) = match address {
-        Address::TCP(ip_address, port) => (ip_address, port)
-    };
-
-    let mut stream = match TcpStream::connect(SocketAddrV4::new(ip, port)) {
+    let mut stream = match TcpStream::connect(address.socket_addr()) {
         Ok(tcp_stream) => tcp_stream,
-        Err(_) => return Err(format!("unable to connect to peer {:?}", ip))
+        Err(_) => return Err(format!("unable to connect to peer {:?}", address))
     };
 
     println!("connected to {:?}", address);
 
-    let _ = stream.write_all(&to_handshake("BitTorrent protocol", &metadata.info_hash, peer_id));
+    let _ = stream.write_all(&to_handshake(PROTOCOL, info_hash, peer_id));
     let _ = stream.flush();
 
-    //for now enforce a maximum handshake size of 512 bytes
-    let mut buffer = [0; 512];
-    match stream.read(&mut buffer) {
-        Ok(0) => Err(format!("invalid handshake from peer")),
-        Ok(bytes_read) => {
-            let (protocol, _, info_hash, peer_id, rest) = decode_handshake(&buffer[0..bytes_read]);
-            match (protocol, info_hash) {
-                (b"BitTorrent protocol", i_h) if i_h == metadata.info_hash => {
-                    Ok((peer_id.to_owned(), BufferedReader::new(stream, rest.to_vec())))
-                },
-                _ => Err(format!("invalid peer handshake"))
-            }
+    let handshake = read_handshake(&mut stream, info_hash)?;
+    Ok((handshake, BufferedReader::new(stream, vec![])))
+}
+
+/// Answers a connection a peer made to us, for a torrent with the given info hash
+pub fn accept_peer (mut stream: TcpStream, info_hash: &[u8; 20], peer_id: &String) -> Result<(Handshake, BufferedReader<TcpStream>), String> {
+    let handshake = read_handshake(&mut stream, info_hash)?;
+
+    let _ = stream.write_all(&to_handshake(PROTOCOL, info_hash, peer_id));
+    let _ = stream.flush();
+
+    Ok((handshake, BufferedReader::new(stream, vec![])))
+}
+
+/// Reads exactly one handshake, so nothing that follows it is consumed
+fn read_handshake <R: Read> (stream: &mut R, expected_info_hash: &[u8; 20]) -> Result<Handshake, String> {
+    let mut pstrlen = [0; 1];
+    if stream.read_exact(&mut pstrlen).is_err() {
+        return Err(format!("invalid handshake from peer"))
+    }
+    let mut buffer = vec![0; pstrlen[0] as usize + 48];
+    if stream.read_exact(&mut buffer).is_err() {
+        return Err(format!("invalid handshake from peer"))
+    }
+    buffer.insert(0, pstrlen[0]);
+
+    let (protocol, reserved, info_hash, peer_id, _) = decode_handshake(&buffer);
+    match (protocol, info_hash) {
+        (b"BitTorrent protocol", i_h) if i_h == expected_info_hash => {
+            let mut reserved_bits = [0; 8];
+            reserved_bits.copy_from_slice(reserved);
+            Ok(Handshake {
+                reserved: reserved_bits,
+                peer_id: peer_id.to_owned()
+            })
         },
-        Err(_) => Err(format!("unable to read from peer {:?}", ip))
+        _ => Err(format!("invalid peer handshake"))
     }
 }
 
@@ -160,7 +204,8 @@ pub fn connect_to_peer (address: Address, metadata: &Metadata, peer_id: &String)
 /// The peer handshake message, according to protocol
 ///
 fn to_handshake (pstr:&str, info_hash: &[u8; 20], peer_id: &String) -> Vec<u8> {
-    let reserved = [0u8; 8];
+    let mut reserved = [0u8; 8];
+    reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
     let pstr_bytes = pstr.to_string().into_bytes();
     let a = [pstr_bytes.len() as u8];
     let b = pstr_bytes;
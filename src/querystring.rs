@@ -35,4 +35,35 @@ impl <'a> QueryString<'a> {
         percent_encode(component, DEFAULT_ENCODE_SET)
     }
 
+    /// Undoes percent encoding, and + standing in for a space. None if an escape isn't a % and two
+    /// hex digits
+    pub fn decode_component (component: &str) -> Option<Vec<u8>> {
+        let bytes = component.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'%' => {
+                    //exactly two hex digits, from_str_radix would take a sign too
+                    let hex = bytes.get(i+1..i+3)?;
+                    if !hex.iter().all(|x| x.is_ascii_hexdigit()) {
+                        return None
+                    }
+                    let digit = |x: u8| (x as char).to_digit(16).unwrap() as u8;
+                    decoded.push(digit(hex[0]) << 4 | digit(hex[1]));
+                    i += 3;
+                },
+                b'+' => {
+                    decoded.push(b' ');
+                    i += 1;
+                },
+                byte => {
+                    decoded.push(byte);
+                    i += 1;
+                }
+            }
+        }
+        Some(decoded)
+    }
+
 }
@@ -0,0 +1,57 @@
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use metadata::{Metadata, TorrentFile};
+
+/// Contains functionality to put verified pieces into the files they belong to under a download
+/// directory. Files are created as their first piece comes in and finished off, with symlinks put
+/// in place and executable bits set, once all of their pieces are there
+
+/// Writes piece number index into the parts of the files it covers under dir. Pad files are left
+/// out, as are symlinks
+pub fn write_piece (metadata: &Metadata, dir: &Path, index: usize, data: &[u8]) -> io::Result<()> {
+    let start = index as u64 * metadata.piece_length;
+    let end = start + data.len() as u64;
+    for file in metadata.files() {
+        if file.symlink_path.is_some() || file.offset + file.length <= start || file.offset >= end {
+            continue
+        }
+        let path = file.local_path(dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+        let (from, to) = (cmp::max(start, file.offset), cmp::min(end, file.offset + file.length));
+        out.seek(SeekFrom::Start(from - file.offset))?;
+        out.write_all(&data[(from - start) as usize..(to - start) as usize])?;
+    }
+    Ok(())
+}
+
+/// The files that are complete once piece number index is, owns telling which other pieces are
+/// there. Empty files and symlinks have no pieces and come with the last one of the torrent
+pub fn completed_files <F> (metadata: &Metadata, index: usize, owns: F) -> Vec<TorrentFile> where F: Fn(usize) -> bool {
+    let piece_length = metadata.piece_length;
+    let all = (0..metadata.piece_count()).all(&owns);
+    metadata.files().into_iter().filter(|file| {
+        if file.length == 0 {
+            return all
+        }
+        let first = (file.offset / piece_length) as usize;
+        let last = ((file.offset + file.length - 1) / piece_length) as usize;
+        first <= index && index <= last && (first..last + 1).all(&owns)
+    }).collect()
+}
+
+/// Puts a complete file in its final shape: created if it's empty, then given its attributes
+pub fn finish_file (file: &TorrentFile, dir: &Path) -> io::Result<()> {
+    if file.length == 0 && file.symlink_path.is_none() {
+        let path = file.local_path(dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&path)?;
+    }
+    file.apply_attributes(dir)
+}
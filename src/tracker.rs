@@ -1,68 +1,556 @@
-use std::net::{Ipv4Addr};
-use std::collections::HashMap;
-use std::io::Read;
+extern crate time;
+
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::collections::BTreeMap;
+use std::time::Duration;
 use hyper::Client;
 use hyper::header::Connection;
-use bencode::{deserialize, Bencode, BencodeVecOption, TypedMethods};
+use rand::{Rng, thread_rng};
+use bencode::{read_value, Bencode, FromBencode};
 use metadata::{Metadata};
+use magnet::MagnetLink;
 use querystring::QueryString;
+use udp_tracker::{UdpTracker, UdpTrackerError, BASE_TIMEOUT, MAX_SCRAPE_HASHES};
 
 /// Contains functionality required to connect and parse tracker responses
 
 pub const PEER_ID_LENGTH:usize = 20;
 pub const PEER_ID_PREFIX:&'static str = "-TR1000-";
+/// What to report as left while the size of the torrent is unknown, as with magnet links. Anything
+/// above zero tells the tracker we aren't a seed
+pub const UNKNOWN_LEFT:u64 = 16384;
+/// How long to wait between announces when the tracker doesn't say, in seconds
+pub const DEFAULT_INTERVAL:u64 = 1800;
+/// Retransmissions to a UDP tracker before moving on to the next in the list, which comes to 105
+/// seconds instead of the couple of hours BEP 15 allows for
+pub const UDP_MAX_RETRIES:u32 = 2;
 
 //Address doesn't exactly belong here
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Address {
-    TCP(Ipv4Addr, u16)
+    TCP(Ipv4Addr, u16),
+    TCP6(Ipv6Addr, u16)
+}
+
+impl Address {
+    pub fn socket_addr (&self) -> SocketAddr {
+        match *self {
+            Address::TCP(ip, port) => SocketAddr::new(IpAddr::V4(ip), port),
+            Address::TCP6(ip, port) => SocketAddr::new(IpAddr::V6(ip), port)
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from (addr: SocketAddr) -> Address {
+        match addr {
+            SocketAddr::V4(v4) => Address::TCP(*v4.ip(), v4.port()),
+            SocketAddr::V6(v6) => Address::TCP6(*v6.ip(), v6.port())
+        }
+    }
+}
+
+/// The trackers of a torrent in tiers, used as described in BEP 12: each tier is shuffled once,
+/// then trackers are tried in order, tier by tier, and one that answers is moved to the front of
+/// its tier so it's tried first next time
+#[derive(Clone, Debug)]
+pub struct TrackerList {
+    tiers: Vec<Vec<String>>
+}
+
+impl TrackerList {
+    pub fn new (mut tiers: Vec<Vec<String>>) -> TrackerList {
+        let mut rng = thread_rng();
+        for tier in tiers.iter_mut() {
+            rng.shuffle(tier);
+        }
+        TrackerList::in_order(tiers)
+    }
+
+    /// Takes the tiers as they are, without shuffling
+    pub fn in_order (tiers: Vec<Vec<String>>) -> TrackerList {
+        TrackerList {
+            tiers: tiers.into_iter().filter(|tier| !tier.is_empty()).collect()
+        }
+    }
+
+    pub fn from_metadata (metadata: &Metadata) -> TrackerList {
+        TrackerList::new(metadata.trackers())
+    }
+
+    /// Magnet links have no tiers, each tracker gets its own so they're tried in the given order
+    pub fn from_magnet (magnet: &MagnetLink) -> TrackerList {
+        TrackerList::in_order(magnet.trackers.iter().map(|x| vec![x.clone()]).collect())
+    }
+
+    pub fn tiers (&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Calls announce with each tracker in turn until one returns Some
+    pub fn try_each <T, F> (&mut self, mut announce: F) -> Option<T> where F: FnMut(&str) -> Option<T> {
+        for tier in self.tiers.iter_mut() {
+            for i in 0..tier.len() {
+                if let Some(result) = announce(&tier[i]) {
+                    let working = tier.remove(i);
+                    tier.insert(0, working);
+                    return Some(result)
+                }
+            }
+        }
+        None
+    }
+}
+
+/// What an announce tells the tracker about the state of the download
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// A regular announce at the interval the tracker asked for
+    None,
+    Completed,
+    Started,
+    Stopped
+}
+
+impl Event {
+    /// The value of the event parameter of HTTP announces, which is left out for regular ones
+    pub fn name (&self) -> Option<&'static str> {
+        match *self {
+            Event::None => None,
+            Event::Completed => Some("completed"),
+            Event::Started => Some("started"),
+            Event::Stopped => Some("stopped")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: String,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    /// Our addresses for the tracker to hand out besides the one the request comes from (BEP 7).
+    /// Only HTTP trackers take these
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>
+}
+
+/// A peer from an announce. Only peers listed as dictionaries come with their id
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerPeer {
+    pub address: Address,
+    pub peer_id: Option<Vec<u8>>
+}
+
+#[derive(Debug, Default)]
+pub struct AnnounceResponse {
+    /// Seconds to wait before announcing again
+    pub interval: u64,
+    /// Seconds to wait at the least before announcing out of turn
+    pub min_interval: Option<u64>,
+    /// Seeders in the swarm
+    pub complete: Option<u32>,
+    /// Leechers in the swarm
+    pub incomplete: Option<u32>,
+    /// To be sent back with later announces
+    pub tracker_id: Option<String>,
+    pub warnings: Vec<String>,
+    pub peers: Vec<TrackerPeer>
+}
+
+bencode_struct! {
+    //an HTTP announce response as it comes in. trackers send negative counts and text that isn't
+    //UTF-8, so those are cleaned up on the way to AnnounceResponse rather than rejected
+    struct AnnounceDict {
+        "failure reason" => failure_reason: Option<Vec<u8>>,
+        "interval" => interval: Option<i64>,
+        "min interval" => min_interval: Option<i64>,
+        "complete" => complete: Option<i64>,
+        "incomplete" => incomplete: Option<i64>,
+        "tracker id" => tracker_id: Option<Vec<u8>>,
+        "warning message" => warning_message: Option<Vec<u8>>,
+        //compact or a list of PeerDicts
+        "peers" => peers: Option<Bencode>,
+        "peers6" => peers6: Option<Vec<u8>>
+    }
+}
+
+bencode_struct! {
+    //a peer of a non-compact announce response
+    struct PeerDict {
+        "ip" => ip: Vec<u8>,
+        "peer id" => peer_id: Option<Vec<u8>>,
+        "port" => port: u16
+    }
+}
+
+impl AnnounceResponse {
+    /// Reads the bencoded response of an HTTP tracker. A failure reason comes back as
+    /// TrackerError::Failure, peers may be in compact form or a list of dictionaries
+    pub fn from_dict (dict: &BTreeMap<Vec<u8>, Bencode>) -> Result<AnnounceResponse, TrackerError> {
+        let response = AnnounceDict::from_bencode(&Bencode::Dict(dict.clone())).map_err(|_| TrackerError::InvalidResponse)?;
+        if let Some(ref reason) = response.failure_reason {
+            return Err(TrackerError::Failure(String::from_utf8_lossy(reason).into_owned()))
+        }
+        let count = |x: Option<i64>| x.map(|x| x.max(0) as u32);
+        let text = |x: &Option<Vec<u8>>| x.as_ref().map(|x| String::from_utf8_lossy(x).into_owned());
+        Ok(AnnounceResponse {
+            interval: response.interval.map_or(DEFAULT_INTERVAL, |x| x.max(0) as u64),
+            min_interval: response.min_interval.map(|x| x.max(0) as u64),
+            complete: count(response.complete),
+            incomplete: count(response.incomplete),
+            tracker_id: text(&response.tracker_id),
+            warnings: text(&response.warning_message).into_iter().collect(),
+            peers: get_peers(&response)?
+        })
+    }
+
+    pub fn addresses (&self) -> Vec<Address> {
+        self.peers.iter().map(|x| x.address.clone()).collect()
+    }
+}
+
+/// Swarm sizes of a torrent as a tracker sees them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    /// Times the torrent has been downloaded in full
+    pub completed: u32,
+    pub leechers: u32
+}
+
+bencode_struct! {
+    //an HTTP scrape response as it comes in, files mapping info hashes to ScrapeFileDicts
+    struct ScrapeDict {
+        "files" => files: BTreeMap<Vec<u8>, Bencode>,
+        "flags" => flags: Option<ScrapeFlags>
+    }
+}
+
+bencode_struct! {
+    struct ScrapeFlags {
+        "min_request_interval" => min_request_interval: Option<i64>
+    }
+}
+
+bencode_struct! {
+    struct ScrapeFileDict {
+        "complete" => complete: Option<i64>,
+        "downloaded" => downloaded: Option<i64>,
+        "incomplete" => incomplete: Option<i64>
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrapeResponse {
+    /// Torrents the tracker doesn't know are left out
+    pub files: BTreeMap<[u8; 20], ScrapeStats>,
+    /// Seconds to wait before scraping again
+    pub min_request_interval: Option<u64>
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    Http(String),
+    Udp(UdpTrackerError),
+    /// The response couldn't be decoded or lacks peers
+    InvalidResponse,
+    /// The tracker refused the request, saying why
+    Failure(String),
+    /// The tracker has no scrape url
+    ScrapeUnsupported,
+    /// The tracker asked not to be scraped again for this many seconds
+    ScrapeTooSoon(u64)
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackerError::Http(ref e) => write!(f, "{}", e),
+            TrackerError::Udp(ref e) => write!(f, "{}", e),
+            TrackerError::InvalidResponse => write!(f, "invalid response from tracker"),
+            TrackerError::Failure(ref reason) => write!(f, "tracker failure: {}", reason),
+            TrackerError::ScrapeUnsupported => write!(f, "tracker does not support scraping"),
+            TrackerError::ScrapeTooSoon(secs) => write!(f, "scrape again in {}s", secs)
+        }
+    }
+}
+
+impl Error for TrackerError {}
+
+impl From<UdpTrackerError> for TrackerError {
+    fn from (e: UdpTrackerError) -> TrackerError {
+        match e {
+            UdpTrackerError::Tracker(reason) => TrackerError::Failure(reason),
+            e => TrackerError::Udp(e)
+        }
+    }
+}
+
+/// A tracker reachable over some protocol
+pub trait Tracker {
+    fn url (&self) -> &str;
+    fn announce (&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError>;
+
+    /// Asks for the swarm sizes of the torrents without announcing
+    fn scrape (&mut self, _info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        Err(TrackerError::ScrapeUnsupported)
+    }
+}
+
+/// A tracker speaking bencoded responses over HTTP(S)
+#[derive(Debug, Clone)]
+pub struct HttpTracker {
+    url: String,
+    tracker_id: Option<String>,
+    //when the tracker last said it may be scraped again
+    next_scrape: i64
+}
+
+impl HttpTracker {
+    pub fn new (url: &str) -> HttpTracker {
+        HttpTracker {
+            url: url.to_string(),
+            tracker_id: None,
+            next_scrape: 0
+        }
+    }
+}
+
+/// Where an HTTP tracker takes scrapes, as in BEP 48: the last path component of the announce url
+/// has to start with announce, which is swapped for scrape. Trackers with other urls can't be
+/// scraped
+pub fn scrape_url (announce: &str) -> Option<String> {
+    let last = announce.rfind('/')? + 1;
+    if announce[last..].starts_with("announce") {
+        Some(format!("{}scrape{}", &announce[..last], &announce[last + "announce".len()..]))
+    } else {
+        None
+    }
 }
 
-pub fn get_http_tracker_peers (peer_id: &String, metadata: &Metadata, listen_port:u32, bytes_dled: u32) -> Option<Vec<Address>> {
-    let bytes_left = metadata.get_total_length() - bytes_dled;
+impl Tracker for HttpTracker {
+    fn url (&self) -> &str {
+        &self.url
+    }
+
+    fn announce (&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let mut args = vec![("info_hash", QueryString::encode_component(&request.info_hash)),
+                            ("peer_id", request.peer_id.clone()),
+                            ("port", request.port.to_string()),
+                            ("uploaded", request.uploaded.to_string()),
+                            ("downloaded", request.downloaded.to_string()),
+                            ("left", request.left.to_string()),
+                            ("compact", 1.to_string()),
+                            ("num_want", 15.to_string())];
+        if let Some(event) = request.event.name() {
+            args.push(("event", event.to_string()));
+        }
+        if let Some(ip) = request.ipv4 {
+            args.push(("ipv4", ip.to_string()));
+        }
+        if let Some(ip) = request.ipv6 {
+            args.push(("ipv6", ip.to_string()));
+        }
+        if let Some(ref tracker_id) = self.tracker_id {
+            args.push(("trackerid", QueryString::encode_component(tracker_id.as_bytes())));
+        }
+        let response = AnnounceResponse::from_dict(&ping_tracker(&self.url, args)?)?;
+        for warning in response.warnings.iter() {
+            println!("tracker {} warns: {}", self.url, warning);
+        }
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+        Ok(response)
+    }
 
-    let info_hash_escaped = QueryString::encode_component(&metadata.info_hash);
-    let response = ping_tracker(&metadata.announce, vec![
-                                ("info_hash", info_hash_escaped),
-                                ("peer_id", peer_id.clone()),
-                                ("port", listen_port.to_string()),
-                                ("uploaded", 0.to_string()),
-                                ("downloaded", bytes_dled.to_string()),
-                                ("left", bytes_left.to_string()),
-                                ("compact", 1.to_string()),
-                                ("event", "started".to_string()),
-                                ("num_want", 15.to_string())
-                                ]);
+    fn scrape (&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        let url = scrape_url(&self.url).ok_or(TrackerError::ScrapeUnsupported)?;
+        let now = time::get_time().sec;
+        if now < self.next_scrape {
+            return Err(TrackerError::ScrapeTooSoon((self.next_scrape - now) as u64))
+        }
+        //info_hash repeats, which QueryString can't do
+        let separator = if url.contains('?') { "&" } else { "?" };
+        let query = info_hashes.iter().map(|x| format!("info_hash={}", QueryString::encode_component(x))).collect::<Vec<String>>();
+        let response = fetch_dict(&(url + separator + &query.join("&")))?;
+        let response = ScrapeDict::from_bencode(&Bencode::Dict(response)).map_err(|_| TrackerError::InvalidResponse)?;
 
-    match response {
-        Some(resp) => Some(get_peers(&resp)),
-        None => panic!("no valid bencode response from tracker")
+        let min_request_interval = response.flags.and_then(|flags| flags.min_request_interval).map(|x| x.max(0) as u64);
+        if let Some(secs) = min_request_interval {
+            self.next_scrape = now + secs as i64;
+        }
+        let count = |x: Option<i64>| x.unwrap_or(0).max(0) as u32;
+        Ok(ScrapeResponse {
+            //entries that aren't for an info hash or aren't stats are skipped
+            files: response.files.iter().filter_map(|(hash, stats)| match ScrapeFileDict::from_bencode(stats) {
+                Ok(stats) if hash.len() == 20 => {
+                    let mut info_hash = [0; 20];
+                    info_hash.copy_from_slice(hash);
+                    Some((info_hash, ScrapeStats {
+                        seeders: count(stats.complete),
+                        completed: count(stats.downloaded),
+                        leechers: count(stats.incomplete)
+                    }))
+                },
+                _ => None
+            }).collect(),
+            min_request_interval: min_request_interval
+        })
     }
 }
 
-fn ping_tracker (announce: &String, args: Vec<(&str, String)>) -> Option<HashMap<String, Bencode>> {
-    let req_addr = announce.to_string() + "?" + &QueryString::from(args).query_string();
+impl Tracker for UdpTracker {
+    fn url (&self) -> &str {
+        UdpTracker::url(self)
+    }
+
+    fn announce (&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let response = UdpTracker::announce(self, request)?;
+        Ok(AnnounceResponse {
+            interval: response.interval as u64,
+            complete: Some(response.seeders),
+            incomplete: Some(response.leechers),
+            peers: response.peers.into_iter().map(|peer| TrackerPeer {address: Address::from(peer), peer_id: None}).collect(),
+            ..AnnounceResponse::default()
+        })
+    }
+
+    fn scrape (&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse, TrackerError> {
+        let mut files = BTreeMap::new();
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let stats = UdpTracker::scrape(self, chunk)?;
+            files.extend(chunk.iter().cloned().zip(stats));
+        }
+        Ok(ScrapeResponse {
+            files: files,
+            min_request_interval: None
+        })
+    }
+}
+
+/// The tracker at url, picked by its scheme. UDP trackers give up after UDP_MAX_RETRIES
+pub fn open_tracker (url: &str) -> Result<Box<dyn Tracker + Send>, TrackerError> {
+    if url.starts_with("udp://") {
+        let tracker = UdpTracker::new(url)?.with_timeouts(Duration::from_secs(BASE_TIMEOUT), UDP_MAX_RETRIES);
+        Ok(Box::new(tracker))
+    } else {
+        Ok(Box::new(HttpTracker::new(url)))
+    }
+}
+
+/// Announces to the trackers in the list until one gives back peers
+pub fn get_tracker_peers (trackers: &mut TrackerList, peer_id: &String, info_hash: &[u8; 20], listen_port: u32, bytes_dled: u64, bytes_left: u64) -> Option<Vec<Address>> {
+    trackers.try_each(|announce| {
+        match announce_started(announce, peer_id, info_hash, listen_port, bytes_dled, bytes_left) {
+            Ok(peers) => Some(peers),
+            Err(e) => {
+                println!("tracker {}: {}", announce, e);
+                None
+            }
+        }
+    })
+}
+
+/// Announces to the main tracker of the torrent only. Despite the name, udp:// trackers work too,
+/// the tracker is picked by scheme as with open_tracker
+pub fn get_http_tracker_peers (peer_id: &String, metadata: &Metadata, listen_port:u32, bytes_dled: u64) -> Result<Vec<Address>, TrackerError> {
+    announce_started(&metadata.announce, peer_id, &metadata.info_hash, listen_port, bytes_dled, metadata.bytes_left(bytes_dled))
+}
+
+fn announce_started (announce: &str, peer_id: &String, info_hash: &[u8; 20], listen_port:u32, bytes_dled: u64, bytes_left: u64) -> Result<Vec<Address>, TrackerError> {
+    let request = AnnounceRequest {
+        info_hash: *info_hash,
+        peer_id: peer_id.clone(),
+        port: listen_port as u16,
+        uploaded: 0,
+        downloaded: bytes_dled,
+        left: bytes_left,
+        event: Event::Started,
+        ipv4: None,
+        ipv6: None
+    };
+    let response = open_tracker(announce)?.announce(&request)?;
+    Ok(response.addresses())
+}
+
+fn ping_tracker (announce: &str, args: Vec<(&str, String)>) -> Result<BTreeMap<Vec<u8>, Bencode>, TrackerError> {
+    fetch_dict(&(announce.to_string() + "?" + &QueryString::from(args).query_string()))
+}
+
+fn fetch_dict (req_addr: &str) -> Result<BTreeMap<Vec<u8>, Bencode>, TrackerError> {
     println!("pinging tracker {}", req_addr);
     let client = Client::new();
-    let mut res = client.get(&req_addr)
-                        .header(Connection::close())
-                        .send().unwrap();
-    let mut body = Vec::new();
-    res.read_to_end(&mut body).unwrap();
-
-    deserialize(&body).to_singleton_dict()
-}
-
-/// Gets peer addresses from a received tracker response. These are just Ipv4 addresses currently
-fn get_peers <T> (tracker_response: &T) -> Vec<Address> where T:TypedMethods {
-    let peers = tracker_response.get_owned_string("peers").unwrap_or_else(||panic!("no peers found"));
-    //for now keep the bottom unused value, it's for ipv6. which maybe will be addressed
-    (0..peers.len()/6).map(|x| {
-        let ip_start = x * 6;
-        let ip_end = ip_start + 4;
-        let ip_bytes = &peers[ip_start..ip_end];
-        let ip = Ipv4Addr::new(ip_bytes[0], ip_bytes[1], ip_bytes[2], ip_bytes[3]);
-        let port = (peers[ip_end] as u16)*256 + peers[ip_end+1] as u16;
-        Address::TCP(ip, port)
-    }).collect::<Vec<Address>>()
+    let mut res = client.get(req_addr).header(Connection::close()).send()
+        .map_err(|e| TrackerError::Http(e.to_string()))?;
+
+    //decode straight off the response body
+    match read_value(&mut res) {
+        Ok((Bencode::Dict(dict), _)) => Ok(dict),
+        _ => Err(TrackerError::InvalidResponse)
+    }
+}
+
+/// Gets the peers from a tracker response: IPv4 ones under peers, either compact (6 bytes to a
+/// peer) or a list of dictionaries, and compact IPv6 ones (18 bytes to a peer) under peers6. A
+/// response without peers has none to give
+fn get_peers (tracker_response: &AnnounceDict) -> Result<Vec<TrackerPeer>, TrackerError> {
+    let mut peers = match tracker_response.peers {
+        None => vec![],
+        Some(Bencode::ByteString(ref peers)) => compact_peers(peers, 4),
+        Some(Bencode::List(ref peers)) => peers.iter().filter_map(|peer| {
+            let peer = PeerDict::from_bencode(peer).ok()?;
+            let ip = String::from_utf8_lossy(&peer.ip).parse::<IpAddr>().ok()?;
+            if peer.port == 0 {
+                return None
+            }
+            Some(TrackerPeer {address: Address::from(SocketAddr::new(ip, peer.port)), peer_id: peer.peer_id})
+        }).collect(),
+        Some(_) => return Err(TrackerError::InvalidResponse)
+    };
+    if let Some(ref peers6) = tracker_response.peers6 {
+        peers.extend(compact_peers(peers6, 16));
+    }
+    Ok(peers)
+}
+
+//compact peers are addresses of ip_length bytes followed by two port bytes
+fn compact_peers (peers: &[u8], ip_length: usize) -> Vec<TrackerPeer> {
+    peers.chunks(ip_length + 2).filter(|x| x.len() == ip_length + 2).map(|peer| {
+        let ip = if ip_length == 4 {
+            IpAddr::V4(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]))
+        } else {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&peer[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        };
+        let port = (peer[ip_length] as u16)*256 + peer[ip_length+1] as u16;
+        TrackerPeer {address: Address::from(SocketAddr::new(ip, port)), peer_id: None}
+    }).collect()
+}
+
+/// Our addresses as routing sees them, for the ipv4 and ipv6 announce parameters. Found by
+/// pointing UDP sockets at documentation addresses, which sends nothing. Loopback and link-local
+/// addresses are no use to a tracker and left out
+pub fn local_addresses () -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let local = |bind: &str, to: &str| UdpSocket::bind(bind).and_then(|socket| {
+        socket.connect(to)?;
+        socket.local_addr()
+    }).ok().map(|addr| addr.ip());
+    let ipv4 = match local("0.0.0.0:0", "192.0.2.1:80") {
+        Some(IpAddr::V4(ip)) if !ip.is_loopback() && !ip.is_unspecified() && !ip.is_link_local() => Some(ip),
+        _ => None
+    };
+    let ipv6 = match local("[::]:0", "[2001:db8::1]:80") {
+        Some(IpAddr::V6(ip)) if !ip.is_loopback() && !ip.is_unspecified() && ip.segments()[0] & 0xffc0 != 0xfe80 => Some(ip),
+        _ => None
+    };
+    (ipv4, ipv6)
 }
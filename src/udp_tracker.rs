@@ -0,0 +1,257 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+use rand::random;
+use tracker::{AnnounceRequest, Event, ScrapeStats};
+
+/// Contains functionality to talk to trackers over UDP (BEP 15). Every request needs a connection
+/// id from a connect exchange first, which stays good for a minute. Requests that go unanswered
+/// are sent again after 15 * 2^n seconds, n going from 0 up to 8
+
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+/// Scrapes are limited to what fits in one packet
+pub const MAX_SCRAPE_HASHES: usize = 74;
+/// Seconds to wait for the first answer, doubled on each retransmission
+pub const BASE_TIMEOUT: u64 = 15;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+const CONNECTION_ID_LIFETIME: u64 = 60; //seconds
+const MAX_RETRIES: u32 = 8;
+
+#[derive(Debug)]
+pub enum UdpTrackerError {
+    Io(io::Error),
+    /// Not a udp://host:port url, or the host doesn't resolve
+    InvalidUrl(String),
+    /// No answer after all retransmissions
+    Timeout,
+    /// The tracker answered with an error message
+    Tracker(String),
+    /// The answer is too short or for a different action
+    InvalidResponse
+}
+
+impl fmt::Display for UdpTrackerError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UdpTrackerError::Io(ref e) => write!(f, "{}", e),
+            UdpTrackerError::InvalidUrl(ref url) => write!(f, "invalid udp tracker url {}", url),
+            UdpTrackerError::Timeout => write!(f, "tracker did not respond"),
+            UdpTrackerError::Tracker(ref message) => write!(f, "tracker error: {}", message),
+            UdpTrackerError::InvalidResponse => write!(f, "invalid response from tracker")
+        }
+    }
+}
+
+impl Error for UdpTrackerError {}
+
+impl From<io::Error> for UdpTrackerError {
+    fn from (e: io::Error) -> UdpTrackerError {
+        UdpTrackerError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpAnnounce {
+    /// Seconds to wait before announcing again
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    /// IPv4 peers from trackers reached over IPv4, IPv6 ones from trackers reached over IPv6
+    pub peers: Vec<SocketAddr>
+}
+
+/// A UDP tracker, holding on to the connection id between requests
+#[derive(Debug)]
+pub struct UdpTracker {
+    url: String,
+    socket: UdpSocket,
+    addr: SocketAddr,
+    connection: Option<(u64, Instant)>,
+    //sent with every announce so the tracker knows us if our address changes
+    key: u32,
+    base_timeout: Duration,
+    max_retries: u32
+}
+
+impl UdpTracker {
+    /// Resolves the tracker of a udp://host:port/... url and opens a socket to talk to it
+    pub fn new (url: &str) -> Result<UdpTracker, UdpTrackerError> {
+        let invalid = || UdpTrackerError::InvalidUrl(url.to_string());
+        if !url.starts_with("udp://") {
+            return Err(invalid())
+        }
+        let rest = &url["udp://".len()..];
+        let host_port = match rest.find('/') {
+            Some(i) => &rest[..i],
+            None => rest
+        };
+        let addr = host_port.to_socket_addrs().map_err(|_| invalid())?.next().ok_or_else(invalid)?;
+        let mut tracker = UdpTracker::from_addr(addr)?;
+        tracker.url = url.to_string();
+        Ok(tracker)
+    }
+
+    pub fn from_addr (addr: SocketAddr) -> Result<UdpTracker, UdpTrackerError> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), 0)
+        };
+        Ok(UdpTracker {
+            url: format!("udp://{}", addr),
+            socket: UdpSocket::bind(local)?,
+            addr: addr,
+            connection: None,
+            key: random::<u32>(),
+            base_timeout: Duration::from_secs(BASE_TIMEOUT),
+            max_retries: MAX_RETRIES
+        })
+    }
+
+    /// Changes the retransmission schedule from 15 * 2^n seconds, n up to 8, to base * 2^n with
+    /// n up to max_retries
+    pub fn with_timeouts (mut self, base: Duration, max_retries: u32) -> UdpTracker {
+        self.base_timeout = base;
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn url (&self) -> &str {
+        &self.url
+    }
+
+    pub fn announce (&mut self, request: &AnnounceRequest) -> Result<UdpAnnounce, UdpTrackerError> {
+        let mut body = request.info_hash.to_vec();
+        //peer ids are kept as strings with a char per byte
+        body.extend(request.peer_id.chars().map(|x| x as u8));
+        body.extend(request.downloaded.to_be_bytes().iter());
+        body.extend(request.left.to_be_bytes().iter());
+        body.extend(request.uploaded.to_be_bytes().iter());
+        let event = match request.event {
+            Event::None => 0u32,
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3
+        };
+        body.extend(event.to_be_bytes().iter());
+        body.extend([0u8; 4].iter()); //ip, the one the packet came from
+        body.extend(self.key.to_be_bytes().iter());
+        body.extend((-1i32).to_be_bytes().iter()); //num_want, the tracker's default
+        body.extend(request.port.to_be_bytes().iter());
+
+        let response = self.request(ACTION_ANNOUNCE, &body)?;
+        let peer_length = if self.addr.is_ipv4() { 6 } else { 18 };
+        Ok(UdpAnnounce {
+            interval: read_u32(&response[0..4]),
+            leechers: read_u32(&response[4..8]),
+            seeders: read_u32(&response[8..12]),
+            peers: response[12..].chunks(peer_length).filter(|x| x.len() == peer_length).map(|peer| {
+                let (ip, port) = peer.split_at(peer_length - 2);
+                let ip = if ip.len() == 4 {
+                    IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+                } else {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(ip);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                SocketAddr::new(ip, (port[0] as u16) << 8 | port[1] as u16)
+            }).collect()
+        })
+    }
+
+    /// Asks for the swarm sizes of up to MAX_SCRAPE_HASHES torrents, answered in the same order
+    pub fn scrape (&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, UdpTrackerError> {
+        let info_hashes = &info_hashes[..info_hashes.len().min(MAX_SCRAPE_HASHES)];
+        let body = info_hashes.iter().flat_map(|x| x.iter().cloned()).collect::<Vec<u8>>();
+        let response = self.request(ACTION_SCRAPE, &body)?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(UdpTrackerError::InvalidResponse)
+        }
+        Ok(response.chunks(12).take(info_hashes.len()).map(|x| ScrapeStats {
+            seeders: read_u32(&x[0..4]),
+            completed: read_u32(&x[4..8]),
+            leechers: read_u32(&x[8..12])
+        }).collect())
+    }
+
+    /// Sends a request, connecting first if there's no connection id or it has expired, and
+    /// returns what follows the action and transaction id in the answer
+    fn request (&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>, UdpTrackerError> {
+        let mut n = 0;
+        loop {
+            let connection_id = match self.connection {
+                Some((id, at)) if at.elapsed() < Duration::from_secs(CONNECTION_ID_LIFETIME) => Some(id),
+                _ => None
+            };
+            let transaction_id = random::<u32>();
+            let (expected, packet) = match connection_id {
+                Some(id) => (action, to_packet(id, action, transaction_id, body)),
+                None => (ACTION_CONNECT, to_packet(PROTOCOL_ID, ACTION_CONNECT, transaction_id, &[]))
+            };
+            self.socket.send_to(&packet, self.addr)?;
+
+            let timeout = self.base_timeout * (1 << n);
+            match self.receive(transaction_id, timeout)? {
+                Some((ACTION_ERROR, message)) => return Err(UdpTrackerError::Tracker(String::from_utf8_lossy(&message).into_owned())),
+                Some((got, ref data)) if got != expected || data.len() < min_response_length(got) => return Err(UdpTrackerError::InvalidResponse),
+                Some((ACTION_CONNECT, data)) => {
+                    let mut id = [0; 8];
+                    id.copy_from_slice(&data[..8]);
+                    self.connection = Some((u64::from_be_bytes(id), Instant::now()));
+                },
+                Some((_, data)) => return Ok(data),
+                None if n < self.max_retries => n += 1,
+                None => return Err(UdpTrackerError::Timeout)
+            }
+        }
+    }
+
+    /// Waits up to timeout for the answer to transaction_id, skipping anything else that arrives
+    fn receive (&mut self, transaction_id: u32, timeout: Duration) -> Result<Option<(u32, Vec<u8>)>, UdpTrackerError> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; 2048];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            self.socket.set_read_timeout(Some(deadline - now))?;
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(UdpTrackerError::Io(e))
+            };
+            if from != self.addr || len < 8 || read_u32(&buf[4..8]) != transaction_id {
+                continue
+            }
+            return Ok(Some((read_u32(&buf[0..4]), buf[8..len].to_vec())))
+        }
+    }
+}
+
+//what has to follow the action and transaction id in an answer: a connection id for connects,
+//the interval and swarm sizes for announces. Scrapes are checked against the hashes asked for
+fn min_response_length (action: u32) -> usize {
+    match action {
+        ACTION_CONNECT => 8,
+        ACTION_ANNOUNCE => 12,
+        _ => 0
+    }
+}
+
+fn to_packet (connection_id: u64, action: u32, transaction_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut packet = connection_id.to_be_bytes().to_vec();
+    packet.extend(action.to_be_bytes().iter());
+    packet.extend(transaction_id.to_be_bytes().iter());
+    packet.extend(body.iter());
+    packet
+}
+
+fn read_u32 (bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
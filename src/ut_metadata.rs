@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::cmp;
+use bencode::{Bencode, BencodeError, BencodeToString, ToBencode, FromBencode, Decoder, Decoded};
+use bt_messages::Message;
+use metadata::{Metadata, MetadataDict, MetadataError, sha1, truncate_info_hash};
+use merkle::sha256;
+
+/// Contains functionality to fetch the info dictionary from peers and hand ours out, for
+/// sessions started from a magnet link. This is the ut_metadata extension (BEP 9), which runs
+/// over the extension protocol (BEP 10)
+
+/// Extended message id of the extension protocol's own handshake
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+/// The id we ask peers to send ut_metadata messages with
+pub const UT_METADATA_ID: u8 = 3;
+pub const UT_METADATA: &'static str = "ut_metadata";
+/// Metadata is sent in pieces of this size, only the last one may be shorter
+pub const METADATA_PIECE_LENGTH: usize = 16384;
+//anything larger is a peer lying to us, real info dicts stay well below this
+const MAX_METADATA_SIZE: u64 = 1 << 26;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+bencode_struct! {
+    /// The payload of the extended handshake
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ExtendedHandshake {
+        "m" => pub extensions: BTreeMap<String, i64>,
+        "metadata_size" => pub metadata_size: Option<u64>,
+        //not always UTF-8, some clients put their name in latin-1
+        "v" => pub client: Option<Vec<u8>>
+    }
+}
+
+bencode_struct! {
+    //the dictionary at the start of every ut_metadata message
+    struct MetadataHeader {
+        "msg_type" => msg_type: i64,
+        "piece" => piece: u32,
+        "total_size" => total_size: Option<u64>
+    }
+}
+
+impl ExtendedHandshake {
+    /// Our handshake, telling the peer the size of our info dict if we have one to serve
+    pub fn ours (metadata_size: Option<u64>) -> ExtendedHandshake {
+        let mut extensions = BTreeMap::new();
+        extensions.insert(UT_METADATA.to_string(), UT_METADATA_ID as i64);
+        ExtendedHandshake {
+            extensions: extensions,
+            metadata_size: metadata_size,
+            client: Some(b"bittorrent.rs".to_vec())
+        }
+    }
+
+    pub fn to_message (&self) -> Message {
+        //the only integers are ids and the size of an info dict, nowhere near i64::MAX
+        let payload = self.to_bencode().expect("extended handshake out of range");
+        Message::Extended{id: EXTENDED_HANDSHAKE_ID, payload: payload.to_bencode_string()}
+    }
+
+    /// Reads a peer's handshake. Anything in it that is malformed is left out rather than
+    /// costing us the peer: m entries that aren't ids, a metadata_size that isn't a positive
+    /// integer and a v that isn't a string
+    pub fn from_payload (payload: &[u8]) -> Option<ExtendedHandshake> {
+        let dict = match split_value(payload).ok()? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return None
+        };
+        let extensions = match dict.get(&b"m"[..]) {
+            Some(&Bencode::Dict(ref m)) => m.iter().filter_map(|(name, id)| match *id {
+                Bencode::Int(id) => String::from_utf8(name.clone()).ok().map(|name| (name, id)),
+                _ => None
+            }).collect(),
+            _ => BTreeMap::new()
+        };
+        Some(ExtendedHandshake {
+            extensions: extensions,
+            metadata_size: match dict.get(&b"metadata_size"[..]) {
+                Some(&Bencode::Int(size)) if size > 0 => Some(size as u64),
+                _ => None
+            },
+            client: match dict.get(&b"v"[..]) {
+                Some(&Bencode::ByteString(ref client)) => Some(client.clone()),
+                _ => None
+            }
+        })
+    }
+
+    /// The message id the peer wants ut_metadata messages sent with. Zero or out of range ids
+    /// mean the peer doesn't support it
+    pub fn ut_metadata_id (&self) -> Option<u8> {
+        match self.extensions.get(UT_METADATA) {
+            Some(&id) if id > 0 && id < 256 => Some(id as u8),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataMessage {
+    Request {piece: u32},
+    Data {piece: u32, total_size: u64, data: Vec<u8>},
+    Reject {piece: u32}
+}
+
+impl MetadataMessage {
+    /// Wraps the message up to be sent with the id the peer chose for ut_metadata
+    pub fn to_message (&self, peer_id: u8) -> Message {
+        let (header, data) = match *self {
+            MetadataMessage::Request{piece} => (MetadataHeader {msg_type: REQUEST, piece: piece, total_size: None}, &[][..]),
+            MetadataMessage::Data{piece, total_size, ref data} => (MetadataHeader {msg_type: DATA, piece: piece, total_size: Some(total_size)}, &data[..]),
+            MetadataMessage::Reject{piece} => (MetadataHeader {msg_type: REJECT, piece: piece, total_size: None}, &[][..])
+        };
+        //total_size is that of an info dict, nowhere near i64::MAX
+        let mut payload = header.to_bencode().expect("metadata header out of range").to_bencode_string();
+        payload.extend(data.iter());
+        Message::Extended{id: peer_id, payload: payload}
+    }
+
+    /// Reads the payload of an extended message sent with our UT_METADATA_ID
+    pub fn from_payload (payload: &[u8]) -> Option<MetadataMessage> {
+        //data messages carry the metadata piece raw, right after the dictionary
+        let (value, data) = split_value(payload).ok()?;
+        let header = MetadataHeader::from_bencode(&value).ok()?;
+        match header.msg_type {
+            REQUEST => Some(MetadataMessage::Request{piece: header.piece}),
+            DATA => Some(MetadataMessage::Data{piece: header.piece, total_size: header.total_size?, data: data.to_vec()}),
+            REJECT => Some(MetadataMessage::Reject{piece: header.piece}),
+            _ => None
+        }
+    }
+}
+
+/// How a peer's request for a piece of our info dict is answered
+pub fn respond (info_bytes: Option<&[u8]>, piece: u32) -> MetadataMessage {
+    let start = piece as usize * METADATA_PIECE_LENGTH;
+    match info_bytes {
+        Some(info) if start < info.len() => {
+            let end = cmp::min(start + METADATA_PIECE_LENGTH, info.len());
+            MetadataMessage::Data{piece: piece, total_size: info.len() as u64, data: info[start..end].to_vec()}
+        },
+        _ => MetadataMessage::Reject{piece: piece}
+    }
+}
+
+/// An info dict being put together from pieces
+#[derive(Debug, Clone)]
+pub struct MetadataDownload {
+    info_hash: [u8; 20],
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>
+}
+
+impl MetadataDownload {
+    /// None if the size a peer gave is implausible
+    pub fn new (info_hash: [u8; 20], size: u64) -> Option<MetadataDownload> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return None
+        }
+        let size = size as usize;
+        Some(MetadataDownload {
+            info_hash: info_hash,
+            size: size,
+            pieces: vec![None; (size + METADATA_PIECE_LENGTH - 1) / METADATA_PIECE_LENGTH]
+        })
+    }
+
+    pub fn size (&self) -> usize {
+        self.size
+    }
+
+    /// Requests for the pieces we don't have yet
+    pub fn requests (&self) -> Vec<MetadataMessage> {
+        self.pieces.iter().enumerate()
+            .filter(|&(_, piece)| piece.is_none())
+            .map(|(i, _)| MetadataMessage::Request{piece: i as u32})
+            .collect()
+    }
+
+    /// Stores a piece. Once the last one is in, the whole is checked against the info hash and
+    /// turned into Metadata. On a mismatch everything is dropped so it can be fetched again
+    pub fn add_piece (&mut self, piece: u32, data: Vec<u8>) -> Option<Result<Metadata, MetadataError>> {
+        let index = piece as usize;
+        let expected = match self.pieces.len() {
+            n if index >= n => return None,
+            n if index == n - 1 => self.size - index * METADATA_PIECE_LENGTH,
+            _ => METADATA_PIECE_LENGTH
+        };
+        if data.len() != expected {
+            return None
+        }
+        self.pieces[index] = Some(data);
+
+        if self.pieces.iter().any(|x| x.is_none()) {
+            return None
+        }
+        let info_bytes = self.pieces.iter().flat_map(|x| x.as_ref().unwrap().iter().cloned()).collect::<Vec<u8>>();
+        //v2 torrents go by their truncated SHA-256 instead
+        if sha1(&info_bytes) != self.info_hash && truncate_info_hash(&sha256(&info_bytes)) != self.info_hash {
+            for piece in self.pieces.iter_mut() {
+                *piece = None;
+            }
+            return Some(Err(MetadataError::InfoHashMismatch))
+        }
+        Some(to_metadata(info_bytes))
+    }
+}
+
+/// Splits a bencoded value off the front of bytes, returning what follows it
+fn split_value (bytes: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
+    match Decoder::new().push(bytes)? {
+        Decoded::Complete(value, used) => Ok((value, &bytes[used..])),
+        Decoded::NeedMore => Err(BencodeError::UnexpectedEof(bytes.len()))
+    }
+}
+
+//the info dict is all peers send, the rest of the torrent is made around it
+fn to_metadata (info_bytes: Vec<u8>) -> Result<Metadata, MetadataError> {
+    let info = match split_value(&info_bytes)? {
+        (info @ Bencode::Dict(_), rest) if rest.is_empty() => info,
+        _ => return Err(MetadataError::WrongType("dict", vec!["info".to_string()]))
+    };
+    let mut torrent = BTreeMap::new();
+    torrent.insert(b"info".to_vec(), info);
+    torrent.to_metadata_with_info(info_bytes)
+}
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::thread;
+use hyper::Client;
+use hyper::header::{Connection, Range};
+use hyper::status::StatusCode;
+use metadata::Metadata;
+use querystring::QueryString;
+use default_handler::GlobalState;
+
+/// Contains functionality to download from HTTP servers acting as seeds. GetRight style seeds
+/// (url-list, BEP 19) host the files themselves and are asked for byte ranges of them, Hoffman
+/// style seeds (httpseeds, BEP 17) are scripts that hand out pieces by index
+
+/// How long to wait when a Hoffman seed is busy and doesn't say
+pub const RETRY_DEFAULT: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebSeedKind {
+    GetRight,
+    Hoffman
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind
+}
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    Http(String),
+    Io(io::Error),
+    /// The server answered with something other than the data
+    Status(u16),
+    /// A Hoffman seed is busy and asked to be tried again after this many seconds
+    Retry(u64),
+    /// The torrent has no such piece
+    NoSuchPiece(usize)
+}
+
+impl fmt::Display for WebSeedError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WebSeedError::Http(ref e) => write!(f, "{}", e),
+            WebSeedError::Io(ref e) => write!(f, "{}", e),
+            WebSeedError::Status(code) => write!(f, "unexpected status {}", code),
+            WebSeedError::Retry(secs) => write!(f, "seed busy, retry in {}s", secs),
+            WebSeedError::NoSuchPiece(index) => write!(f, "no piece {}", index)
+        }
+    }
+}
+
+impl Error for WebSeedError {}
+
+impl From<io::Error> for WebSeedError {
+    fn from (e: io::Error) -> WebSeedError {
+        WebSeedError::Io(e)
+    }
+}
+
+/// A byte range of one of the torrent's files, as fetched from a GetRight seed
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileRange {
+    pub url: String,
+    /// Where the range starts within the file
+    pub start: u64,
+    pub length: u64,
+    /// Where the range goes within the piece
+    pub piece_offset: u64
+}
+
+impl WebSeed {
+    /// The web seeds listed in the torrent, url-list ones first
+    pub fn from_metadata (metadata: &Metadata) -> Vec<WebSeed> {
+        let get_right = metadata.url_list.iter().map(|url| WebSeed {url: url.clone(), kind: WebSeedKind::GetRight});
+        let hoffman = metadata.http_seeds.iter().map(|url| WebSeed {url: url.clone(), kind: WebSeedKind::Hoffman});
+        get_right.chain(hoffman).collect()
+    }
+
+    /// Where a GetRight seed has the file with the given path, as from Metadata::files. A url
+    /// ending in a slash is a directory the torrent sits in, multi-file torrents are always
+    /// treated that way
+    pub fn file_url (&self, metadata: &Metadata, path: &[String]) -> String {
+        if !metadata.is_multi_file() && !self.url.ends_with('/') {
+            return self.url.clone()
+        }
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url + &path.iter().map(|x| escape_path_component(x)).collect::<Vec<String>>().join("/")
+    }
+
+    /// The file ranges making up piece number index. Pad files aren't hosted and don't show up,
+    /// their bytes are zeroes
+    pub fn ranges (&self, metadata: &Metadata, index: usize) -> Vec<FileRange> {
+        let start = index as u64 * metadata.piece_length;
+        let end = start + metadata.piece_size(index);
+        metadata.files().iter()
+            .filter(|file| file.length > 0 && file.symlink_path.is_none())
+            .filter(|file| file.offset < end && file.offset + file.length > start)
+            .map(|file| {
+                let from = if file.offset > start { file.offset } else { start };
+                let to = if file.offset + file.length < end { file.offset + file.length } else { end };
+                FileRange {
+                    url: self.file_url(metadata, &file.path),
+                    start: from - file.offset,
+                    length: to - from,
+                    piece_offset: from - start
+                }
+            }).collect()
+    }
+
+    /// Downloads piece number index. The data isn't checked against the piece hash
+    pub fn fetch_piece (&self, metadata: &Metadata, index: usize) -> Result<Vec<u8>, WebSeedError> {
+        if index >= metadata.piece_count() {
+            return Err(WebSeedError::NoSuchPiece(index))
+        }
+        match self.kind {
+            WebSeedKind::GetRight => {
+                let mut piece = vec![0; metadata.piece_size(index) as usize];
+                for range in self.ranges(metadata, index) {
+                    let at = range.piece_offset as usize;
+                    fetch_range(&range.url, range.start, &mut piece[at..at + range.length as usize])?;
+                }
+                Ok(piece)
+            },
+            WebSeedKind::Hoffman => self.fetch_hoffman(metadata, index)
+        }
+    }
+
+    fn fetch_hoffman (&self, metadata: &Metadata, index: usize) -> Result<Vec<u8>, WebSeedError> {
+        let separator = if self.url.contains('?') { "&" } else { "?" };
+        let url = format!("{}{}info_hash={}&piece={}", self.url, separator,
+                          QueryString::encode_component(&metadata.info_hash), index);
+        let mut res = Client::new().get(&url).header(Connection::close()).send()
+            .map_err(|e| WebSeedError::Http(e.to_string()))?;
+
+        //a byte more than the piece is enough to tell it's too long
+        let mut body = vec![];
+        (&mut res).take(metadata.piece_size(index) + 1).read_to_end(&mut body)?;
+        match res.status {
+            StatusCode::Ok if body.len() as u64 == metadata.piece_size(index) => Ok(body),
+            StatusCode::Ok => Err(WebSeedError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "piece has the wrong length"))),
+            //the body says how long to back off for
+            StatusCode::ServiceUnavailable => {
+                let secs = String::from_utf8_lossy(&body).trim().parse().unwrap_or(RETRY_DEFAULT);
+                Err(WebSeedError::Retry(secs))
+            },
+            status => Err(WebSeedError::Status(status.to_u16()))
+        }
+    }
+}
+
+/// Fills buf with the file at url from byte start on. Servers that ignore the range and send the
+/// whole file are read up to where the range begins
+fn fetch_range (url: &str, start: u64, buf: &mut [u8]) -> Result<(), WebSeedError> {
+    let end = start + buf.len() as u64 - 1;
+    let mut res = Client::new().get(url).header(Connection::close()).header(Range::bytes(start, end)).send()
+        .map_err(|e| WebSeedError::Http(e.to_string()))?;
+
+    match res.status {
+        StatusCode::PartialContent => {},
+        StatusCode::Ok => {
+            io::copy(&mut (&mut res).take(start), &mut io::sink())?;
+        },
+        status => return Err(WebSeedError::Status(status.to_u16()))
+    }
+    res.read_exact(buf)?;
+    Ok(())
+}
+
+/// Percent encodes everything in a path component but unreserved characters
+fn escape_path_component (component: &str) -> String {
+    component.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+/// Starts a thread per web seed and hands them to the global state, which the spinner then gives
+/// pieces to download. Each thread fetches one piece at a time and reports back through
+/// GlobalState::web_seed_finished
+pub fn start_web_seeds (global_arc: &Arc<Mutex<GlobalState>>, seeds: Vec<WebSeed>) {
+    for seed in seeds {
+        let (tx, rx) = channel::<usize>();
+        let ga = global_arc.clone();
+        let worker_seed = seed.clone();
+        thread::spawn(move || {
+            let mut metadata = None;
+            for index in rx {
+                if metadata.is_none() {
+                    metadata = ga.lock().unwrap().metadata.clone();
+                }
+                let result = match metadata {
+                    Some(ref metadata) => worker_seed.fetch_piece(metadata, index),
+                    None => Err(WebSeedError::NoSuchPiece(index))
+                };
+                ga.lock().unwrap().web_seed_finished(&worker_seed.url, index, result);
+            }
+        });
+        global_arc.lock().unwrap().add_web_seed(seed, tx);
+    }
+}
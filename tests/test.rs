@@ -1,8 +1,32 @@
 extern crate bittorrent;
+extern crate crypto;
+extern crate bencode;
 
+use crypto::sha1::Sha1;
+use crypto::digest::Digest;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use bencode::{Bencode, BencodeToString, TypedMethods, deserialize_value};
+use std::collections::BTreeMap;
 use bittorrent::default_handler::*;
 use bittorrent::chunk::*;
 use bittorrent::peer::*;
+use bittorrent::buffered_reader::BufferedReader;
+use bittorrent::metadata::*;
+use bittorrent::merkle;
+use bittorrent::tracker::*;
+use bittorrent::announce::*;
+use bittorrent::udp_tracker::*;
+use bittorrent::magnet::*;
+use bittorrent::bt_messages::Message;
+use bittorrent::ut_metadata::*;
+use bittorrent::web_seed::*;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_nand_slice() {
@@ -252,4 +276,1382 @@ fn test_intersection () {
 
 }
 
+#[test]
+fn test_info_hash_uses_original_bytes () {
+    //keys in the info dict are out of order, re-encoding would sort them and change the hash
+    let info = b"d4:name1:a6:lengthi5e12:piece lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let mut torrent = b"d8:announce9:localhost4:info".to_vec();
+    torrent.extend(info.iter());
+    torrent.push(b'e');
+
+    let mut sha = Sha1::new();
+    sha.input(info);
+    let mut expected = [0; 20];
+    sha.result(&mut expected);
+
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+    assert_eq!(metadata.info_hash, expected);
+}
+
+#[test]
+fn test_metadata_errors () {
+    let err = |torrent: &[u8]| Metadata::from_bytes(torrent).err().unwrap();
+    let path = |keys: &[&str]| keys.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(err(b"d8:announce9:localhoste"), MetadataError::MissingKey(path(&["info"])));
+    assert_eq!(err(b"le"), MetadataError::NotADict);
+    assert!(match err(b"d8:announce") { MetadataError::Bencode(_) => true, _ => false });
+    assert_eq!(err(b"d8:announce9:localhost4:infod6:lengthi5e4:name1:a12:piece lengthi5e6:pieces3:abcee"),
+               MetadataError::InvalidPieces(3));
+    assert_eq!(err(b"d8:announce9:localhost4:infod6:lengthi5e4:name1:a12:piece lengthi0e6:pieces0:ee"),
+               MetadataError::OutOfRange(path(&["info", "piece length"])));
+    assert_eq!(err(b"d8:announcei5e4:infodee"), MetadataError::WrongType("byte string", path(&["announce"])));
+    assert_eq!(err(b"d8:announce9:localhost4:infod5:filesld6:lengthi5e4:pathl1:\xffeee4:name1:a\
+                     12:piece lengthi5e6:pieces0:ee"),
+               MetadataError::InvalidUtf8(path(&["info", "files", "0", "path", "0"])));
+    //a hash for each piece, no more and no less
+    assert_eq!(err(b"d8:announce9:localhost4:infod6:lengthi5e4:name1:a12:piece lengthi8e6:pieces0:ee"),
+               MetadataError::InvalidPieces(0));
+    assert_eq!(err(b"d8:announce9:localhost4:infod6:lengthi5e4:name1:a12:piece lengthi8e\
+                     6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee"),
+               MetadataError::InvalidPieces(40));
+    //files adding up to more than fits in a u64
+    let huge = "d6:lengthi9223372036854775807e4:pathl1:aee";
+    let torrent = format!("d8:announce9:localhost4:infod5:filesl{}{}{}e4:name1:a12:piece lengthi8e6:pieces0:ee", huge, huge, huge);
+    assert_eq!(err(torrent.as_bytes()), MetadataError::OutOfRange(path(&["info", "files"])));
+
+    //nothing that could put a file outside of the download directory
+    assert_eq!(err(b"d8:announce9:localhost4:infod6:lengthi5e4:name2:..12:piece lengthi8e6:pieces0:ee"),
+               MetadataError::InvalidPath(path(&["info", "name"])));
+    let files = |files: &str| format!("d8:announce9:localhost4:infod5:filesl{}e4:name1:a12:piece lengthi8e6:pieces0:ee", files);
+    assert_eq!(err(files("d6:lengthi5e4:pathl1:b2:..ee").as_bytes()),
+               MetadataError::InvalidPath(path(&["info", "files", "0", "path", "1"])));
+    assert_eq!(err(files("d6:lengthi5e4:pathl3:b/cee").as_bytes()),
+               MetadataError::InvalidPath(path(&["info", "files", "0", "path", "0"])));
+    assert_eq!(err(files("d6:lengthi5e4:pathl0:1:bee").as_bytes()),
+               MetadataError::InvalidPath(path(&["info", "files", "0", "path", "0"])));
+    assert_eq!(err(files("d6:lengthi5e4:pathlee").as_bytes()),
+               MetadataError::InvalidPath(path(&["info", "files", "0", "path"])));
+    assert_eq!(err(files("d6:lengthi0e4:pathl1:be12:symlink pathl4:\\etcee").as_bytes()),
+               MetadataError::InvalidPath(path(&["info", "files", "0", "symlink path", "0"])));
+    assert_eq!(err(b"d4:infod9:file treed1:ad2:..d0:d6:lengthi0eeeee12:meta versioni2e4:name1:a12:piece lengthi16384eee"),
+               MetadataError::InvalidPath(path(&["info", "file tree", "a", ".."])));
+}
+
+#[test]
+fn test_metadata_accessors () {
+    let mut pieces = vec![1; 20];
+    pieces.extend(vec![2; 20]);
+    let mut torrent = b"d8:announce9:localhost4:infod5:filesl\
+                        d6:lengthi5e4:pathl1:a1:bee\
+                        d6:lengthi7e4:pathl1:ceee\
+                        4:name3:dir12:piece lengthi8e6:pieces40:".to_vec();
+    torrent.extend(pieces.iter());
+    torrent.extend(b"ee".iter());
+
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+    assert_eq!(metadata.name(), "dir");
+    assert!(metadata.is_multi_file());
+    assert_eq!(metadata.total_length(), 12);
+    assert_eq!(metadata.piece_count(), 2);
+    assert_eq!(metadata.piece_hash(1), Some(&pieces[20..]));
+    assert_eq!(metadata.piece_hash(2), None);
+    assert_eq!(metadata.piece_hash(usize::max_value()), None);
+
+    let to_path = |keys: &[&str]| keys.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+    assert_eq!(metadata.files(), vec![
+        TorrentFile { path: to_path(&["dir", "a", "b"]), length: 5, offset: 0, attributes: FileAttributes::default(), symlink_path: None },
+        TorrentFile { path: to_path(&["dir", "c"]), length: 7, offset: 5, attributes: FileAttributes::default(), symlink_path: None }
+    ]);
+
+    let single = Metadata::from_bytes(b"d8:announce9:localhost4:infod6:lengthi5e4:name1:a\
+                                        12:piece lengthi8e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+    assert!(!single.is_multi_file());
+    assert_eq!(single.files(), vec![TorrentFile { path: to_path(&["a"]), length: 5, offset: 0, attributes: FileAttributes::default(), symlink_path: None }]);
+}
+
+/// Builds a multi-file torrent with the given file lengths without any file data
+fn synthetic_torrent (piece_length: u64, lengths: &[u64]) -> Vec<u8> {
+    let total = lengths.iter().fold(0, |a, b| a + b);
+    let piece_count = (total + piece_length - 1) / piece_length;
+    let mut torrent = b"d8:announce9:localhost4:infod5:filesl".to_vec();
+    for (i, length) in lengths.iter().enumerate() {
+        torrent.extend(format!("d6:lengthi{}e4:pathl{}:{}ee", length, i.to_string().len(), i).bytes());
+    }
+    torrent.extend(format!("e4:name4:huge12:piece lengthi{}e6:pieces{}:", piece_length, piece_count * 20).bytes());
+    torrent.extend((0..piece_count * 20).map(|_| 0));
+    torrent.extend(b"ee".iter());
+    torrent
+}
+
+#[test]
+fn test_multi_terabyte_torrent () {
+    let tib = 1u64 << 40;
+    let metadata = Metadata::from_bytes(&synthetic_torrent(1 << 30, &[3 * tib, 2 * tib + 1])).unwrap();
+
+    assert_eq!(metadata.total_length(), 5 * tib + 1);
+    assert_eq!(metadata.piece_count(), 5 * 1024 + 1);
+    assert_eq!(metadata.files()[1].offset, 3 * tib);
+    assert_eq!(metadata.bytes_left(4 * tib), tib + 1);
+    assert_eq!(metadata.bytes_left(6 * tib), 0);
+
+    let mut state = GlobalState::new(&metadata);
+    assert_eq!(state.bytes_owned(), 0);
+    state.owned_pieces = vec![Piece::create((0, 0), (5 * 1024 + 1, 0))];
+    assert_eq!(state.bytes_owned(), 5 * tib + 1);
+}
+
+#[test]
+fn test_piece_bytes_past_4gib () {
+    let piece_length = 1 << 20;
+    let piece = Piece::from(piece_length, 5000, 0, 3 << 30);
+    assert_eq!(piece, Piece::create((5000, 0), (8072, 0)));
+    assert_eq!(piece.num_bytes(&piece_length), 3 << 30);
+    assert_eq!(Piece::create((0, 0), (8192, 5)).num_bytes(&piece_length), (8u64 << 30) + 5);
+}
+
+fn sha1 (bytes: &[u8]) -> Vec<u8> {
+    let mut sha = Sha1::new();
+    sha.input(bytes);
+    let mut hash = vec![0; 20];
+    sha.result(&mut hash);
+    hash
+}
+
+#[test]
+fn test_build_torrent () {
+    let dir = env::temp_dir().join("bittorrent-test-build").join("release");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let (a, b): (Vec<u8>, Vec<u8>) = ((0..40000).map(|x| x as u8).collect(), (0..9000).map(|x| (x / 7) as u8).collect());
+    File::create(dir.join("b.bin")).unwrap().write_all(&a).unwrap();
+    File::create(dir.join("sub").join("a.bin")).unwrap().write_all(&b).unwrap();
+    //links aren't followed, so neither a loop nor a second copy of b.bin ends up in the torrent,
+    //and things that aren't files like sockets are left out
+    #[cfg(unix)] {
+        ::std::os::unix::fs::symlink(&dir, dir.join("sub").join("loop")).unwrap();
+        ::std::os::unix::fs::symlink(dir.join("b.bin"), dir.join("c.bin")).unwrap();
+        ::std::os::unix::net::UnixListener::bind(dir.join("d.sock")).unwrap();
+    }
+
+    let torrent = TorrentBuilder::new(&dir)
+        .announce("http://localhost/announce")
+        .announce_list(vec![vec!["http://localhost/announce".to_string()], vec!["udp://backup:80".to_string()]])
+        .comment("test")
+        .created_by("bittorrent")
+        .creation_date(1234)
+        .private(true)
+        .piece_length(1 << 14)
+        .build()
+        .unwrap();
+
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+    assert_eq!(metadata.name(), "release");
+    assert_eq!(metadata.total_length(), 49000);
+    assert_eq!(metadata.files().iter().map(|x| x.path.join("/")).collect::<Vec<String>>(),
+               vec!["release/b.bin", "release/sub/a.bin"]);
+
+    //the third piece straddles both files
+    let mut stream = a.clone();
+    stream.extend(b.iter());
+    assert_eq!(metadata.piece_count(), 3);
+    for (i, chunk) in stream.chunks(1 << 14).enumerate() {
+        assert_eq!(metadata.piece_hash(i).unwrap(), &sha1(chunk)[..]);
+    }
+
+    let dict = match deserialize_value(&torrent).unwrap() {
+        Bencode::Dict(dict) => dict,
+        _ => panic!("torrent is not a dict")
+    };
+    assert_eq!(dict.get_int("creation date"), Some(1234));
+    assert_eq!(dict.get_string("created by"), Some(&b"bittorrent".to_vec()));
+    assert_eq!(dict.get_dict("info").unwrap().get_int("private"), Some(1));
+    assert_eq!(dict.get_list("announce-list").map(|x| x.len()), Some(2));
+
+    //a single file picks its piece length automatically
+    let single = Metadata::from_bytes(&TorrentBuilder::new(dir.join("b.bin")).announce("http://localhost").build().unwrap()).unwrap();
+    assert!(!single.is_multi_file());
+    assert_eq!(single.piece_length, auto_piece_length(40000));
+    assert_eq!(single.piece_hash(0).unwrap(), &sha1(&a[..1 << 14])[..]);
+
+    for &len in [1000, 1 << 13, 1 << 25, 1 << 40].iter() {
+        match TorrentBuilder::new(&dir).piece_length(len).build() {
+            Err(BuildError::InvalidPieceLength(x)) => assert_eq!(x, len),
+            other => panic!("piece length {} gave {:?}", len, other.map(|x| x.len()))
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_auto_piece_length () {
+    assert_eq!(auto_piece_length(0), 1 << 14);
+    assert_eq!(auto_piece_length(1 << 30), 1 << 20);
+    assert_eq!(auto_piece_length(1 << 50), 1 << 24);
+}
+
+fn tiers (tiers: &[&[&str]]) -> Vec<Vec<String>> {
+    tiers.iter().map(|tier| tier.iter().map(|x| x.to_string()).collect()).collect()
+}
+
+#[test]
+fn test_announce_list () {
+    let metadata = Metadata::from_bytes(b"d13:announce-listll1:a1:bel1:cee4:infod6:lengthi5e4:name1:a\
+                                          12:piece lengthi8e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+    assert_eq!(metadata.announce, "a");
+    assert_eq!(metadata.trackers(), tiers(&[&["a", "b"], &["c"]]));
+
+    let single = Metadata::from_bytes(b"d8:announce1:x4:infod6:lengthi5e4:name1:a12:piece lengthi8e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+    assert_eq!(single.trackers(), tiers(&[&["x"]]));
+}
+
+#[test]
+fn test_tracker_list_fallback () {
+    let mut trackers = TrackerList::in_order(tiers(&[&["dead1", "dead2", "live1"], &[], &["live2"]]));
+    let mut tried = vec![];
+    let result = trackers.try_each(|url| {
+        tried.push(url.to_string());
+        if url.starts_with("live") { Some(url.to_string()) } else { None }
+    });
+    assert_eq!(result, Some("live1".to_string()));
+    assert_eq!(tried, vec!["dead1", "dead2", "live1"]);
+    //the working tracker moves to the front of its tier
+    assert_eq!(trackers.tiers(), &tiers(&[&["live1", "dead1", "dead2"], &["live2"]])[..]);
+
+    //a whole dead tier falls through to the next one
+    let result = trackers.try_each(|url| if url == "live2" { Some(()) } else { None });
+    assert_eq!(result, Some(()));
+    assert_eq!(trackers.try_each(|_| None::<()>), None);
+
+    let shuffled = TrackerList::new(tiers(&[&["a", "b", "c"], &["d"]]));
+    let mut first = shuffled.tiers()[0].clone();
+    first.sort();
+    assert_eq!(first, vec!["a", "b", "c"]);
+    assert_eq!(shuffled.tiers()[1], vec!["d"]);
+}
+
+#[test]
+fn test_magnet_link () {
+    let hex = "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Some+Name%21\
+               &tr=http%3A%2F%2Ftracker.one%2Fannounce&tr.1=udp%3A%2F%2Ftracker.two%3A80\
+               &x.pe=127.0.0.1:6881&ws=http%3A%2F%2Fmirror%2Ffile&so=0,2,4-6";
+    let magnet = MagnetLink::parse(hex).unwrap();
+    assert_eq!(&magnet.info_hash[..4], &[0xc1, 0x2f, 0xe1, 0xc0]);
+    assert_eq!(magnet.display_name, Some("Some Name!".to_string()));
+    assert_eq!(magnet.trackers, vec!["http://tracker.one/announce", "udp://tracker.two:80"]);
+    assert_eq!(magnet.web_seeds, vec!["http://mirror/file"]);
+    assert_eq!(magnet.select_only, vec![(0, 0), (2, 2), (4, 6)]);
+    assert!(magnet.is_selected(5) && !magnet.is_selected(3) && !magnet.is_selected(7));
+    match &magnet.peer_addresses()[..] {
+        [Address::TCP(ip, port)] => assert_eq!((*ip, *port), (Ipv4Addr::new(127, 0, 0, 1), 6881)),
+        other => panic!("unexpected peers {:?}", other)
+    }
+    assert_eq!(TrackerList::from_magnet(&magnet).tiers().len(), 2);
+
+    //the same hash in base32
+    let base32: MagnetLink = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK".parse().unwrap();
+    assert_eq!(base32.info_hash, magnet.info_hash);
+    assert_eq!(base32.display_name, None);
+
+    assert_eq!(MagnetLink::parse("http://example.com"), Err(MagnetError::NotAMagnet));
+    assert_eq!(MagnetLink::parse("magnet:?dn=x"), Err(MagnetError::MissingInfoHash));
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btih:abc"), Err(MagnetError::InvalidInfoHash("abc".to_string())));
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&dn=%zz"),
+               Err(MagnetError::InvalidEncoding("dn".to_string())));
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&so=3-1"),
+               Err(MagnetError::InvalidSelection("3-1".to_string())));
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&so=%2B1"),
+               Err(MagnetError::InvalidSelection("+1".to_string())));
+    //ranges aren't expanded, however large
+    let all = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&so=0-18446744073709551615").unwrap();
+    assert!(all.is_selected(1 << 40));
+
+    //hex digits only, no signs or multibyte characters
+    let signed = format!("+f{}", &hex[22..60]);
+    assert_eq!(MagnetLink::parse(&format!("magnet:?xt=urn:btih:%2B{}", &signed[1..])), Err(MagnetError::InvalidInfoHash(signed)));
+    let multibyte = format!("\u{e9}{}", &hex[22..60]);
+    assert_eq!(multibyte.len(), 40);
+    assert_eq!(MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", multibyte)), Err(MagnetError::InvalidInfoHash(multibyte)));
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&dn=%+1"),
+               Err(MagnetError::InvalidEncoding("dn".to_string())));
+}
+
+#[test]
+fn test_merkle_tree () {
+    let mut data = vec![b'a'; 16384];
+    data.extend(vec![b'b'; 16384]);
+    data.extend(vec![b'c'; 100]);
+    let leaves = merkle::block_hashes(&data);
+    let root = merkle::root(&leaves, 4, merkle::ZERO_HASH);
+    assert_eq!(root.iter().map(|x| format!("{:02x}", x)).collect::<String>(),
+               "6745cd3dd5e66eb4754995c1d95784ad85f2b9afdbbf03128036019543dc2393");
+
+    for (i, leaf) in leaves.iter().enumerate() {
+        let proof = merkle::proof(&leaves, 4, i);
+        assert_eq!(proof.len(), 2);
+        assert!(merkle::verify(&root, i, leaf, &proof));
+        assert!(!merkle::verify(&root, i ^ 1, leaf, &proof));
+        assert!(!merkle::verify(&root, i + 4, leaf, &proof));
+    }
+    assert!(!merkle::verify(&root, 0, &merkle::sha256(b"a"), &merkle::proof(&leaves, 4, 0)));
+}
+
+fn dict (entries: Vec<(&str, Bencode)>) -> Bencode {
+    Bencode::Dict(entries.into_iter().map(|(k, v)| (k.as_bytes().to_vec(), v)).collect::<BTreeMap<Vec<u8>, Bencode>>())
+}
+
+/// Builds a v2 torrent named "dir" holding the given files, or a hybrid one that also has v1
+/// pieces, with pad files keeping every file on a piece boundary
+fn v2_torrent (piece_length: usize, files: &[(&str, &[u8])], hybrid: bool) -> Vec<u8> {
+    let blocks = piece_length / merkle::BLOCK_SIZE;
+    let (mut tree, mut layers, mut v1_files, mut stream) = (BTreeMap::new(), BTreeMap::new(), vec![], vec![]);
+    for (i, &(name, data)) in files.iter().enumerate() {
+        let leaves = merkle::block_hashes(data);
+        let root = if data.len() <= piece_length {
+            merkle::root(&leaves, leaves.len().next_power_of_two(), merkle::ZERO_HASH)
+        } else {
+            let layer = leaves.chunks(blocks).map(|x| merkle::root(x, blocks, merkle::ZERO_HASH)).collect::<Vec<_>>();
+            let root = merkle::root(&layer, layer.len().next_power_of_two(), merkle::pad_hash(blocks));
+            layers.insert(root.to_vec(), Bencode::ByteString(layer.iter().flat_map(|x| x.iter().cloned()).collect()));
+            root
+        };
+        tree.insert(name.as_bytes().to_vec(), dict(vec![("", dict(vec![
+            ("length", Bencode::Int(data.len() as i64)),
+            ("pieces root", Bencode::ByteString(root.to_vec()))
+        ]))]));
+
+        let path = |x: Vec<&str>| Bencode::List(x.iter().map(|x| Bencode::ByteString(x.as_bytes().to_vec())).collect());
+        v1_files.push(dict(vec![("length", Bencode::Int(data.len() as i64)), ("path", path(vec![name]))]));
+        stream.extend(data.iter().cloned());
+        let pad = (piece_length - stream.len() % piece_length) % piece_length;
+        if pad > 0 && i + 1 < files.len() {
+            v1_files.push(dict(vec![("attr", Bencode::ByteString(b"p".to_vec())),
+                                    ("length", Bencode::Int(pad as i64)),
+                                    ("path", path(vec![".pad", &pad.to_string()]))]));
+            stream.extend(vec![0; pad]);
+        }
+    }
+
+    let mut info = vec![
+        ("file tree", dict(vec![("dir", Bencode::Dict(tree))])),
+        ("meta version", Bencode::Int(2)),
+        ("name", Bencode::ByteString(b"dir".to_vec())),
+        ("piece length", Bencode::Int(piece_length as i64))
+    ];
+    if hybrid {
+        let pieces = stream.chunks(piece_length).flat_map(sha1).collect::<Vec<u8>>();
+        info.push(("files", Bencode::List(v1_files)));
+        info.push(("pieces", Bencode::ByteString(pieces)));
+    }
+    dict(vec![("info", dict(info)), ("piece layers", Bencode::Dict(layers))]).to_bencode_string()
+}
+
+#[test]
+fn test_v2_torrent () {
+    let piece_length = 2 * merkle::BLOCK_SIZE;
+    let big = (0..piece_length + 5000).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+    let small = vec![7; 100];
+    let torrent = v2_torrent(piece_length, &[("a", &big), ("b", &small)], false);
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+
+    assert_eq!(metadata.version(), TorrentVersion::V2);
+    let info_hash_v2 = merkle::sha256(metadata.info_bytes());
+    assert_eq!(metadata.info_hash_v2, Some(info_hash_v2));
+    assert_eq!(&metadata.info_hash[..], &info_hash_v2[..20]);
+    assert_eq!(metadata.file_tree().len(), 2);
+    assert_eq!(metadata.file_tree()[0].path, vec!["dir".to_string(), "a".to_string()]);
+    assert_eq!(metadata.total_length(), (big.len() + small.len()) as u64);
+    assert_eq!(metadata.piece_count(), 3);
+    //files start on piece boundaries
+    let offsets = metadata.files().iter().map(|x| x.offset).collect::<Vec<u64>>();
+    assert_eq!(offsets, vec![0, 2 * piece_length as u64]);
+
+    assert!(metadata.verify_piece(0, &big[..piece_length]));
+    assert!(metadata.verify_piece(1, &big[piece_length..]));
+    assert!(metadata.verify_piece(2, &small));
+    assert!(!metadata.verify_piece(1, &small));
+    assert!(!metadata.verify_piece(3, &small));
+
+    let leaves = merkle::block_hashes(&big[..piece_length]);
+    let proof = merkle::proof(&leaves, 2, 1);
+    assert!(metadata.verify_block(0, 1, &big[merkle::BLOCK_SIZE..piece_length], &proof));
+    assert!(!metadata.verify_block(0, 0, &big[merkle::BLOCK_SIZE..piece_length], &proof));
+    assert!(metadata.verify_block(2, 0, &small, &[]));
+}
+
+#[test]
+fn test_hybrid_torrent () {
+    let piece_length = merkle::BLOCK_SIZE;
+    let first = vec![1; piece_length + 10];
+    let second = vec![2; 20];
+    let metadata = Metadata::from_bytes(&v2_torrent(piece_length, &[("a", &first), ("b", &second)], true)).unwrap();
+
+    assert_eq!(metadata.version(), TorrentVersion::Hybrid);
+    assert_eq!(&metadata.info_hash[..], &sha1(metadata.info_bytes())[..]);
+    assert_eq!(metadata.info_hash_v2, Some(merkle::sha256(metadata.info_bytes())));
+    assert_eq!(metadata.piece_count(), 3);
+    assert_eq!(metadata.piece_hash_v2(2), metadata.file_tree()[1].pieces_root);
+    //the pad file between them isn't a file of its own
+    let offsets = metadata.files().iter().map(|x| x.offset).collect::<Vec<u64>>();
+    assert_eq!(offsets, vec![0, 2 * piece_length as u64]);
+
+    let mut padded = first[piece_length..].to_vec();
+    padded.extend(vec![0; piece_length - 10]);
+    assert!(metadata.verify_piece(1, &padded));
+    assert!(metadata.verify_piece(2, &second));
+}
+
+#[test]
+fn test_v2_errors () {
+    let piece_length = merkle::BLOCK_SIZE;
+    let mut torrent = v2_torrent(piece_length, &[("a", &vec![1; piece_length * 2])], false);
+    //flip a byte of the piece layer, which comes last
+    let at = torrent.len() - 3;
+    torrent[at] ^= 1;
+    match Metadata::from_bytes(&torrent) {
+        Err(MetadataError::InvalidHashTree(ref path)) => assert_eq!(path[0], "piece layers"),
+        other => panic!("unexpected {:?}", other)
+    }
+
+    let version_3 = b"d4:infod9:file treed1:ad0:d6:lengthi0eeee12:meta versioni3e4:name1:a12:piece lengthi16384eee";
+    assert_eq!(Metadata::from_bytes(version_3).err().unwrap(),
+               MetadataError::OutOfRange(vec!["info".to_string(), "meta version".to_string()]));
+    let short_root = b"d4:infod9:file treed1:ad0:d6:lengthi5e11:pieces root3:abceee12:meta versioni2e\
+                       4:name1:a12:piece lengthi16384eee";
+    assert_eq!(Metadata::from_bytes(short_root).err().map(|e| e.path().map(|x| x.to_vec())), Some(Some(
+               ["info", "file tree", "a", "", "pieces root"].iter().map(|x| x.to_string()).collect::<Vec<String>>())));
+}
+
+#[test]
+fn test_file_attributes () {
+    let attributes = FileAttributes::parse("xhz");
+    assert!(attributes.executable && attributes.hidden && !attributes.pad && !attributes.symlink);
+    assert_eq!(attributes.to_string(), "xh");
+
+    let torrent = b"d8:announce9:localhost4:infod5:filesl\
+                    d4:attr1:x6:lengthi5e4:pathl3:rune4:sha120:aaaaaaaaaaaaaaaaaaaae\
+                    d4:attr1:p6:lengthi3e4:pathl4:.pad1:3ee\
+                    d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:runee\
+                    d6:lengthi4e4:pathl4:dataee\
+                    e4:name3:dir12:piece lengthi8e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee";
+    let metadata = Metadata::from_bytes(torrent).unwrap();
+    match *metadata.mode_info() {
+        FileMode::MultiFile(ref mf) => {
+            assert!(mf.files()[1].is_pad());
+            assert_eq!(mf.files()[0].sha1(), Some(&[b'a'; 20][..]));
+        },
+        _ => panic!("expected a multi-file torrent")
+    }
+    assert_eq!(metadata.total_length(), 12);
+
+    let files = metadata.files();
+    assert_eq!(files.iter().map(|x| (x.path.last().unwrap().as_str(), x.offset)).collect::<Vec<_>>(),
+               vec![("run", 0), ("link", 8), ("data", 8)]);
+    assert!(files[0].attributes.executable);
+    assert_eq!(files[1].symlink_path, Some(vec!["dir".to_string(), "run".to_string()]));
+
+    let dir = env::temp_dir().join("bittorrent-test-attributes");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("dir")).unwrap();
+    File::create(files[0].local_path(&dir)).unwrap().write_all(b"#!/bin/sh").unwrap();
+    for file in files.iter().take(2) {
+        file.apply_attributes(&dir).unwrap();
+    }
+
+    if cfg!(unix) {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(files[0].local_path(&dir)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o100, 0o100);
+        assert_eq!(fs::read_link(files[1].local_path(&dir)).unwrap(), std::path::Path::new("run"));
+        assert_eq!(fs::read(files[1].local_path(&dir)).unwrap(), b"#!/bin/sh");
+
+        let mut escaping = files[1].clone();
+        escaping.symlink_path = Some(vec!["..".to_string(), "etc".to_string()]);
+        assert!(escaping.apply_attributes(&dir).is_err());
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_store_pieces () {
+    let data = b"#!/bin/sh";
+    let mut torrent = b"d8:announce9:localhost4:infod5:filesl\
+                        d4:attr1:x6:lengthi5e4:pathl3:runee\
+                        d4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:runee\
+                        d6:lengthi4e4:pathl3:sub4:dataee\
+                        e4:name3:dir12:piece lengthi8e6:pieces40:".to_vec();
+    torrent.extend(sha1(&data[..8]));
+    torrent.extend(sha1(&data[8..]));
+    torrent.extend(b"ee".iter());
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+
+    let dir = env::temp_dir().join("bittorrent-test-store");
+    let _ = fs::remove_dir_all(&dir);
+    let mut state = GlobalState::new(&metadata);
+    state.set_download_dir(dir.clone());
+    assert_eq!(state.complete_piece(0, b"#!/bin/x"), PieceOutcome::HashFailed);
+    assert_eq!(state.complete_piece(1, &data[8..]), PieceOutcome::Verified);
+    assert!(fs::read(dir.join("dir").join("link")).is_err());
+    assert_eq!(state.complete_piece(0, &data[..8]), PieceOutcome::Verified);
+
+    assert_eq!(fs::read(dir.join("dir").join("run")).unwrap(), b"#!/bi");
+    assert_eq!(fs::read(dir.join("dir").join("sub").join("data")).unwrap(), b"n/sh");
+    if cfg!(unix) {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(dir.join("dir").join("run")).unwrap().permissions().mode() & 0o100, 0o100);
+        assert_eq!(fs::read(dir.join("dir").join("link")).unwrap(), b"#!/bi");
+    }
+
+    //a good piece that can't be written isn't ours
+    let mut state = GlobalState::new(&metadata);
+    state.set_download_dir(dir.join("dir").join("run"));
+    assert_eq!(state.complete_piece(1, &data[8..]), PieceOutcome::WriteFailed);
+    assert!(state.owned_pieces.is_empty());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_v2_magnet_link () {
+    let hash = "0c7ca3fa2ff6d1e4c3b4b6e4a9d1a7a2d3d1a5f4c6d8e3f3a6f2e1d0c9b8a7f6";
+    let v2 = MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220{}", hash)).unwrap();
+    assert_eq!(v2.info_hash_v2.map(|x| x[0]), Some(0x0c));
+    assert_eq!(&v2.info_hash[..], &v2.info_hash_v2.unwrap()[..20]);
+
+    let hybrid = MagnetLink::parse(&format!("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&xt=urn:btmh:1220{}", hash)).unwrap();
+    assert_eq!(hybrid.info_hash[0], 0xc1);
+    assert!(hybrid.info_hash_v2.is_some());
+
+    assert_eq!(MagnetLink::parse("magnet:?xt=urn:btmh:1220abcd"), Err(MagnetError::InvalidInfoHash("abcd".to_string())));
+}
+
+#[test]
+fn test_state_without_metadata () {
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+    let mut state = GlobalState::from_magnet(&magnet);
+    assert!(!state.has_metadata());
+    assert_eq!(state.info_hash, magnet.info_hash);
+
+    let metadata = Metadata::from_bytes(b"d8:announce1:x4:infod6:lengthi5e4:name1:a12:piece lengthi8e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").unwrap();
+    state.set_metadata(metadata);
+    assert!(state.has_metadata());
+}
+
+#[test]
+fn test_metadata_messages () {
+    let request = MetadataMessage::Request{piece: 2};
+    match request.to_message(7) {
+        Message::Extended{id: 7, ref payload} => assert_eq!(MetadataMessage::from_payload(payload), Some(request.clone())),
+        other => panic!("unexpected message {:?}", other)
+    }
+
+    let info = (0..20000).map(|x| x as u8).collect::<Vec<u8>>();
+    let data = respond(Some(&info), 1);
+    assert_eq!(data, MetadataMessage::Data{piece: 1, total_size: 20000, data: info[16384..].to_vec()});
+    match data.to_message(1) {
+        Message::Extended{ref payload, ..} => assert_eq!(MetadataMessage::from_payload(payload), Some(data.clone())),
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(respond(Some(&info), 2), MetadataMessage::Reject{piece: 2});
+    assert_eq!(respond(None, 0), MetadataMessage::Reject{piece: 0});
+
+    let handshake = ExtendedHandshake::ours(Some(20000));
+    match handshake.to_message() {
+        Message::Extended{id: 0, ref payload} => assert_eq!(ExtendedHandshake::from_payload(payload), Some(handshake.clone())),
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(handshake.ut_metadata_id(), Some(UT_METADATA_ID));
+
+    //a bad metadata_size or v doesn't lose us the rest
+    let lenient = ExtendedHandshake::from_payload(b"d1:md11:ut_metadatai3e6:ut_pex1:xe13:metadata_sizei-5e1:v4:\xb5Tor1:pi6881ee").unwrap();
+    assert_eq!(lenient.ut_metadata_id(), Some(3));
+    assert_eq!(lenient.extensions.len(), 1);
+    assert_eq!(lenient.metadata_size, None);
+    assert_eq!(lenient.client, Some(b"\xb5Tor".to_vec()));
+    assert_eq!(ExtendedHandshake::from_payload(b"d13:metadata_size3:abc1:vi1ee").map(|x| (x.metadata_size, x.client)), Some((None, None)));
+}
+
+#[test]
+fn test_metadata_download_rejects_bad_hash () {
+    let mut download = MetadataDownload::new([0; 20], 20000).unwrap();
+    assert_eq!(download.requests().len(), 2);
+    assert!(download.add_piece(0, vec![0; 16384]).is_none());
+    //wrong length for the last piece
+    assert!(download.add_piece(1, vec![0; 10]).is_none());
+    assert_eq!(download.add_piece(1, vec![0; 20000 - 16384]).unwrap().err(), Some(MetadataError::InfoHashMismatch));
+    assert_eq!(download.requests().len(), 2);
+    assert!(MetadataDownload::new([0; 20], 0).is_none());
+}
+
+/// Sets a connected peer up the way main does, returning the peer to hand to the handler
+fn add_peer (state: &mut GlobalState, handshake: &Handshake, reader: &BufferedReader<TcpStream>) -> Peer {
+    let peer = Peer::new(handshake.peer_id.iter().map(|x| *x as char).collect());
+    let mut stream = reader.clone_stream();
+    stream.send_message(state.extended_handshake());
+    state.add_new_peer(Arc::new(RwLock::new(peer.clone())), stream, handshake.peer_id.clone());
+    peer
+}
+
+#[test]
+fn test_fetch_metadata_over_loopback () {
+    //large enough for the info dict to span several metadata pieces
+    let metadata = Metadata::from_bytes(&synthetic_torrent(1 << 14, &[2000 << 14])).unwrap();
+    assert!(metadata.info_bytes().len() > 2 * METADATA_PIECE_LENGTH);
+    let info_hash = metadata.info_hash;
 
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let seed_metadata = metadata.clone();
+    let seeder = thread::spawn(move || {
+        let mut state = GlobalState::new(&seed_metadata);
+        let (stream, _) = listener.accept().unwrap();
+        let (handshake, mut reader) = accept_peer(stream, &info_hash, &gen_rand_peer_id("-SD0001-")).unwrap();
+        assert!(handshake.supports_extensions());
+        let mut peer = add_peer(&mut state, &handshake, &reader);
+        let mut handler = DefaultHandler;
+        //serve until the other side hangs up
+        while let Ok(message) = reader.wait_for_message() {
+            handler.handle(&message, &mut peer, &mut state);
+        }
+    });
+
+    let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}&tr=http%3A%2F%2Flocalhost",
+                                            info_hash.iter().map(|x| format!("{:02x}", x)).collect::<String>())).unwrap();
+    let mut state = GlobalState::from_magnet(&magnet);
+    let (handshake, mut reader) = connect_to_peer(Address::TCP(Ipv4Addr::new(127, 0, 0, 1), port), &info_hash, &gen_rand_peer_id("-LC0001-")).unwrap();
+    let mut peer = add_peer(&mut state, &handshake, &reader);
+    let mut handler = DefaultHandler;
+    while !state.has_metadata() {
+        let message = reader.wait_for_message().unwrap();
+        handler.handle(&message, &mut peer, &mut state);
+    }
+
+    let fetched = state.metadata.clone().unwrap();
+    assert_eq!(fetched.info_hash, info_hash);
+    assert_eq!(fetched.info_bytes(), metadata.info_bytes());
+    assert_eq!(fetched.files(), metadata.files());
+    assert_eq!(fetched.announce, "http://localhost");
+
+    drop(state);
+    drop(reader);
+    seeder.join().unwrap();
+}
+
+#[test]
+fn test_metadata_mismatch_drops_peer () {
+    //a seeder handing out some other torrent's info dict under the hash we asked for
+    let metadata = Metadata::from_bytes(&synthetic_torrent(1 << 14, &[100])).unwrap();
+    let info_hash = [9; 20];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let seeder = thread::spawn(move || {
+        let mut state = GlobalState::new(&metadata);
+        let (stream, _) = listener.accept().unwrap();
+        let (handshake, mut reader) = accept_peer(stream, &info_hash, &gen_rand_peer_id("-SD0001-")).unwrap();
+        let mut peer = add_peer(&mut state, &handshake, &reader);
+        let mut handler = DefaultHandler;
+        while let Ok(message) = reader.wait_for_message() {
+            handler.handle(&message, &mut peer, &mut state);
+        }
+    });
+
+    let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", "09".repeat(20))).unwrap();
+    let mut state = GlobalState::from_magnet(&magnet);
+    let (handshake, mut reader) = connect_to_peer(Address::TCP(Ipv4Addr::new(127, 0, 0, 1), port), &info_hash, &gen_rand_peer_id("-LC0001-")).unwrap();
+    let mut peer = add_peer(&mut state, &handshake, &reader);
+    let mut handler = DefaultHandler;
+    //served until we hang up on the seeder after the mismatch
+    while let Ok(message) = reader.wait_for_message() {
+        handler.handle(&message, &mut peer, &mut state);
+    }
+    assert!(!state.has_metadata());
+    seeder.join().unwrap();
+}
+
+#[test]
+fn test_metadata_download_recovers () {
+    let fetch = |info: &[u8]| {
+        let hash = sha1(info).iter().map(|x| format!("{:02x}", x)).collect::<String>();
+        GlobalState::from_magnet(&MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", hash)).unwrap())
+    };
+    let handshake = |size: usize| {
+        let mut extensions = BTreeMap::new();
+        extensions.insert(UT_METADATA.to_string(), 1);
+        ExtendedHandshake {extensions: extensions, metadata_size: Some(size as u64), client: None}.to_message()
+    };
+    let data = |total_size: usize, data: &[u8]| {
+        MetadataMessage::Data{piece: 0, total_size: total_size as u64, data: data.to_vec()}.to_message(UT_METADATA_ID)
+    };
+    let (mut liar, mut honest) = (Peer::new("liar".to_string()), Peer::new("honest".to_string()));
+    let mut handler = DefaultHandler;
+
+    //the first size given sticks until the pieces fail to match the info hash
+    let info = b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let mut state = fetch(info);
+    handler.handle(&handshake(10), &mut liar, &mut state);
+    handler.handle(&handshake(info.len()), &mut honest, &mut state);
+    handler.handle(&data(info.len(), info), &mut honest, &mut state);
+    assert!(!state.has_metadata());
+    handler.handle(&data(10, &[b'x'; 10]), &mut liar, &mut state);
+    handler.handle(&handshake(info.len()), &mut honest, &mut state);
+    handler.handle(&data(info.len(), info), &mut honest, &mut state);
+    assert_eq!(state.metadata.as_ref().map(|x| x.name()), Some("a"));
+
+    //an info dict that matches the hash but can't be used ends the download for good
+    let info = b"d6:lengthi1e4:name2:..12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+    let mut state = fetch(info);
+    handler.handle(&handshake(info.len()), &mut honest, &mut state);
+    handler.handle(&data(info.len(), info), &mut honest, &mut state);
+    assert!(match state.metadata_error() { Some(&MetadataError::InvalidPath(_)) => true, _ => false });
+    handler.handle(&handshake(info.len()), &mut honest, &mut state);
+    handler.handle(&data(info.len(), info), &mut honest, &mut state);
+    assert!(!state.has_metadata());
+    assert!(state.metadata_error().is_some());
+}
+
+#[test]
+fn test_web_seed_metadata () {
+    let torrent = |extra: &str| {
+        let mut torrent = b"d4:infod5:filesld6:lengthi5e4:pathl1:aeed6:lengthi7e4:pathl1:beee\
+                            4:name3:dir12:piece lengthi8e6:pieces40:".to_vec();
+        torrent.extend(vec![0; 40]);
+        torrent.extend(b"e".iter());
+        torrent.extend(extra.bytes());
+        torrent.push(b'e');
+        Metadata::from_bytes(&torrent).unwrap()
+    };
+
+    let single_url = torrent("8:url-list15:http://mirror/d");
+    assert_eq!(single_url.url_list, vec!["http://mirror/d"]);
+    let metadata = torrent("9:httpseedsl12:http://seed/e8:url-listl15:http://mirror/d0:e");
+    assert_eq!(metadata.url_list, vec!["http://mirror/d"]);
+    assert_eq!(WebSeed::from_metadata(&metadata), vec![
+        WebSeed { url: "http://mirror/d".to_string(), kind: WebSeedKind::GetRight },
+        WebSeed { url: "http://seed/".to_string(), kind: WebSeedKind::Hoffman }
+    ]);
+
+    //the first piece is all of a and the start of b
+    let seed = &WebSeed::from_metadata(&metadata)[0];
+    let to_path = |keys: &[&str]| keys.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+    assert_eq!(seed.file_url(&metadata, &to_path(&["dir", "a b"])), "http://mirror/d/dir/a%20b");
+    assert_eq!(seed.ranges(&metadata, 0), vec![
+        FileRange { url: "http://mirror/d/dir/a".to_string(), start: 0, length: 5, piece_offset: 0 },
+        FileRange { url: "http://mirror/d/dir/b".to_string(), start: 0, length: 3, piece_offset: 5 }
+    ]);
+    assert_eq!(seed.ranges(&metadata, 1), vec![
+        FileRange { url: "http://mirror/d/dir/b".to_string(), start: 3, length: 4, piece_offset: 0 }
+    ]);
+}
+
+/// Serves files over HTTP on a local port, honouring Range headers. /seed acts as a Hoffman web
+/// seed handing out pieces of stream
+fn serve_http (files: Vec<(String, Vec<u8>)>, stream: Vec<u8>, piece_length: usize) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = match conn { Ok(conn) => conn, Err(_) => continue };
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let path = request.split(' ').nth(1).unwrap_or("").to_string();
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                    break
+                }
+                if line.to_lowercase().starts_with("range: bytes=") {
+                    let spec = line.trim()["range: bytes=".len()..].to_string();
+                    let mut ends = spec.split('-').map(|x| x.parse::<usize>().unwrap());
+                    range = Some((ends.next().unwrap(), ends.next().unwrap()));
+                }
+            }
+
+            let (status, body) = if path.starts_with("/seed?") {
+                let piece = path.split("piece=").nth(1).and_then(|x| x.parse::<usize>().ok()).unwrap();
+                ("200 OK", stream.chunks(piece_length).nth(piece).unwrap().to_vec())
+            } else {
+                match files.iter().find(|x| x.0 == path) {
+                    Some(&(_, ref data)) => match range {
+                        Some((start, end)) => ("206 Partial Content", data[start..end + 1].to_vec()),
+                        None => ("200 OK", data.clone())
+                    },
+                    None => ("404 Not Found", vec![])
+                }
+            };
+            let _ = write!(conn, "HTTP/1.0 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+            let _ = conn.write_all(&body);
+        }
+    });
+    port
+}
+
+#[test]
+fn test_web_seed_download () {
+    let dir = env::temp_dir().join("bittorrent-test-web-seed").join("release");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (a, b): (Vec<u8>, Vec<u8>) = ((0..20000).map(|x| x as u8).collect(), (0..30000).map(|x| (x / 3) as u8).collect());
+    File::create(dir.join("a")).unwrap().write_all(&a).unwrap();
+    File::create(dir.join("b")).unwrap().write_all(&b).unwrap();
+    let mut stream = a.clone();
+    stream.extend(b.iter());
+
+    let files = vec![("/release/a".to_string(), a.clone()), ("/release/b".to_string(), b.clone())];
+    let port = serve_http(files, stream.clone(), 1 << 14);
+    let torrent = TorrentBuilder::new(&dir)
+        .url_list(vec![format!("http://127.0.0.1:{}/", port)])
+        .piece_length(1 << 14)
+        .build()
+        .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    let metadata = Metadata::from_bytes(&torrent).unwrap();
+    assert_eq!(metadata.piece_count(), 4);
+    assert_eq!(metadata.piece_size(3), 50000 - 3 * (1 << 14));
+
+    let get_right = &WebSeed::from_metadata(&metadata)[0];
+    for i in 0..metadata.piece_count() {
+        let piece = get_right.fetch_piece(&metadata, i).unwrap();
+        assert!(metadata.verify_piece(i, &piece));
+    }
+    assert!(get_right.fetch_piece(&metadata, 4).is_err());
+
+    let hoffman = WebSeed { url: format!("http://127.0.0.1:{}/seed", port), kind: WebSeedKind::Hoffman };
+    assert!(metadata.verify_piece(1, &hoffman.fetch_piece(&metadata, 1).unwrap()));
+
+    //the spinner hands pieces out to the seed until it has all of them, which end up on disk
+    let download_dir = env::temp_dir().join("bittorrent-test-web-seed").join("download");
+    let _ = fs::remove_dir_all(&download_dir);
+    let mut state = GlobalState::new(&metadata);
+    state.set_download_dir(download_dir.clone());
+    let global_arc = Arc::new(Mutex::new(state));
+    start_web_seeds(&global_arc, WebSeed::from_metadata(&metadata));
+    let started = Instant::now();
+    while global_arc.lock().unwrap().bytes_owned() < metadata.total_length() {
+        assert!(started.elapsed() < Duration::from_secs(10), "web seed download timed out");
+        global_arc.lock().unwrap().spin();
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(global_arc.lock().unwrap().owned_pieces, vec![Piece::create((0, 0), (4, 0))]);
+    assert_eq!(fs::read(download_dir.join("release").join("a")).unwrap(), a);
+    assert_eq!(fs::read(download_dir.join("release").join("b")).unwrap(), b);
+    let _ = fs::remove_dir_all(&download_dir);
+}
+
+#[test]
+fn test_pieces_from_blocks () {
+    let metadata = Metadata::from_bytes(&synthetic_torrent(8, &[12])).unwrap();
+    let mut state = GlobalState::new(&metadata);
+    //synthetic pieces are all zero hashes, which nothing hashes to
+    assert_eq!(state.add_block(0, 4, &[1; 4]), None);
+    assert_eq!(state.add_block(0, 4, &[1; 4]), None);
+    assert_eq!(state.add_block(0, 0, &[1; 4]), Some(PieceOutcome::HashFailed));
+    assert_eq!(state.add_block(1, 0, &[1; 5]), None);
+
+    let mut torrent = b"d4:infod6:lengthi12e4:name1:a12:piece lengthi8e6:pieces40:".to_vec();
+    torrent.extend(sha1(&[1; 8]).iter());
+    torrent.extend(sha1(&[2; 4]).iter());
+    torrent.extend(b"ee".iter());
+    let mut state = GlobalState::new(&Metadata::from_bytes(&torrent).unwrap());
+    assert_eq!(state.add_block(1, 0, &[2; 4]), Some(PieceOutcome::Verified));
+    assert_eq!(state.add_block(0, 0, &[1; 4]), None);
+    assert_eq!(state.add_block(0, 4, &[1; 4]), Some(PieceOutcome::Verified));
+    assert_eq!(state.owned_pieces, vec![Piece::create((0, 0), (2, 0))]);
+    assert_eq!(state.bytes_owned(), 12);
+}
+
+fn announce_request (event: Event, downloaded: u64, left: u64) -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [7; 20],
+        peer_id: "-TR1000-000000000000".to_string(),
+        port: 6887,
+        uploaded: 0,
+        downloaded: downloaded,
+        left: left,
+        event: event,
+        ipv4: None,
+        ipv6: None
+    }
+}
+
+/// A UDP tracker that answers announces with the given peers in compact form, drops the first
+/// announce it gets when told to, and logs the action of every packet received
+fn udp_stand_in (bind: &str, peers: Vec<u8>, drop_first_announce: bool) -> Option<(SocketAddr, Arc<Mutex<Vec<u32>>>)> {
+    let socket = UdpSocket::bind(bind).ok()?;
+    let addr = socket.local_addr().unwrap();
+    let log = Arc::new(Mutex::new(vec![]));
+    let thread_log = log.clone();
+    thread::spawn(move || {
+        let connection_id = [0, 0, 0, 0, 0xc0, 0xff, 0xee, 0];
+        let mut dropped = !drop_first_announce;
+        let mut buf = [0; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).unwrap();
+            let packet = &buf[..len];
+            let action = packet[11] as u32;
+            thread_log.lock().unwrap().push(action);
+            let mut reply = packet[8..16].to_vec();
+            match action {
+                0 => {
+                    assert_eq!(&packet[0..8], &[0, 0, 4, 0x17, 0x27, 0x10, 0x19, 0x80]);
+                    reply.extend(connection_id.iter());
+                },
+                _ if &packet[0..8] != &connection_id => {
+                    reply[3] = 3;
+                    reply.extend(b"bad connection id".iter());
+                },
+                1 => {
+                    if !dropped {
+                        dropped = true;
+                        continue
+                    }
+                    assert_eq!(len, 98);
+                    assert_eq!(&packet[36..56], b"-TR1000-000000000000");
+                    assert_eq!(&packet[80..84], &[0, 0, 0, 2]); //started
+                    assert_eq!(&packet[96..98], &[0x1a, 0xe7]); //6887
+                    reply.extend([0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2].iter());
+                    reply.extend(peers.iter());
+                },
+                _ => {
+                    for (i, _) in packet[16..].chunks(20).enumerate() {
+                        reply.extend([0, 0, 0, i as u8 + 1, 0, 0, 0, 9, 0, 0, 0, 4].iter());
+                    }
+                }
+            }
+            socket.send_to(&reply, from).unwrap();
+        }
+    });
+    Some((addr, log))
+}
+
+#[test]
+fn test_udp_tracker () {
+    let peer_id = "-TR1000-000000000000".to_string();
+    let info_hash = [7; 20];
+    let request = announce_request(Event::Started, 0, 100);
+    let peers = vec![10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80];
+    let (addr, log) = udp_stand_in("127.0.0.1:0", peers, true).unwrap();
+
+    //the first announce goes unanswered and is sent again after the base timeout
+    let mut tracker = UdpTracker::new(&format!("udp://{}/announce", addr)).unwrap()
+        .with_timeouts(Duration::from_millis(50), 2);
+    let started = Instant::now();
+    let response = UdpTracker::announce(&mut tracker, &request).unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(response.interval, 0x708);
+    assert_eq!(response.leechers, 1);
+    assert_eq!(response.seeders, 2);
+    assert_eq!(response.peers, vec![
+        SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881),
+        SocketAddr::new(Ipv4Addr::new(192, 168, 1, 2).into(), 80)
+    ]);
+
+    //the connection id is reused rather than asked for again
+    let stats = tracker.scrape(&[[1; 20], [2; 20]]).unwrap();
+    assert_eq!(stats, vec![
+        ScrapeStats {seeders: 1, completed: 9, leechers: 4},
+        ScrapeStats {seeders: 2, completed: 9, leechers: 4}
+    ]);
+    assert_eq!(*log.lock().unwrap(), vec![0, 1, 1, 2]);
+
+    //same interface as HTTP trackers, falling back past ones that don't answer
+    let mut trackers = TrackerList::in_order(vec![vec!["udp://nowhere.invalid:80".to_string(), format!("udp://{}", addr)]]);
+    let peers = get_tracker_peers(&mut trackers, &peer_id, &info_hash, 6887, 0, 100).unwrap();
+    assert_eq!(peers[1], Address::TCP(Ipv4Addr::new(192, 168, 1, 2), 80));
+    assert_eq!(trackers.tiers()[0][0], format!("udp://{}", addr));
+}
+
+#[test]
+fn test_udp_tracker_errors () {
+    assert!(match UdpTracker::new("http://tracker.example.com/announce") {
+        Err(UdpTrackerError::InvalidUrl(_)) => true,
+        _ => false
+    });
+
+    //nothing listening, every retransmission goes unanswered
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut tracker = UdpTracker::from_addr(silent.local_addr().unwrap()).unwrap()
+        .with_timeouts(Duration::from_millis(10), 2);
+    let started = Instant::now();
+    assert!(match UdpTracker::announce(&mut tracker, &announce_request(Event::Started, 0, 100)) {
+        Err(UdpTrackerError::Timeout) => true,
+        _ => false
+    });
+    //10 + 20 + 40ms
+    assert!(started.elapsed() >= Duration::from_millis(70));
+    let mut buf = [0; 16];
+    for _ in 0..3 {
+        assert_eq!(silent.recv_from(&mut buf).unwrap().0, 16);
+    }
+}
+
+#[test]
+fn test_udp_tracker_replies () {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    //two announces answered in full, then a connect answered with half a connection id
+    let stand_in = thread::spawn(move || {
+        let mut buf = [0; 2048];
+        let mut keys = vec![];
+        loop {
+            let (_, from) = socket.recv_from(&mut buf).unwrap();
+            let mut reply = buf[8..16].to_vec();
+            match buf[11] {
+                0 if keys.len() == 2 => {
+                    reply.extend([0xc0, 0xff].iter());
+                    socket.send_to(&reply, from).unwrap();
+                    return keys
+                },
+                0 => reply.extend([0, 0, 0, 0, 0xc0, 0xff, 0xee, 0].iter()),
+                _ => {
+                    keys.push(buf[88..92].to_vec());
+                    reply.extend([0; 12].iter());
+                }
+            }
+            socket.send_to(&reply, from).unwrap();
+        }
+    });
+
+    let mut tracker = UdpTracker::from_addr(addr).unwrap().with_timeouts(Duration::from_secs(5), 0);
+    for _ in 0..2 {
+        assert!(UdpTracker::announce(&mut tracker, &announce_request(Event::Started, 0, 100)).is_ok());
+    }
+    let mut tracker = UdpTracker::from_addr(addr).unwrap().with_timeouts(Duration::from_secs(5), 0);
+    assert!(match UdpTracker::announce(&mut tracker, &announce_request(Event::Started, 0, 100)) {
+        Err(UdpTrackerError::InvalidResponse) => true,
+        _ => false
+    });
+    //the key stays the same for the session
+    let keys = stand_in.join().unwrap();
+    assert_eq!(keys[0], keys[1]);
+}
+
+#[test]
+fn test_udp_tracker_ipv6 () {
+    let mut peers = vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    peers.extend([0x1a, 0xe1].iter());
+    //skip where there's no IPv6 loopback
+    let (addr, _) = match udp_stand_in("[::1]:0", peers, false) {
+        Some(stand_in) => stand_in,
+        None => return
+    };
+    let mut tracker = UdpTracker::new(&format!("udp://{}/announce", addr)).unwrap();
+    let response = UdpTracker::announce(&mut tracker, &announce_request(Event::Started, 0, 100)).unwrap();
+    assert_eq!(response.peers, vec![SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 6881)]);
+    let response = Tracker::announce(&mut tracker, &announce_request(Event::Started, 0, 100)).unwrap();
+    assert_eq!(response.addresses(), vec![Address::TCP6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6881)]);
+}
+
+/// An HTTP tracker that answers announces with the given responses in turn, the last one over and
+/// over, and logs the query strings of the requests
+fn http_tracker (responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let log = Arc::new(Mutex::new(vec![]));
+    let thread_log = log.clone();
+    thread::spawn(move || {
+        for (i, conn) in listener.incoming().enumerate() {
+            let mut conn = match conn { Ok(conn) => conn, Err(_) => continue };
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let path = request.split(' ').nth(1).unwrap_or("").to_string();
+            thread_log.lock().unwrap().push(path.splitn(2, '?').nth(1).unwrap_or("").to_string());
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                    break
+                }
+            }
+            let body = &responses[i.min(responses.len() - 1)];
+            let _ = write!(conn, "HTTP/1.0 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = conn.write_all(body);
+        }
+    });
+    (url, log)
+}
+
+fn query_value (query: &str, key: &str) -> Option<String> {
+    query.split('&').find(|x| x.starts_with(&format!("{}=", key))).map(|x| x[key.len() + 1..].to_string())
+}
+
+#[test]
+fn test_announce_schedule () {
+    let mut response = b"d8:intervali100e12:min intervali30e5:peers6:".to_vec();
+    response.extend([10, 0, 0, 1, 0x1a, 0xe1].iter());
+    response.push(b'e');
+    let (url, log) = http_tracker(vec![response]);
+    let mut scheduler = AnnounceScheduler::new(TrackerList::in_order(vec![vec![url]]), [7; 20], "-TR1000-000000000000".to_string(), 6887);
+    scheduler.set_local_addresses(Some(Ipv4Addr::new(10, 1, 2, 3)), Some(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 5)));
+    let mut stats = TransferStats {uploaded: 0, downloaded: 0, left: 1000};
+
+    assert_eq!(scheduler.due(0, &stats), Some(Event::Started));
+    let peers = scheduler.poll(0, &stats).unwrap();
+    assert_eq!(peers, vec![Address::TCP(Ipv4Addr::new(10, 0, 0, 1), 6881)]);
+    assert_eq!(scheduler.next_announce(), 100);
+    assert_eq!(scheduler.poll(50, &stats), None);
+
+    //completed goes out early, but not before the min interval is up
+    stats = TransferStats {uploaded: 300, downloaded: 1200, left: 0};
+    assert_eq!(scheduler.due(20, &stats), None);
+    assert_eq!(scheduler.due(30, &stats), Some(Event::Completed));
+    assert!(scheduler.poll(30, &stats).is_some());
+    assert_eq!(scheduler.due(129, &stats), None);
+    assert!(scheduler.poll(130, &stats).is_some());
+    scheduler.stop(&stats);
+
+    let log = log.lock().unwrap();
+    let params = |i: usize, key: &str| query_value(&log[i], key);
+    assert_eq!(log.len(), 4);
+    assert_eq!(params(0, "event"), Some("started".to_string()));
+    assert_eq!(params(0, "left"), Some("1000".to_string()));
+    assert_eq!(params(0, "ipv4"), Some("10.1.2.3".to_string()));
+    assert_eq!(params(0, "ipv6"), Some("2001:db8::5".to_string()));
+    assert_eq!(params(1, "event"), Some("completed".to_string()));
+    assert_eq!((params(1, "uploaded"), params(1, "downloaded"), params(1, "left")),
+               (Some("300".to_string()), Some("1200".to_string()), Some("0".to_string())));
+    assert_eq!(params(2, "event"), None);
+    assert_eq!(params(3, "event"), Some("stopped".to_string()));
+}
+
+/// A tracker that fails until told otherwise
+struct FlakyTracker {
+    up: Arc<Mutex<bool>>,
+    events: Arc<Mutex<Vec<Event>>>
+}
+
+impl Tracker for FlakyTracker {
+    fn url (&self) -> &str {
+        "flaky"
+    }
+
+    fn announce (&mut self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        self.events.lock().unwrap().push(request.event);
+        if !*self.up.lock().unwrap() {
+            return Err(TrackerError::InvalidResponse)
+        }
+        Ok(AnnounceResponse {interval: 600, ..AnnounceResponse::default()})
+    }
+}
+
+#[test]
+fn test_announce_retry () {
+    let (up, events) = (Arc::new(Mutex::new(false)), Arc::new(Mutex::new(vec![])));
+    let mut scheduler = AnnounceScheduler::new(TrackerList::in_order(vec![vec!["flaky".to_string()]]), [7; 20], "-TR1000-000000000000".to_string(), 6887);
+    scheduler.set_tracker(Box::new(FlakyTracker {up: up.clone(), events: events.clone()}));
+    //a session that starts out seeding never sends completed
+    let stats = TransferStats {uploaded: 0, downloaded: 0, left: 0};
+
+    //stopped is only sent once started went through
+    scheduler.stop(&stats);
+    assert_eq!(scheduler.poll(0, &stats), None);
+    assert_eq!(scheduler.next_announce(), RETRY_INTERVAL);
+    assert_eq!(scheduler.due(RETRY_INTERVAL - 1, &stats), None);
+
+    *up.lock().unwrap() = true;
+    assert_eq!(scheduler.poll(RETRY_INTERVAL, &stats), Some(vec![]));
+    assert_eq!(scheduler.due(RETRY_INTERVAL + 1, &stats), None);
+    assert_eq!(scheduler.due(RETRY_INTERVAL + 600, &stats), Some(Event::None));
+    assert_eq!(*events.lock().unwrap(), vec![Event::Started, Event::Started]);
+}
+
+#[test]
+fn test_announce_interval_floor () {
+    //a tracker asking for announces every second gets one a minute
+    let (url, _) = http_tracker(vec![b"d8:intervali0e12:min intervali1e5:peers0:e".to_vec()]);
+    let mut scheduler = AnnounceScheduler::new(TrackerList::in_order(vec![vec![url]]), [7; 20], "-TR1000-000000000000".to_string(), 6887);
+    let stats = TransferStats {uploaded: 0, downloaded: 0, left: 1000};
+    assert!(scheduler.poll(0, &stats).is_some());
+    assert_eq!(scheduler.next_announce(), MIN_ANNOUNCE_INTERVAL);
+    assert_eq!(scheduler.due(MIN_ANNOUNCE_INTERVAL - 1, &stats), None);
+    assert!(scheduler.poll(MIN_ANNOUNCE_INTERVAL, &stats).is_some());
+    assert_eq!(scheduler.next_announce(), 2 * MIN_ANNOUNCE_INTERVAL);
+}
+
+#[test]
+fn test_announce_stopped_on_shutdown () {
+    let metadata = Metadata::from_bytes(&synthetic_torrent(16384, &[20000])).unwrap();
+    let global_arc = Arc::new(Mutex::new(GlobalState::new(&metadata)));
+    let failure = b"d14:failure reason4:nopee".to_vec();
+    let (url, log) = http_tracker(vec![b"d8:intervali1800e5:peers0:e".to_vec(), failure.clone()]);
+    let (failing_url, failing_log) = http_tracker(vec![failure]);
+    let scheduler = AnnounceScheduler::new(TrackerList::in_order(vec![vec![failing_url, url]]), [7; 20], "-TR1000-000000000000".to_string(), 6887);
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+    let announcer = thread::spawn(move || scheduler.run(global_arc, stop_rx, |_| {}));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while log.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    stop_tx.send(()).unwrap();
+    announcer.join().unwrap();
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 2);
+    assert_eq!(query_value(&log[0], "event"), Some("started".to_string()));
+    assert_eq!(query_value(&log[1], "event"), Some("stopped".to_string()));
+    assert_eq!(query_value(&log[1], "left"), Some("20000".to_string()));
+    //stopped only goes to the tracker that answered, even when it doesn't take it
+    assert_eq!(failing_log.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_scrape_url () {
+    assert_eq!(scrape_url("http://example.com/announce"), Some("http://example.com/scrape".to_string()));
+    assert_eq!(scrape_url("http://example.com/x/announce"), Some("http://example.com/x/scrape".to_string()));
+    assert_eq!(scrape_url("http://example.com/announce.php"), Some("http://example.com/scrape.php".to_string()));
+    assert_eq!(scrape_url("http://example.com/announce?x2%0644"), Some("http://example.com/scrape?x2%0644".to_string()));
+    assert_eq!(scrape_url("http://example.com/a"), None);
+    assert_eq!(scrape_url("http://example.com/announce?x=2/4"), None);
+    assert_eq!(scrape_url("http://example.com/x%064announce"), None);
+}
+
+#[test]
+fn test_http_scrape () {
+    let mut response = b"d5:filesd20:".to_vec();
+    response.extend([1; 20].iter());
+    response.extend(b"d8:completei5e10:downloadedi50e10:incompletei10ee20:".iter());
+    response.extend([2; 20].iter());
+    response.extend(b"d8:completei0e10:downloadedi1e10:incompletei2eee5:flagsd20:min_request_intervali900eee".iter());
+    let (url, log) = http_tracker(vec![response]);
+    let mut tracker = HttpTracker::new(&url);
+
+    let scrape = tracker.scrape(&[[1; 20], [2; 20], [3; 20]]).unwrap();
+    assert_eq!(scrape.min_request_interval, Some(900));
+    assert_eq!(scrape.files.len(), 2);
+    assert_eq!(scrape.files[&[1; 20]], ScrapeStats {seeders: 5, completed: 50, leechers: 10});
+    assert_eq!(scrape.files[&[2; 20]], ScrapeStats {seeders: 0, completed: 1, leechers: 2});
+    assert_eq!(log.lock().unwrap()[0].matches("info_hash=").count(), 3);
+
+    //the tracker isn't asked again before min_request_interval is up
+    assert!(match tracker.scrape(&[[1; 20]]) {
+        Err(TrackerError::ScrapeTooSoon(secs)) => secs > 890 && secs <= 900,
+        _ => false
+    });
+    assert_eq!(log.lock().unwrap().len(), 1);
+
+    assert!(match HttpTracker::new("http://example.com/a").scrape(&[[1; 20]]) {
+        Err(TrackerError::ScrapeUnsupported) => true,
+        _ => false
+    });
+}
+
+#[test]
+fn test_udp_scrape () {
+    let (addr, log) = udp_stand_in("127.0.0.1:0", vec![], false).unwrap();
+    let mut tracker = open_tracker(&format!("udp://{}", addr)).unwrap();
+    //more than fit in one packet are split over several
+    let hashes = (0..80).map(|x| [x as u8; 20]).collect::<Vec<[u8; 20]>>();
+    let scrape = tracker.scrape(&hashes).unwrap();
+    assert_eq!(scrape.files.len(), 80);
+    assert_eq!(scrape.files[&[73; 20]], ScrapeStats {seeders: 74, completed: 9, leechers: 4});
+    assert_eq!(scrape.files[&[74; 20]], ScrapeStats {seeders: 1, completed: 9, leechers: 4});
+    assert_eq!(*log.lock().unwrap(), vec![0, 2, 2]);
+}
+
+fn announce_response (bytes: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    match deserialize_value(bytes) {
+        Ok(Bencode::Dict(dict)) => AnnounceResponse::from_dict(&dict),
+        other => panic!("not a dictionary: {:?}", other)
+    }
+}
+
+#[test]
+fn test_announce_response () {
+    let mut compact = b"d8:completei9e10:incompletei3e8:intervali900e12:min intervali60e5:peers12:".to_vec();
+    compact.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0, 80].iter());
+    compact.extend(b"10:tracker id3:abc15:warning message9:slow downe".iter());
+    let response = announce_response(&compact).unwrap();
+    assert_eq!((response.interval, response.min_interval), (900, Some(60)));
+    assert_eq!((response.complete, response.incomplete), (Some(9), Some(3)));
+    assert_eq!(response.tracker_id, Some("abc".to_string()));
+    assert_eq!(response.warnings, vec!["slow down".to_string()]);
+    assert_eq!(response.addresses(), vec![Address::TCP(Ipv4Addr::new(10, 0, 0, 1), 6881),
+                                          Address::TCP(Ipv4Addr::new(10, 0, 0, 2), 80)]);
+
+    //dictionary peers, skipping ones that can't be dialed
+    let dicts = b"d8:intervali900e5:peersld2:ip8:10.0.0.37:peer id20:-XX0001-0123456789ab4:porti6881eed2:ip9:not an ip4:porti1eed2:ip8:10.0.0.44:porti0eeee";
+    let response = announce_response(dicts).unwrap();
+    assert_eq!(response.peers, vec![TrackerPeer {
+        address: Address::TCP(Ipv4Addr::new(10, 0, 0, 3), 6881),
+        peer_id: Some(b"-XX0001-0123456789ab".to_vec())
+    }]);
+    assert_eq!(response.tracker_id, None);
+    assert!(response.warnings.is_empty());
+
+    //no peers is an empty list, missing interval the default
+    let response = announce_response(b"de").unwrap();
+    assert_eq!((response.interval, response.peers.len()), (DEFAULT_INTERVAL, 0));
+    assert!(match announce_response(b"d5:peersi5ee") {
+        Err(TrackerError::InvalidResponse) => true,
+        _ => false
+    });
+    assert!(match announce_response(b"d14:failure reason12:unregistered5:peers0:e") {
+        Err(TrackerError::Failure(ref reason)) => reason == "unregistered",
+        _ => false
+    });
+}
+
+#[test]
+fn test_tracker_failure_and_id () {
+    let (url, log) = http_tracker(vec![b"d8:intervali900e5:peers0:10:tracker id4:t-01e".to_vec(),
+                                       b"d8:intervali900e5:peers0:e".to_vec(),
+                                       b"d14:failure reason16:torrent not heree".to_vec()]);
+    let mut tracker = HttpTracker::new(&url);
+    tracker.announce(&announce_request(Event::Started, 0, 100)).unwrap();
+    tracker.announce(&announce_request(Event::None, 0, 100)).unwrap();
+    //kept after responses that don't repeat it
+    tracker.announce(&announce_request(Event::None, 0, 100)).err().unwrap();
+    {
+        let log = log.lock().unwrap();
+        assert_eq!(query_value(&log[0], "trackerid"), None);
+        assert_eq!(query_value(&log[1], "trackerid"), Some("t-01".to_string()));
+        assert_eq!(query_value(&log[2], "trackerid"), Some("t-01".to_string()));
+    }
+
+    let torrent = format!("d8:announce{}:{}4:infod6:lengthi5e4:name1:a12:piece lengthi8e6:pieces20:aaaaaaaaaaaaaaaaaaaaee", url.len(), url);
+    let metadata = Metadata::from_bytes(torrent.as_bytes()).unwrap();
+    assert!(match get_http_tracker_peers(&"-TR1000-000000000000".to_string(), &metadata, 6887, 0) {
+        Err(TrackerError::Failure(ref reason)) => reason == "torrent not here",
+        _ => false
+    });
+}
+
+#[test]
+fn test_ipv6_peers () {
+    //peers6 alongside compact IPv4 peers, as in BEP 7
+    let mut response = b"d8:intervali900e5:peers6:".to_vec();
+    response.extend([10, 0, 0, 1, 0x1a, 0xe1].iter());
+    response.extend(b"6:peers618:".iter());
+    response.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe2].iter());
+    response.push(b'e');
+    assert_eq!(announce_response(&response).unwrap().addresses(), vec![
+        Address::TCP(Ipv4Addr::new(10, 0, 0, 1), 6881),
+        Address::TCP6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 6882)
+    ]);
+    //or on their own, and in dictionaries
+    let response = announce_response(b"d8:intervali900e6:peers60:e").unwrap();
+    assert!(response.peers.is_empty());
+    let response = announce_response(b"d8:intervali900e5:peersld2:ip11:2001:db8::24:porti80eeee").unwrap();
+    assert_eq!(response.addresses(), vec![Address::TCP6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2), 80)]);
+
+    let magnet = MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK&x.pe=%5B%3A%3A1%5D%3A6881").unwrap();
+    assert_eq!(magnet.peer_addresses(), vec![Address::TCP6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 6881)]);
+    assert_eq!(Address::TCP6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 6881).socket_addr(), "[::1]:6881".parse::<SocketAddr>().unwrap());
+
+    //skip where there's no IPv6 loopback
+    let listener = match TcpListener::bind("[::1]:0") {
+        Ok(listener) => listener,
+        Err(_) => return
+    };
+    let port = listener.local_addr().unwrap().port();
+    let info_hash = [7; 20];
+    let seeder = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        accept_peer(stream, &info_hash, &gen_rand_peer_id("-SD0001-")).unwrap()
+    });
+    let (handshake, _) = connect_to_peer(Address::TCP6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), port), &info_hash, &gen_rand_peer_id("-LC0001-")).unwrap();
+    assert!(handshake.peer_id.starts_with(b"-SD0001-"));
+    seeder.join().unwrap();
+}